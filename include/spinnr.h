@@ -0,0 +1,218 @@
+#ifndef SPINNR_H
+#define SPINNR_H
+
+/* Generated from src/capi.rs and src/plugin.rs by cbindgen; don't edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * The plugin ABI version
+ */
+#define SPINNR_PLUGIN_ABI 1
+
+/**
+ * What a frontend is told besides rotations (see `Announcement`)
+ */
+typedef enum SpinnrAnnouncement {
+  SPINNR_ANNOUNCEMENT_PENDING = 0,
+  SPINNR_ANNOUNCEMENT_SUGGESTED = 1,
+  SPINNR_ANNOUNCEMENT_CANCELLED = 2,
+} SpinnrAnnouncement;
+
+/**
+ * What a plugin provides
+ */
+typedef enum SpinnrPluginKind {
+  SPINNR_PLUGIN_KIND_BACKEND = 1,
+  SPINNR_PLUGIN_KIND_FRONTEND = 2,
+} SpinnrPluginKind;
+
+/**
+ * A screen rotation. `SPINNR_ROTATION_NONE` means there's no clear
+ * orientation (yet).
+ */
+typedef enum SpinnrRotation {
+  SPINNR_ROTATION_NONE = -1,
+  SPINNR_ROTATION_NORMAL = 0,
+  SPINNR_ROTATION_LEFT = 1,
+  SPINNR_ROTATION_INVERTED = 2,
+  SPINNR_ROTATION_RIGHT = 3,
+} SpinnrRotation;
+
+/**
+ * An orientation detector
+ */
+typedef struct SpinnrDetector SpinnrDetector;
+
+/**
+ * An acceleration, in m/s^2
+ */
+typedef struct SpinnrVector {
+  double x;
+  double y;
+  double z;
+} SpinnrVector;
+
+/**
+ * Called with the new rotation and the callback's user data whenever a
+ * detector's rotation changes
+ */
+typedef void (*SpinnrCallback)(enum SpinnrRotation rotation, void *user_data);
+
+/**
+ * One of an instance's options
+ */
+typedef struct SpinnrOption {
+  const char *name;
+  const char *value;
+} SpinnrOption;
+
+/**
+ * A backend or frontend a plugin provides. Optional functions may be NULL.
+ */
+typedef struct SpinnrPlugin {
+  /**
+   * `SPINNR_PLUGIN_ABI`, as the plugin was built against it
+   */
+  uint32_t abi;
+  enum SpinnrPluginKind kind;
+  /**
+   * The name it's asked for by
+   */
+  const char *name;
+  /**
+   * A line of help (optional)
+   */
+  const char *help;
+  /**
+   * Make an instance from `count` options. On failure, return NULL and
+   * optionally point `error` at a static message.
+   */
+  void *(*open)(const struct SpinnrOption *options, size_t count, const char **error);
+  /**
+   * Free an instance (optional)
+   */
+  void (*close)(void *instance);
+  /**
+   * Backends: the device's orientation (a `SpinnrRotation`)
+   */
+  int (*orientation)(void *instance);
+  /**
+   * Backends (optional): the latest raw and filtered acceleration; false
+   * if there's none
+   */
+  bool (*vector)(void *instance, struct SpinnrVector *raw, struct SpinnrVector *filtered);
+  /**
+   * Backends (optional): set the low-pass filter multiplier; false if
+   * the backend isn't filtered
+   */
+  bool (*set_filter)(void *instance, double mult);
+  /**
+   * Backends (optional): set the sensitivity; false if it can't be
+   */
+  bool (*set_sensitivity)(void *instance, double sensitivity);
+  /**
+   * Frontends: show a rotation (a `SpinnrRotation`); 0, or an errno
+   */
+  int (*send)(void *instance, int rotation);
+  /**
+   * Frontends (optional): pass on an announcement; 0, or an errno
+   */
+  int (*announce)(void *instance, enum SpinnrAnnouncement announcement, int rotation);
+} SpinnrPlugin;
+
+/**
+ * The type of `spinnr_plugins`
+ */
+typedef const struct SpinnrPlugin *(*SpinnrPluginsFn)(size_t *count);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Create a detector. `options` is NULL or a string of comma-separated
+ * `key=value` pairs:
+ * - `source`: `poll` to read the accelerometer (the default if fsaccel is
+ *   compiled in), or `feed` to be passed samples
+ * - `filter`: how far (0 to 1) each sample moves the low-pass filter
+ * - `sensitivity`: how readily a tilt counts as a rotation
+ * - `path`, `scale`, `defscale`, `fix_sign`: as for spinnrd's fsaccel
+ *   backend
+ *
+ * Returns NULL (see `spinnr_last_error`) if the options are bad or the
+ * accelerometer can't be opened.
+ *
+ * # Safety
+ * `options` must be NULL or a NUL-terminated string.
+ */
+struct SpinnrDetector *spinnr_detector_new(const char *options);
+
+/**
+ * Free a detector. Passing NULL does nothing.
+ */
+void spinnr_detector_free(struct SpinnrDetector *detector);
+
+/**
+ * Pass a `source=feed` detector a sample (in m/s^2; up, right and towards
+ * the viewer are positive), and return its rotation.
+ */
+enum SpinnrRotation spinnr_detector_feed(struct SpinnrDetector *detector,
+                                         double x,
+                                         double y,
+                                         double z);
+
+/**
+ * Have a `source=poll` detector read its accelerometer, and return its
+ * rotation. Call it every so often (spinnrd polls every 150ms).
+ */
+enum SpinnrRotation spinnr_detector_poll(struct SpinnrDetector *detector);
+
+/**
+ * The last clear rotation the detector saw
+ */
+enum SpinnrRotation spinnr_detector_rotation(const struct SpinnrDetector *detector);
+
+/**
+ * Write the latest raw and filtered acceleration to `raw` and `filtered`
+ * (either may be NULL). Returns false if there hasn't been a sample yet.
+ */
+bool spinnr_detector_vector(const struct SpinnrDetector *detector,
+                            struct SpinnrVector *raw,
+                            struct SpinnrVector *filtered);
+
+/**
+ * Set the sensitivity. Returns false if the detector can't change it.
+ */
+bool spinnr_detector_set_sensitivity(struct SpinnrDetector *detector, double sensitivity);
+
+/**
+ * Call `callback` (from inside `spinnr_detector_feed` or
+ * `spinnr_detector_poll`) whenever the rotation changes. A NULL callback
+ * stops the calls.
+ */
+void spinnr_detector_set_callback(struct SpinnrDetector *detector,
+                                  SpinnrCallback callback,
+                                  void *user_data);
+
+/**
+ * A rotation's name (`"normal"`, `"left"`, `"inverted"`, `"right"` or
+ * `"none"`), as a static string
+ */
+const char *spinnr_rotation_name(enum SpinnrRotation rotation);
+
+/**
+ * Why the last call on this thread failed, or NULL. The string is valid
+ * until the next failure on this thread.
+ */
+const char *spinnr_last_error(void);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* SPINNR_H */
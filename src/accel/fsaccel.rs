@@ -3,8 +3,8 @@
 //! A module for representing an accelerometer based on data from the filesystem.
 
 use super::AccelerationVector as AVector;
+use super::DEFAULT_SENSITIVITY;
 
-use std::collections::HashMap;
 use std::path::{Path,PathBuf};
 use std::fs::File;
 use std::io::prelude::*;
@@ -31,7 +31,44 @@ pub const DEFAULT_DATA_PREFIX:  &str = "in_accel_";
 pub const DEFAULT_DESCR_PREFIX: &str = "scan_elements/in_accel_";
 pub const DEFAULT_DATA_SUFFIX:  &str = "_raw";
 pub const DEFAULT_DESCR_SUFFIX: &str = "_type";
-pub const DEFAULT_FIX_SIGN:     &str = "false";
+
+/// How to find and read an accelerometer through the filesystem
+#[derive(Debug,Clone)]
+pub struct FsAccelConfig {
+    /// The directory with the accelerometer files; autodetected if `None`
+    pub path: Option<PathBuf>,
+    /// A set scale, instead of reading `scale_file`
+    pub scale: Option<f64>,
+    /// The scale to use if `scale_file` can't be read
+    pub default_scale: Option<f64>,
+    pub scale_file: String,
+    /// Channel data files are named prefix, channel, suffix
+    pub data_prefix: String,
+    pub data_suffix: String,
+    /// Likewise channel description files
+    pub descr_prefix: String,
+    pub descr_suffix: String,
+    /// Whether signed values are written as unsigned
+    pub fix_sign: bool,
+    pub sensitivity: f64,
+}
+
+impl Default for FsAccelConfig {
+    fn default() -> FsAccelConfig {
+        FsAccelConfig {
+            path: None,
+            scale: None,
+            default_scale: None,
+            scale_file: DEFAULT_SCALE_FILE.to_owned(),
+            data_prefix: DEFAULT_DATA_PREFIX.to_owned(),
+            data_suffix: DEFAULT_DATA_SUFFIX.to_owned(),
+            descr_prefix: DEFAULT_DESCR_PREFIX.to_owned(),
+            descr_suffix: DEFAULT_DESCR_SUFFIX.to_owned(),
+            fix_sign: false,
+            sensitivity: DEFAULT_SENSITIVITY,
+        }
+    }
+}
 
 
 #[derive(Debug)]
@@ -180,9 +217,10 @@ impl ScanType {
     ///
     /// # Examples
     /// ```
-    /// use fsaccel::ScanType;
-    /// assert_eq!(ScanType::from_str("s8/32>>0").convert(255), -1)
-    /// assert_eq!(ScanType::from_str("u6/16>>0").convert(17), -15)
+    /// use spinnr::accel::fsaccel::ScanType;
+    /// assert_eq!(ScanType::from_str("s8/32>>0", true).unwrap().convert("255"), -1);
+    /// assert_eq!(ScanType::from_str("u6/16>>0", false).unwrap().convert("17"), -15);
+    /// assert_eq!(ScanType::from_str("u6/16>>0", false).unwrap().convert("40"), 8);
     /// ```
     pub fn convert(&self, num: &str) -> i64 {
         (self.conversion.1)(num, self.bits)
@@ -228,10 +266,10 @@ fn _conv_unsigned_signed(num: &str, s: u8) -> i64 {
 fn _conv_unsigned(num: &str, s: u8) -> i64 {
     let n = num.parse::<u64>().expect(&format!("Number parsing failed! (u{} -> i64 '{}') ", s, num));
     if n >= 1<<(s-1) {
-        (n - 1<<(s-1)) as i64
+        (n - (1<<(s-1))) as i64
     } else {
         // Assuming 2's complement.
-        0i64 - (1u64<<(s-1) as u64 - n) as i64
+        0i64 - ((1u64<<(s-1)) - n) as i64
     }
 }
 
@@ -273,38 +311,15 @@ macro_rules! f2s {
     };
 }
 
-macro_rules! unmapvars {
-    [ $opts:ident: $($vars:tt)+ ] => {
-        unmapvars!(@inner $opts: $($vars)+)
-    };
-    ( @inner $opts:ident: $var:ident; $($tail:tt)* ) => {
-        unmapvars!(@inner $opts: $vars, ""; $($tail)*)
-    };
-    ( @inner $opts:ident: $var:ident, $def:expr; $($tail:tt)+ ) => {
-        let def_var = $def.to_owned();
-        let $var = $opts.get(stringify!($var)).unwrap_or(&def_var);
-        unmapvars!(@inner $opts: $($tail)+);
-        debug!("{} = {}", stringify!($var), &$var);
-    };
-    ( @inner $opts:ident: $var:ident, $def:expr; ) => {
-        let def_var = $def.to_owned();
-        let $var = $opts.get(stringify!($var)).unwrap_or(&def_var);
-        debug!("{} = {}", stringify!($var), &$var);
-    };
-}
-pub fn build_channels(chans: (&str, &str, &str), opts: &HashMap<String, String>) -> IoResult<(Channel, Channel, Channel)> {
+pub fn build_channels(chans: (&str, &str, &str), path: &Path, config: &FsAccelConfig) -> IoResult<(Channel, Channel, Channel)> {
     debug!("Building channels {:?}", &chans);
-    unmapvars![opts:
-        data_prefix, DEFAULT_DATA_PREFIX;
-        // normally would insist on Path.join, but this app is
-        // *nix-exclusive anyway.
-        descr_prefix, DEFAULT_DESCR_PREFIX;
-        data_suffix, DEFAULT_DATA_SUFFIX;
-        descr_suffix, DEFAULT_DESCR_SUFFIX;
-        fix_sign, DEFAULT_FIX_SIGN;
-    ];
-    let fs: bool = fix_sign.parse().expect("fix_sign must be 'true' or 'false'.");
-    let path = PathBuf::from(opts.get("path").unwrap_or(&DEFAULT_FSACCEL_PATH.to_owned()));
+    let data_prefix = &config.data_prefix;
+    // normally would insist on Path.join, but this app is
+    // *nix-exclusive anyway.
+    let descr_prefix = &config.descr_prefix;
+    let data_suffix = &config.data_suffix;
+    let descr_suffix = &config.descr_suffix;
+    let fs = config.fix_sign;
     debug!("fs = {}", fs);
     macro_rules! newchan {
         ($($chan: expr),+) => {
@@ -326,53 +341,55 @@ pub fn build_channels(chans: (&str, &str, &str), opts: &HashMap<String, String>)
 pub struct FsAccelerometer {
     scale: f64,
     channels: (Channel, Channel, Channel),
+    latest: Option<AVector<f64>>,
+    sensitivity: f64,
 }
 
 impl FsAccelerometer {
-    /// Creates a new FsAccelerometer with the specified options.
-    pub fn from_opts(opts: &mut HashMap<String, String>) -> IoResult<FsAccelerometer> {
-        debug!("Creating FsAccelerometer with the following options: {:?}", opts);
-        let haskey = opts.contains_key("path");
-        let path = match haskey {
-            true    => PathBuf::from(opts.get("path").unwrap()),
-            false   => {
-                let path = guess_path(&DEFAULT_FSACCEL_PATH.to_owned())?;
-                opts.insert("path".into(), path.to_string_lossy().into_owned());
-                path
-            },
+    /// Creates a new FsAccelerometer as `config` says.
+    pub fn new(config: &FsAccelConfig) -> IoResult<FsAccelerometer> {
+        debug!("Creating FsAccelerometer with the following config: {:?}", config);
+        let path = match config.path {
+            Some(ref p) => p.clone(),
+            None    => guess_path(DEFAULT_FSACCEL_PATH)?,
         };
         debug!("FsAccel path is {}", &path.to_string_lossy());
-        let scale: f64 = match opts.get("scale") {
-            //TODO: Log before aborting
-            Some(s) => s.parse::<f64>().expect("Scale must be a number"),
+        let scale: f64 = match config.scale {
+            Some(s) => s,
             None    => {
-                let mut scales = String::new();
-                let def_scalef = DEFAULT_SCALE_FILE.to_owned();
-                let scalef = path.join(opts.get("scalefile").unwrap_or(&def_scalef));
-                { f2s!(&scalef, scales); }
-                scales.trim().parse::<f64>().unwrap_or_else(|e| {
-                    opts.get("defscale").unwrap_or_else(||
-                            panic!(format!("Couldn't parse scale file {}: {}", &scalef.to_string_lossy(), e)))
-                        .parse::<f64>().expect("default scale must be a number")
-                })
+                let scalef = path.join(&config.scale_file);
+                read_scale(&scalef).or_else(|e| config.default_scale.ok_or(e))?
             }
         };
         debug!("Scale is {}", &scale);
         Ok(FsAccelerometer {
             scale: scale,
-            channels: build_channels(("x","y","z"), opts)?,
+            channels: build_channels(("x","y","z"), &path, config)?,
+            latest: None,
+            sensitivity: config.sensitivity,
         })
     }
 }
 
+/// Read the scale from `scalef`
+fn read_scale(scalef: &Path) -> IoResult<f64> {
+    let mut scales = String::new();
+    { f2s!(scalef, scales); }
+    scales.trim().parse::<f64>().map_err(|e| IoError::new(
+            ::std::io::ErrorKind::InvalidData,
+            format!("Couldn't parse scale file {}: {}", scalef.to_string_lossy(), e)))
+}
+
 impl super::Accelerometer for FsAccelerometer {
 
     fn read(&mut self) -> AVector<f64> {
-        AVector::<f64> {
+        let acc = AVector::<f64> {
             x: { self.channels.0.read() as f64 * self.scale },
             y: { self.channels.1.read() as f64 * self.scale },
             z: { self.channels.2.read() as f64 * self.scale },
-        }
+        };
+        self.latest = Some(acc);
+        acc
     }
     fn read_raw(&mut self) -> AVector<i32> {
         AVector::<i32> {
@@ -384,6 +401,16 @@ impl super::Accelerometer for FsAccelerometer {
     fn get_scale(&self) -> f64 {
         return self.scale;
     }
+    fn latest_raw(&self) -> Option<AVector<f64>> {
+        self.latest
+    }
+    fn sensitivity(&self) -> f64 {
+        self.sensitivity
+    }
+    fn set_sensitivity(&mut self, sensitivity: f64) -> bool {
+        self.sensitivity = sensitivity;
+        true
+    }
 }
 
 
@@ -2,7 +2,7 @@
 //!
 //! Traits and structs for representing accelerometers.
 
-use super::{Rotation,Orientator,SENSITIVITY};
+use super::{Rotation,Orientator};
 
 use std::ops::{Add,Div,Sub,Mul,AddAssign};
 use std::fmt::{Display, Formatter};
@@ -10,7 +10,13 @@ use std::fmt::Result as FmtResult;
 
 #[cfg(feature = "fsaccel")]
 pub mod fsaccel;
+#[cfg(feature = "fsaccel")]
 pub use self::fsaccel::FsAccelerometer as FsAccel;
+#[cfg(feature = "fsaccel")]
+pub use self::fsaccel::{FsAccelerometer, FsAccelConfig};
+
+/// The default sensitivity
+pub const DEFAULT_SENSITIVITY: f64 = 5.0;
 
 
 /// Describes an acceleration vector.
@@ -83,6 +89,13 @@ impl<T,U> Div<U> for AccelerationVector<T> where U: Into<f64>, T: Div<f64> + Def
     }
 }
 
+impl AccelerationVector<f64> {
+    /// The vector's length
+    pub fn magnitude(&self) -> f64 {
+        (self.x * self.x + self.y * self.y + self.z * self.z).sqrt()
+    }
+}
+
 
 /// Trait for an accelerometer
 pub trait Accelerometer {
@@ -97,12 +110,38 @@ pub trait Accelerometer {
 
     /// Returns the scale between raw integers and m/s^2.
     fn get_scale(&self) -> f64;
+
+    /// Returns the most recent unfiltered reading, if there's been one.
+    fn latest_raw(&self) -> Option<AccelerationVector<f64>>;
+
+    /// Returns the most recent filtered reading, if the accelerometer is 
+    /// filtered and there's been one.
+    fn latest_filtered(&self) -> Option<AccelerationVector<f64>> {
+        None
+    }
+
+    /// Sets the low-pass filter multiplier. Returns false if the 
+    /// accelerometer isn't filtered.
+    fn set_mult(&mut self, _mult: f64) -> bool {
+        false
+    }
+
+    /// Returns how readily a tilt counts as a rotation; the higher it is,
+    /// the flatter the device can be held.
+    fn sensitivity(&self) -> f64 {
+        DEFAULT_SENSITIVITY
+    }
+
+    /// Sets the sensitivity. Returns false if it can't be changed.
+    fn set_sensitivity(&mut self, _sensitivity: f64) -> bool {
+        false
+    }
 }
 
 impl<T: Accelerometer> Orientator for T {
     fn orientation(&mut self) -> Option<Rotation> {
         let acc = self.read();
-        if (acc.x.abs() - acc.y.abs()).abs() > acc.z.abs() / *SENSITIVITY + 1.4715 {
+        if (acc.x.abs() - acc.y.abs()).abs() > acc.z.abs() / self.sensitivity() + 1.4715 {
             if acc.x.abs() > acc.y.abs() {
                 if acc.x < 0.0 {
                     trace!("rot: {}; accel: {}", Rotation::Right, acc);
@@ -125,6 +164,22 @@ impl<T: Accelerometer> Orientator for T {
             None
         }
     }
+
+    fn raw_vector(&self) -> Option<AccelerationVector<f64>> {
+        self.latest_raw()
+    }
+
+    fn filtered_vector(&self) -> Option<AccelerationVector<f64>> {
+        self.latest_filtered()
+    }
+
+    fn set_filter_mult(&mut self, mult: f64) -> bool {
+        self.set_mult(mult)
+    }
+
+    fn set_sensitivity(&mut self, sensitivity: f64) -> bool {
+        Accelerometer::set_sensitivity(self, sensitivity)
+    }
 }
 
 
@@ -174,6 +229,27 @@ impl<T: Accelerometer> Accelerometer for FilteredAccelerometer<T> {
     fn get_scale(&self) -> f64 {
         self.accel.get_scale()
     }
+
+    fn latest_raw(&self) -> Option<AccelerationVector<f64>> {
+        self.accel.latest_raw()
+    }
+
+    fn latest_filtered(&self) -> Option<AccelerationVector<f64>> {
+        Some(self.current)
+    }
+
+    fn set_mult(&mut self, mult: f64) -> bool {
+        self.mult = mult;
+        true
+    }
+
+    fn sensitivity(&self) -> f64 {
+        self.accel.sensitivity()
+    }
+
+    fn set_sensitivity(&mut self, sensitivity: f64) -> bool {
+        self.accel.set_sensitivity(sensitivity)
+    }
 }
 
 impl<'l, T: Accelerometer> Accelerometer for &'l mut FilteredAccelerometer<T> {
@@ -190,5 +266,26 @@ impl<'l, T: Accelerometer> Accelerometer for &'l mut FilteredAccelerometer<T> {
     fn get_scale(&self) -> f64 {
         self.accel.get_scale()
     }
+
+    fn latest_raw(&self) -> Option<AccelerationVector<f64>> {
+        self.accel.latest_raw()
+    }
+
+    fn latest_filtered(&self) -> Option<AccelerationVector<f64>> {
+        Some(self.current)
+    }
+
+    fn set_mult(&mut self, mult: f64) -> bool {
+        self.mult = mult;
+        true
+    }
+
+    fn sensitivity(&self) -> f64 {
+        self.accel.sensitivity()
+    }
+
+    fn set_sensitivity(&mut self, sensitivity: f64) -> bool {
+        self.accel.set_sensitivity(sensitivity)
+    }
 }
 
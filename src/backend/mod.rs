@@ -4,110 +4,267 @@
 
 use super::*;
 
+use spinnr::plugin::{PluginBackend, PluginError, SpinnrPluginKind};
+
 #[cfg(any(feature = "fsaccel", feature = "iioaccel"))]
-use accel::FilteredAccelerometer;
+use spinnr::accel::FilteredAccelerometer;
 
 #[cfg(feature = "fsaccel")]
-use accel::FsAccel;
+use spinnr::accel::{FsAccel, FsAccelConfig};
 
-#[allow(dead_code)] // doesn't need to be used, just needs to exist
-struct DummyOrientator();
-impl Orientator for DummyOrientator {
-    fn orientation(&mut self) -> Option<Rotation> {
-        None
-    }
-}
-
-#[cfg(feature = "fsaccel")]
-type FsAccelT = FsAccel;
 #[cfg(feature = "fsaccel")]
-type FilteredFsAccelT = FilteredAccelerometer<FsAccel>;
-#[cfg(not(feature = "fsaccel"))]
-type FsAccelT = DummyOrientator;
-#[cfg(not(feature = "fsaccel"))]
-type FilteredFsAccelT = DummyOrientator;
+use spinnr::accel::fsaccel::{DEFAULT_SCALE_FILE, DEFAULT_DATA_PREFIX, DEFAULT_DESCR_PREFIX,
+    DEFAULT_DATA_SUFFIX, DEFAULT_DESCR_SUFFIX};
 
+/// The backend registry. Each entry declares a backend once: its
+/// `OrientatorKind` variant and type, its name, the cargo feature it needs
+/// (if any), its options, a line of help, and a function that builds it
+/// from its options and the low-pass filter multiplier. Backends whose
+/// feature is off still get a name, so asking for them says so.
+macro_rules! backends {
+    ( $(
+        $variant:ident($t:ty) {
+            name: $name:expr,
+            $(feature: $feature:tt,)*
+            options: $schema:expr,
+            help: $help:expr,
+            init: $init:expr $(,)*
+        }
+    ),+ $(,)* ) => {
+        pub enum OrientatorKind {
+            $(
+                $(#[cfg(feature = $feature)])*
+                $variant($t),
+            )+
+            /// A backend from a plugin
+            Plugin(PluginBackend),
+        }
 
-pub fn backend_help() -> String {
-    format!("{}", fsbackendhelp())
-}
+        impl Orientator for OrientatorKind {
+            fn orientation(&mut self) -> Option<Rotation> {
+                match *self {
+                    $(
+                        $(#[cfg(feature = $feature)])*
+                        OrientatorKind::$variant(ref mut o)  => o.orientation(),
+                    )+
+                    OrientatorKind::Plugin(ref mut o)  => o.orientation(),
+                }
+            }
 
-#[cfg(feature = "fsaccel")]
-fn fsbackendhelp() -> String {
-    use accel::fsaccel::*;
-    format!("
-    For fsaccel:
-        path: The path to the accelerometer files.
-            [Autodetects if not set]
-        scale: Use a set scale instead of reading the scale file.
-        defscale: A default scale to use in case the scale file can't be found.
-        scalefile: The name of the file to check for the scale.
-            [Defaults to \"{}\"]
-        data_prefix: The part of the channel data file name before the 
-            channel name. [Defaults to \"{}\"]
-        descr_prefix: The part of the channel description file name before 
-            the channel name. [Defaults to \"{}\"]
-        data_suffix: The part of the channel data file name after the 
-            channel name. [Defaults to \"{}\"]
-        descr_suffix: The part of the channel description file name after 
-            the channel name. [Defaults to \"{}\"]
-        fix_sign: Whether to apply signfix (when signed integers are 
-            written as unsigned). [Defaults to {}]
-", DEFAULT_SCALE_FILE, DEFAULT_DATA_PREFIX,
-   DEFAULT_DESCR_PREFIX, DEFAULT_DATA_SUFFIX, DEFAULT_DESCR_SUFFIX,
-   DEFAULT_FIX_SIGN
-    )
-}
-#[cfg(not(feature = "fsaccel"))]
-fn fsbackendhelp() -> String { "".to_owned() }
+            fn raw_vector(&self) -> Option<AccelerationVector<f64>> {
+                match *self {
+                    $(
+                        $(#[cfg(feature = $feature)])*
+                        OrientatorKind::$variant(ref o)  => o.raw_vector(),
+                    )+
+                    OrientatorKind::Plugin(ref o)  => o.raw_vector(),
+                }
+            }
 
+            fn filtered_vector(&self) -> Option<AccelerationVector<f64>> {
+                match *self {
+                    $(
+                        $(#[cfg(feature = $feature)])*
+                        OrientatorKind::$variant(ref o)  => o.filtered_vector(),
+                    )+
+                    OrientatorKind::Plugin(ref o)  => o.filtered_vector(),
+                }
+            }
 
-pub enum OrientatorKind {
-    FsAccel(FilteredFsAccelT),
-    FsAccelRaw(FsAccelT),
-    // IioAccel(FilteredIioAccelT),
-    // IioAccelRaw(IioAccelT),
-    // FaceCam(FaceCamT),
-}
+            fn set_filter_mult(&mut self, mult: f64) -> bool {
+                match *self {
+                    $(
+                        $(#[cfg(feature = $feature)])*
+                        OrientatorKind::$variant(ref mut o)  => o.set_filter_mult(mult),
+                    )+
+                    OrientatorKind::Plugin(ref mut o)  => o.set_filter_mult(mult),
+                }
+            }
 
-impl Orientator for OrientatorKind {
-    fn orientation(&mut self) -> Option <Rotation> {
-        match self {
-            &mut OrientatorKind::FsAccel(ref mut a) => a.orientation(),
-            &mut OrientatorKind::FsAccelRaw(ref mut a) => a.orientation(),
-            // &mut OrientatorKind::IioAccel(a)    => a.orientation(),
-            // &mut OrientatorKind::FaceCam(c) => c.orientation(),
+            fn set_sensitivity(&mut self, sensitivity: f64) -> bool {
+                match *self {
+                    $(
+                        $(#[cfg(feature = $feature)])*
+                        OrientatorKind::$variant(ref mut o)  => o.set_sensitivity(sensitivity),
+                    )+
+                    OrientatorKind::Plugin(ref mut o)  => o.set_sensitivity(sensitivity),
+                }
+            }
+        }
+
+        impl Display for OrientatorKind {
+            fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+                match *self {
+                    $(
+                        $(#[cfg(feature = $feature)])*
+                        OrientatorKind::$variant(_)  => write!(fmt, "{}", $name),
+                    )+
+                    OrientatorKind::Plugin(ref o)  => write!(fmt, "{}", o),
+                }
+            }
+        }
+
+        /// Initialize the backend called `backend`, with its options from
+        /// `opts`.
+        #[allow(unused_variables)]
+        fn init_backend(backend: &str, opts: &HashMap<String, HashMap<String, String>>,
+                        mult: f64) -> BackendResult {
+            let none: HashMap<String, String> = HashMap::new();
+            match backend {
+                $(
+                    $(#[cfg(feature = $feature)])*
+                    $name => {
+                        let o = Options::parse($name, &[$schema], opts.get($name).unwrap_or(&none))
+                            .map_err(BackendError::Options)?;
+                        $init(&o, mult)
+                            .map(OrientatorKind::$variant)
+                            .map_err(|e| BackendError::Init($name, e.into()))
+                    },
+                    $(
+                        #[cfg(not(feature = $feature))]
+                        $name => Err(BackendError::NotCompiled($name)),
+                    )*
+                )+
+                _   => init_plugin(backend, opts.get(backend).unwrap_or(&none), mult),
+            }
+        }
+
+        pub fn backend_help() -> String {
+            let entries: Vec<(&str, &str, Schema)> = vec![
+                $(
+                    $(#[cfg(feature = $feature)])*
+                    ($name, $help, $schema),
+                )+
+            ];
+            schemas_help(&entries)
         }
     }
 }
 
-macro_rules! orinit {
-    ( $tomatch:ident, $opts:ident: $( $name:expr, $init:ident $(, $mult:expr)* );+ $(;)* ) => {
-        match $tomatch.as_str() {
-            $(
-                $name => {
-                    if ! $opts.contains_key($name) {
-                        $opts.insert($name.to_owned(), HashMap::new());
-                    }
-                    $init($opts.get_mut($name).unwrap() $(, $mult)*)
-                }),*,
-                _     => Err(BackendError::NoSuchBackend($tomatch)),
-        }
+backends! {
+    FsAccel(FilteredAccelerometer<FsAccel>) {
+        name: "fsaccel",
+        feature: "fsaccel",
+        options: FSACCEL_OPTIONS,
+        help: "Reads an accelerometer through sysfs (such as an iio device), \
+            with low-pass filtering (see --hysteresis).",
+        init: |o: &Options, mult| FsAccel::new(&fsaccel_config(o))
+            .map(|a| FilteredAccelerometer::new(a, mult)),
+    },
+    FsAccelRaw(FsAccel) {
+        name: "fsaccel_raw",
+        feature: "fsaccel",
+        options: FSACCEL_OPTIONS,
+        help: "Like fsaccel, but unfiltered.",
+        init: |o: &Options, _| FsAccel::new(&fsaccel_config(o)),
+    },
+    // IioAccel(FilteredAccelerometer<IioAccel>) {
+    //     name: "iioaccel",
+    //     feature: "iioaccel",
+    //     ...
+    // },
+}
+
+/// The fsaccel backend's options
+#[cfg(feature = "fsaccel")]
+const FSACCEL_OPTIONS: Schema = &[
+    OptSpec {
+        name: "path",
+        kind: OptType::Path,
+        default: None,
+        help: "The path to the accelerometer files. Autodetects if not set.",
+    },
+    OptSpec {
+        name: "scale",
+        kind: OptType::Float,
+        default: None,
+        help: "Use a set scale instead of reading the scale file.",
+    },
+    OptSpec {
+        name: "defscale",
+        kind: OptType::Float,
+        default: None,
+        help: "A default scale to use in case the scale file can't be read.",
+    },
+    OptSpec {
+        name: "scalefile",
+        kind: OptType::Text,
+        default: Some(DEFAULT_SCALE_FILE),
+        help: "The name of the file to check for the scale.",
+    },
+    OptSpec {
+        name: "data_prefix",
+        kind: OptType::Text,
+        default: Some(DEFAULT_DATA_PREFIX),
+        help: "The part of the channel data file name before the channel name.",
+    },
+    OptSpec {
+        name: "descr_prefix",
+        kind: OptType::Text,
+        default: Some(DEFAULT_DESCR_PREFIX),
+        help: "The part of the channel description file name before the \
+            channel name.",
+    },
+    OptSpec {
+        name: "data_suffix",
+        kind: OptType::Text,
+        default: Some(DEFAULT_DATA_SUFFIX),
+        help: "The part of the channel data file name after the channel name.",
+    },
+    OptSpec {
+        name: "descr_suffix",
+        kind: OptType::Text,
+        default: Some(DEFAULT_DESCR_SUFFIX),
+        help: "The part of the channel description file name after the \
+            channel name.",
+    },
+    OptSpec {
+        name: "fix_sign",
+        kind: OptType::Bool,
+        default: Some("false"),
+        help: "Whether to apply signfix (when signed integers are written as \
+            unsigned).",
+    },
+];
+
+
+/// The fsaccel config its options describe
+#[cfg(feature = "fsaccel")]
+fn fsaccel_config(opts: &Options) -> FsAccelConfig {
+    let text = |name, default: &str| opts.get(name).unwrap_or(default).to_owned();
+    FsAccelConfig {
+        path: opts.path("path"),
+        scale: opts.float("scale"),
+        default_scale: opts.float("defscale"),
+        scale_file: text("scalefile", DEFAULT_SCALE_FILE),
+        data_prefix: text("data_prefix", DEFAULT_DATA_PREFIX),
+        data_suffix: text("data_suffix", DEFAULT_DATA_SUFFIX),
+        descr_prefix: text("descr_prefix", DEFAULT_DESCR_PREFIX),
+        descr_suffix: text("descr_suffix", DEFAULT_DESCR_SUFFIX),
+        fix_sign: opts.flag("fix_sign"),
+        ..FsAccelConfig::default()
     }
 }
+
+/// Initialize a backend from a plugin, if there's one called `backend`.
+fn init_plugin(backend: &str, opts: &HashMap<String, String>, mult: f64) -> BackendResult {
+    let plugin = find_plugin(backend, SpinnrPluginKind::Backend)
+        .ok_or_else(|| BackendError::NoSuchBackend(backend.to_owned()))?;
+    let mut b = plugin.open_backend(opts).map_err(BackendError::Plugin)?;
+    b.set_filter_mult(mult);
+    Ok(OrientatorKind::Plugin(b))
+}
+
 /// Initialize an orientator
-pub fn init_orientator(mult: f64) -> Result<OrientatorKind,i32> {
-    let (backends, mut opts) = get_backend_options();
+pub fn init_orientator(mult: f64, sensitivity: f64, options: BackendOptions)
+    -> Result<OrientatorKind,i32> {
+    let (backends, opts) = options;
     for backend in backends {
-        let last_output =  orinit!(backend, opts:
-                // "iioaccel", init_iioaccel, Some(mult);
-                // "iioaccel_raw", init_iioaccel, None;
-                // "camaccel", init_camaccel;
-                "fsaccel_raw", init_fsaccel, None;
-                "fsaccel", init_fsaccel, Some(mult);
-                );
-        match last_output {
-            Ok(o)   => return Ok(o),
+        match init_backend(&backend, &opts, mult) {
+            Ok(mut o)   => {
+                o.set_sensitivity(sensitivity);
+                return Ok(o)
+            },
             Err(e)  => warn!("Error initializing backend: {}", e),
         }
     }
@@ -115,7 +272,6 @@ pub fn init_orientator(mult: f64) -> Result<OrientatorKind,i32> {
 }
 
 
-
 #[derive(Debug)]
 enum BackendError {
     /// Backend wasn't compiled in
@@ -123,8 +279,14 @@ enum BackendError {
     NotCompiled(&'static str),
     /// Backend does not exist
     NoSuchBackend(String),
-    /// Couldn't find/open filesystem accelerometer files
-    FsAccel(std::io::Error),
+    /// A backend option was unknown or had an invalid value
+    #[allow(dead_code)] // not always compiled
+    Options(OptionError),
+    /// The backend couldn't start (backend, why)
+    #[allow(dead_code)]
+    Init(&'static str, Box<dyn std::error::Error>),
+    /// A plugin backend couldn't start
+    Plugin(PluginError),
 }
 
 impl Display for BackendError {
@@ -137,9 +299,11 @@ impl Display for BackendError {
             &NoSuchBackend(ref b)   => {
                 write!(fmt, "backend '{}' does not exist!", b)
             },
-            &FsAccel(ref e) => {
-                write!(fmt, "fsaccel init error: {}", e)
+            &Options(ref e) => write!(fmt, "{}", e),
+            &Init(b, ref e) => {
+                write!(fmt, "{} init error: {}", b, e)
             },
+            Plugin(e)   => write!(fmt, "{}", e),
         }
     }
 }
@@ -154,28 +318,11 @@ impl std::error::Error for BackendError {
         match self {
             &BackendError::NotCompiled(_)   => None,
             &BackendError::NoSuchBackend(_) => None,
-            &BackendError::FsAccel(ref e) => Some(e),
+            &BackendError::Options(ref e) => Some(e),
+            &BackendError::Init(_, ref e) => Some(&**e),
+            BackendError::Plugin(e)   => Some(e),
         }
     }
 }
 
 type BackendResult = Result<OrientatorKind, BackendError>;
-
-#[cfg(not(feature = "fsaccel"))]
-/// Don't initiaze a non-compiled filesystem accelerometer
-fn init_fsaccel(_opts: HashMap<String, String>) -> BackendResult {
-    return Err(BackendError::NotCompiled("fsaccel"));
-}
-#[cfg(feature = "fsaccel")]
-/// Initialize a filesystem accelerometer
-fn init_fsaccel(opts: &mut HashMap<String, String>, mult: Option<f64>) -> BackendResult {
-    match mult {
-        Some(m) => Ok(OrientatorKind::FsAccel(FilteredAccelerometer::new(
-                    FsAccel::from_opts(opts).map_err(|e| BackendError::FsAccel(e))?,
-                    m
-                    ))),
-        None    => Ok(OrientatorKind::FsAccelRaw(FsAccel::from_opts(opts).map_err(|e| BackendError::FsAccel(e))?)),
-    }
-}
-
-
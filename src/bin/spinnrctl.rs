@@ -0,0 +1,96 @@
+//! # spinnrctl
+//! spinnrctl talks to a running spinnrd through its control socket.
+
+extern crate clap;
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+
+use clap::{Arg, AppSettings};
+
+/// The default control socket (spinnrd's default working directory and 
+/// control socket)
+const DEFAULT_CONTROL_SOCKET: &str = "/run/spinnrd/spinnrd.ctl";
+
+fn main() {
+    ::std::process::exit(mainprog());
+}
+
+/// The actual main body of the program
+fn mainprog() -> i32 {
+    let args = clap::App::new("spinnrctl")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author("James Wescott <james@wescottdesign.com>")
+        .about("Inspects and adjusts a running spinnrd")
+        .setting(AppSettings::TrailingVarArg)
+        .arg(Arg::with_name("socket")
+             .long("socket")
+             .short("s")
+             .value_name("SOCKET")
+             .default_value(DEFAULT_CONTROL_SOCKET)
+             .help("spinnrd's control socket")
+            )
+        .arg(Arg::with_name("request")
+             .value_name("REQUEST")
+             .multiple(true)
+             .required(true)
+             .help("The request to send")
+             .long_help("One of:
+    rotation                    The current and last-written rotation, and the lock
+    lock [on|off|toggle]        Show or change the rotation lock
+    vector                      The latest raw and filtered acceleration
+    backend                     The backend in use
+    frontends                   The frontends in use
+    get SETTING                 Show a setting
+    set SETTING VALUE           Change a setting
+    rotate ROTATION             Rotate to normal, left, inverted or right now
+    veto                        Cancel the pending rotation
+    accept                      Commit the suggested or pending rotation now
+    lease pin ROTATION          Force a rotation until spinnrctl is killed
+    lease allow SET             Only allow some rotations until spinnrctl is killed
+    lease forbid SET            Forbid some rotations until spinnrctl is killed
+    leases                      List the leases held
+
+SETTING is delay, flip_delay, motion_threshold, hysteresis, sensitivity or
+veto_window. A SET is a comma-separated list of rotations, 'landscape' or
+'portrait'.")
+            )
+        .get_matches();
+
+    let socket = args.value_of("socket").unwrap_or(DEFAULT_CONTROL_SOCKET);
+    let request = args.values_of("request")
+        .map_or_else(Vec::new, |v| v.collect())
+        .join(" ");
+
+    match ask(socket, &request) {
+        Ok((stream, ref answer)) if answer.starts_with("ok") => {
+            println!("{}", answer["ok".len()..].trim_start());
+            if request.starts_with("lease ") {
+                // The lease lasts as long as the connection does, and
+                // spinnrd never says anything more, so this waits until
+                // we're killed or spinnrd goes away.
+                eprintln!("Holding the lease until killed...");
+                let _ = BufReader::new(&stream).read_line(&mut String::new());
+            }
+            0
+        },
+        Ok((_, answer)) => {
+            eprintln!("{}", answer.trim_start_matches("err").trim_start());
+            1
+        },
+        Err(e)  => {
+            eprintln!("Couldn't talk to spinnrd at {}: {}", socket, e);
+            2
+        },
+    }
+}
+
+/// Send `request` to the daemon listening on `socket` and return the
+/// connection and its answer.
+fn ask(socket: &str, request: &str) -> std::io::Result<(UnixStream, String)> {
+    let mut stream = UnixStream::connect(socket)?;
+    writeln!(stream, "{}", request)?;
+    let mut answer = String::new();
+    BufReader::new(&stream).read_line(&mut answer)?;
+    Ok((stream, answer.trim_end().to_owned()))
+}
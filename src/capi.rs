@@ -0,0 +1,506 @@
+//! # capi
+//! A C API to the orientation detection, for programs that want it
+//! in-process instead of running spinnrd and watching its output. The
+//! header (`include/spinnr.h`) is generated from this file (and the plugin
+//! ABI in `plugin.rs`) by cbindgen when the crate is built.
+//!
+//! A detector either polls an accelerometer itself (`source=poll`, the
+//! default when fsaccel is compiled in) or is fed samples by its caller
+//! (`source=feed`). Either way it keeps the last clear orientation it saw,
+//! and calls its callback when that changes. Delays, flip handling and the
+//! rest of the daemon's settling logic are left to the caller.
+//!
+//! Detectors aren't thread-safe; use each from one thread at a time.
+
+use super::{Rotation, Orientator};
+use accel::{Accelerometer, AccelerationVector, FilteredAccelerometer, DEFAULT_SENSITIVITY};
+#[cfg(feature = "fsaccel")]
+use accel::{FsAccelerometer, FsAccelConfig};
+
+use std::cell::{Cell, RefCell};
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_void};
+use std::ptr;
+use std::rc::Rc;
+
+/// The default low-pass filter multiplier (spinnrd's default period over
+/// its default hysteresis)
+const DEFAULT_FILTER: f64 = 0.15;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Remember why the last call failed, for `spinnr_last_error`
+fn set_error(msg: String) {
+    debug!("C API error: {}", msg);
+    let msg = CString::new(msg).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(msg));
+}
+
+/// A screen rotation. `SPINNR_ROTATION_NONE` means there's no clear
+/// orientation (yet).
+#[repr(C)]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SpinnrRotation {
+    None = -1,
+    Normal = 0,
+    Left = 1,
+    Inverted = 2,
+    Right = 3,
+}
+
+impl From<Option<Rotation>> for SpinnrRotation {
+    fn from(r: Option<Rotation>) -> SpinnrRotation {
+        match r {
+            None    => SpinnrRotation::None,
+            Some(Rotation::Normal)  => SpinnrRotation::Normal,
+            Some(Rotation::Left)    => SpinnrRotation::Left,
+            Some(Rotation::Inverted)    => SpinnrRotation::Inverted,
+            Some(Rotation::Right)   => SpinnrRotation::Right,
+        }
+    }
+}
+
+/// An acceleration, in m/s^2
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SpinnrVector {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+impl From<AccelerationVector<f64>> for SpinnrVector {
+    fn from(v: AccelerationVector<f64>) -> SpinnrVector {
+        SpinnrVector { x: v.x, y: v.y, z: v.z }
+    }
+}
+
+impl From<SpinnrVector> for AccelerationVector<f64> {
+    fn from(v: SpinnrVector) -> AccelerationVector<f64> {
+        AccelerationVector { x: v.x, y: v.y, z: v.z }
+    }
+}
+
+/// Called with the new rotation and the callback's user data whenever a
+/// detector's rotation changes
+pub type SpinnrCallback = Option<extern "C" fn(rotation: SpinnrRotation, user_data: *mut c_void)>;
+
+/// Samples passed in by the caller
+struct Fed {
+    sample: Rc<Cell<AccelerationVector<f64>>>,
+    sensitivity: f64,
+}
+
+impl Accelerometer for Fed {
+    fn read(&mut self) -> AccelerationVector<f64> {
+        self.sample.get()
+    }
+
+    fn read_raw(&mut self) -> AccelerationVector<i32> {
+        let v = self.sample.get();
+        AccelerationVector { x: v.x.round() as i32, y: v.y.round() as i32, z: v.z.round() as i32 }
+    }
+
+    fn get_scale(&self) -> f64 {
+        1.0
+    }
+
+    fn latest_raw(&self) -> Option<AccelerationVector<f64>> {
+        Some(self.sample.get())
+    }
+
+    fn sensitivity(&self) -> f64 {
+        self.sensitivity
+    }
+
+    fn set_sensitivity(&mut self, sensitivity: f64) -> bool {
+        self.sensitivity = sensitivity;
+        true
+    }
+}
+
+/// Where a detector's samples come from
+enum Source {
+    Fed(Fed),
+    #[cfg(feature = "fsaccel")]
+    Fs(Box<FsAccelerometer>),
+}
+
+macro_rules! source_dispatch {
+    ($self:ident, $a:ident => $e:expr) => {
+        match $self {
+            Source::Fed($a) => $e,
+            #[cfg(feature = "fsaccel")]
+            Source::Fs($a)  => $e,
+        }
+    }
+}
+
+impl Accelerometer for Source {
+    fn read(&mut self) -> AccelerationVector<f64> {
+        source_dispatch!(self, a => a.read())
+    }
+
+    fn read_raw(&mut self) -> AccelerationVector<i32> {
+        source_dispatch!(self, a => a.read_raw())
+    }
+
+    fn get_scale(&self) -> f64 {
+        source_dispatch!(self, a => a.get_scale())
+    }
+
+    fn latest_raw(&self) -> Option<AccelerationVector<f64>> {
+        source_dispatch!(self, a => a.latest_raw())
+    }
+
+    fn sensitivity(&self) -> f64 {
+        source_dispatch!(self, a => a.sensitivity())
+    }
+
+    fn set_sensitivity(&mut self, sensitivity: f64) -> bool {
+        match self {
+            Source::Fed(a)  => Accelerometer::set_sensitivity(a, sensitivity),
+            #[cfg(feature = "fsaccel")]
+            Source::Fs(a)   => Accelerometer::set_sensitivity(&mut **a, sensitivity),
+        }
+    }
+}
+
+/// The options a detector is created with
+struct DetectorOptions {
+    feed: bool,
+    filter: f64,
+    sensitivity: f64,
+    #[cfg(feature = "fsaccel")]
+    fsaccel: FsAccelConfig,
+}
+
+impl DetectorOptions {
+    /// Parse comma-separated `key=value` pairs
+    fn parse(s: &str) -> Result<DetectorOptions, String> {
+        let mut opts = DetectorOptions {
+            feed: cfg!(not(feature = "fsaccel")),
+            filter: DEFAULT_FILTER,
+            sensitivity: DEFAULT_SENSITIVITY,
+            #[cfg(feature = "fsaccel")]
+            fsaccel: FsAccelConfig::default(),
+        };
+        for opt in s.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+            let mut kv = opt.splitn(2, '=');
+            let key = kv.next().unwrap_or("");
+            let val = kv.next().ok_or_else(|| format!("option '{}' has no value", key))?;
+            let float = || val.parse::<f64>()
+                .map_err(|_| format!("bad value for {}: '{}'", key, val));
+            match key {
+                "source"    => opts.feed = match val {
+                    "feed"  => true,
+                    "poll"  if cfg!(feature = "fsaccel")    => false,
+                    "poll"  => return Err("polling needs fsaccel, which isn't compiled".to_owned()),
+                    _   => return Err(format!("unknown source '{}'", val)),
+                },
+                "filter"    => opts.filter = float()?,
+                "sensitivity"   => opts.sensitivity = float()?,
+                #[cfg(feature = "fsaccel")]
+                "path"  => opts.fsaccel.path = Some(val.into()),
+                #[cfg(feature = "fsaccel")]
+                "scale" => opts.fsaccel.scale = Some(float()?),
+                #[cfg(feature = "fsaccel")]
+                "defscale"  => opts.fsaccel.default_scale = Some(float()?),
+                #[cfg(feature = "fsaccel")]
+                "fix_sign"  => opts.fsaccel.fix_sign = val.parse()
+                    .map_err(|_| format!("bad value for {}: '{}'", key, val))?,
+                _   => return Err(format!("unknown option '{}'", key)),
+            }
+        }
+        if !(0.0 < opts.filter && opts.filter <= 1.0) {
+            return Err(format!("filter must be in (0, 1], not {}", opts.filter));
+        }
+        Ok(opts)
+    }
+}
+
+/// An orientation detector
+pub struct SpinnrDetector {
+    /// Where fed samples go (`None` if the detector polls)
+    sample: Option<Rc<Cell<AccelerationVector<f64>>>>,
+    /// Created by the first sample, so the filter starts from it
+    orient: Option<FilteredAccelerometer<Source>>,
+    filter: f64,
+    sensitivity: f64,
+    rotation: Option<Rotation>,
+    callback: SpinnrCallback,
+    user_data: *mut c_void,
+}
+
+impl SpinnrDetector {
+    fn new(opts: DetectorOptions) -> Result<SpinnrDetector, String> {
+        let mut det = SpinnrDetector {
+            sample: None,
+            orient: None,
+            filter: opts.filter,
+            sensitivity: opts.sensitivity,
+            rotation: None,
+            callback: None,
+            user_data: ptr::null_mut(),
+        };
+        if opts.feed {
+            det.sample = Some(Rc::new(Cell::new(AccelerationVector::default())));
+        } else {
+            #[cfg(feature = "fsaccel")]
+            {
+                let mut config = opts.fsaccel;
+                config.sensitivity = opts.sensitivity;
+                let accel = FsAccelerometer::new(&config)
+                    .map_err(|e| format!("couldn't open accelerometer: {}", e))?;
+                det.orient = Some(FilteredAccelerometer::new(Source::Fs(Box::new(accel)), det.filter));
+            }
+        }
+        Ok(det)
+    }
+
+    /// Take a sample, and call back if the rotation changed
+    fn update(&mut self) -> Option<Rotation> {
+        if self.orient.is_none() {
+            if let Some(ref sample) = self.sample {
+                let fed = Fed { sample: sample.clone(), sensitivity: self.sensitivity };
+                self.orient = Some(FilteredAccelerometer::new(Source::Fed(fed), self.filter));
+            }
+        }
+        let new = self.orient.as_mut().and_then(|o| o.orientation());
+        if new.is_some() && new != self.rotation {
+            self.rotation = new;
+            if let Some(cb) = self.callback {
+                cb(new.into(), self.user_data);
+            }
+        }
+        self.rotation
+    }
+}
+
+/// Create a detector. `options` is NULL or a string of comma-separated
+/// `key=value` pairs:
+/// - `source`: `poll` to read the accelerometer (the default if fsaccel is
+///   compiled in), or `feed` to be passed samples
+/// - `filter`: how far (0 to 1) each sample moves the low-pass filter
+/// - `sensitivity`: how readily a tilt counts as a rotation
+/// - `path`, `scale`, `defscale`, `fix_sign`: as for spinnrd's fsaccel
+///   backend
+///
+/// Returns NULL (see `spinnr_last_error`) if the options are bad or the
+/// accelerometer can't be opened.
+///
+/// # Safety
+/// `options` must be NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn spinnr_detector_new(options: *const c_char) -> Option<Box<SpinnrDetector>> {
+    let options = if options.is_null() {
+        ""
+    } else {
+        match CStr::from_ptr(options).to_str() {
+            Ok(s)   => s,
+            Err(_)  => {
+                set_error("options aren't UTF-8".to_owned());
+                return None;
+            },
+        }
+    };
+    match DetectorOptions::parse(options).and_then(SpinnrDetector::new) {
+        Ok(det) => Some(Box::new(det)),
+        Err(e)  => {
+            set_error(e);
+            None
+        },
+    }
+}
+
+/// Free a detector. Passing NULL does nothing.
+#[no_mangle]
+pub extern "C" fn spinnr_detector_free(detector: Option<Box<SpinnrDetector>>) {
+    drop(detector);
+}
+
+/// Pass a `source=feed` detector a sample (in m/s^2; up, right and towards
+/// the viewer are positive), and return its rotation.
+#[no_mangle]
+pub extern "C" fn spinnr_detector_feed(detector: Option<&mut SpinnrDetector>,
+                                       x: f64, y: f64, z: f64) -> SpinnrRotation {
+    let det = match detector {
+        Some(d) => d,
+        None    => return SpinnrRotation::None,
+    };
+    match det.sample {
+        Some(ref sample)    => sample.set(AccelerationVector { x, y, z }),
+        None    => {
+            set_error("can't feed a detector that polls".to_owned());
+            return det.rotation.into();
+        },
+    }
+    det.update().into()
+}
+
+/// Have a `source=poll` detector read its accelerometer, and return its
+/// rotation. Call it every so often (spinnrd polls every 150ms).
+#[no_mangle]
+pub extern "C" fn spinnr_detector_poll(detector: Option<&mut SpinnrDetector>) -> SpinnrRotation {
+    match detector {
+        Some(det) if det.sample.is_none()   => det.update().into(),
+        Some(det)   => {
+            set_error("can't poll a detector that's fed".to_owned());
+            det.rotation.into()
+        },
+        None    => SpinnrRotation::None,
+    }
+}
+
+/// The last clear rotation the detector saw
+#[no_mangle]
+pub extern "C" fn spinnr_detector_rotation(detector: Option<&SpinnrDetector>) -> SpinnrRotation {
+    detector.and_then(|d| d.rotation).into()
+}
+
+/// Write the latest raw and filtered acceleration to `raw` and `filtered`
+/// (either may be NULL). Returns false if there hasn't been a sample yet.
+#[no_mangle]
+pub extern "C" fn spinnr_detector_vector(detector: Option<&SpinnrDetector>,
+                                         raw: Option<&mut SpinnrVector>,
+                                         filtered: Option<&mut SpinnrVector>) -> bool {
+    let orient = match detector.and_then(|d| d.orient.as_ref()) {
+        Some(o) => o,
+        None    => return false,
+    };
+    match (orient.raw_vector(), orient.filtered_vector()) {
+        (Some(r), Some(f))  => {
+            if let Some(raw) = raw { *raw = r.into(); }
+            if let Some(filtered) = filtered { *filtered = f.into(); }
+            true
+        },
+        _   => false,
+    }
+}
+
+/// Set the sensitivity. Returns false if the detector can't change it.
+#[no_mangle]
+pub extern "C" fn spinnr_detector_set_sensitivity(detector: Option<&mut SpinnrDetector>,
+                                                  sensitivity: f64) -> bool {
+    match detector {
+        Some(det)   => {
+            det.sensitivity = sensitivity;
+            det.orient.as_mut().is_none_or(|o| Orientator::set_sensitivity(o, sensitivity))
+        },
+        None    => false,
+    }
+}
+
+/// Call `callback` (from inside `spinnr_detector_feed` or
+/// `spinnr_detector_poll`) whenever the rotation changes. A NULL callback
+/// stops the calls.
+#[no_mangle]
+pub extern "C" fn spinnr_detector_set_callback(detector: Option<&mut SpinnrDetector>,
+                                               callback: SpinnrCallback,
+                                               user_data: *mut c_void) {
+    if let Some(det) = detector {
+        det.callback = callback;
+        det.user_data = user_data;
+    }
+}
+
+/// A rotation's name (`"normal"`, `"left"`, `"inverted"`, `"right"` or
+/// `"none"`), as a static string
+#[no_mangle]
+pub extern "C" fn spinnr_rotation_name(rotation: SpinnrRotation) -> *const c_char {
+    let name: &'static [u8] = match rotation {
+        SpinnrRotation::None    => b"none\0",
+        SpinnrRotation::Normal  => b"normal\0",
+        SpinnrRotation::Left    => b"left\0",
+        SpinnrRotation::Inverted    => b"inverted\0",
+        SpinnrRotation::Right   => b"right\0",
+    };
+    name.as_ptr() as *const c_char
+}
+
+/// Why the last call on this thread failed, or NULL. The string is valid
+/// until the next failure on this thread.
+#[no_mangle]
+pub extern "C" fn spinnr_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |s| s.as_ptr()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Gravity with the device turned `angle` degrees
+    fn feed(det: &mut SpinnrDetector, angle: f64) -> SpinnrRotation {
+        let rad = angle.to_radians();
+        spinnr_detector_feed(Some(det), 9.81 * rad.sin(), -9.81 * rad.cos(), 0.0)
+    }
+
+    fn fed(options: &str) -> Box<SpinnrDetector> {
+        let options = CString::new(options).unwrap();
+        unsafe { spinnr_detector_new(options.as_ptr()) }.unwrap()
+    }
+
+    extern "C" fn count(_: SpinnrRotation, calls: *mut c_void) {
+        unsafe { *(calls as *mut u32) += 1 };
+    }
+
+    /// `include/spinnr.h` is checked in rather than written by the build;
+    /// `SPINNR_WRITE_HEADER=1 cargo test --lib header` updates it.
+    #[test]
+    fn header() {
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", dir)).unwrap();
+        let mut generated = Vec::new();
+        cbindgen::Builder::new()
+            .with_config(config)
+            .with_src(format!("{}/src/capi.rs", dir))
+            .with_src(format!("{}/src/plugin.rs", dir))
+            .generate()
+            .unwrap()
+            .write(&mut generated);
+        let path = format!("{}/include/spinnr.h", dir);
+        if std::env::var_os("SPINNR_WRITE_HEADER").is_some() {
+            std::fs::write(&path, &generated).unwrap();
+        }
+        assert!(std::fs::read(&path).unwrap() == generated,
+                "include/spinnr.h is out of date; SPINNR_WRITE_HEADER=1 cargo test --lib header updates it");
+    }
+
+    #[test]
+    fn feeding() {
+        let mut det = fed("source=feed, filter=1");
+        assert_eq!(spinnr_detector_rotation(Some(&det)), SpinnrRotation::None);
+        assert!(!spinnr_detector_vector(Some(&det), None, None));
+        assert_eq!(feed(&mut det, 0.0), SpinnrRotation::Normal);
+        assert_eq!(feed(&mut det, 90.0), SpinnrRotation::Left);
+        // Flat on its back: no clear orientation, so it stays left.
+        assert_eq!(spinnr_detector_feed(Some(&mut det), 0.0, 0.0, 9.81), SpinnrRotation::Left);
+        let mut raw = SpinnrVector::default();
+        assert!(spinnr_detector_vector(Some(&det), Some(&mut raw), None));
+        assert_eq!(raw.z, 9.81);
+    }
+
+    #[test]
+    fn callback() {
+        let mut det = fed("source=feed,filter=1");
+        let mut calls = 0u32;
+        spinnr_detector_set_callback(Some(&mut det), Some(count), &mut calls as *mut u32 as *mut c_void);
+        feed(&mut det, 0.0);
+        feed(&mut det, 5.0);
+        feed(&mut det, 180.0);
+        assert_eq!(calls, 2);
+        assert_eq!(spinnr_detector_rotation(Some(&det)), SpinnrRotation::Inverted);
+    }
+
+    #[test]
+    fn bad_options() {
+        for opts in &["source=feed,filter=0", "source=sideways", "frobnicate=1", "sensitivity"] {
+            let c = CString::new(*opts).unwrap();
+            assert!(unsafe { spinnr_detector_new(c.as_ptr()) }.is_none(), "{}", opts);
+            assert!(!spinnr_last_error().is_null());
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! # clock
+//! Where the main loop gets the time from, so tests can run it against a
+//! clock they control.
+
+use super::*;
+
+/// Something that tells the time. Frontend workers get a copy each, so it
+/// has to be cheap to clone and sendable to their threads.
+pub trait Clock: Clone + Send + 'static {
+    fn now(&self) -> Instant;
+}
+
+/// The real, monotonic clock
+#[derive(Debug,Clone,Copy,Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when it's told to. Clones share the time.
+#[cfg(test)]
+#[derive(Debug,Clone)]
+pub struct VirtualClock {
+    now: std::sync::Arc<std::sync::Mutex<Instant>>,
+}
+
+#[cfg(test)]
+impl VirtualClock {
+    pub fn new() -> VirtualClock {
+        VirtualClock {
+            now: std::sync::Arc::new(std::sync::Mutex::new(Instant::now())),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
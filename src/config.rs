@@ -0,0 +1,479 @@
+//! # config
+//! Layered configuration. Each setting comes from the first of these that
+//! has it:
+//!
+//! 1. the command line
+//! 2. `SPINNRD_*` environment variables (e.g. `SPINNRD_FLIP_DELAY`)
+//! 3. the user's config file (`$XDG_CONFIG_HOME/spinnrd.toml`)
+//! 4. the system config file (`/etc/spinnrd.toml`)
+//! 5. the built-in default
+//!
+//! `--config FILE` replaces both config files. Backend and frontend options
+//! can also be given in `[backend_options.NAME]` and
+//! `[frontend_options.NAME]` sections, which are merged option by option.
+
+use super::*;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::read_to_string;
+use toml::Value;
+
+/// The system-wide config file
+pub const SYSTEM_CONFIG_FILE: &str = "/etc/spinnrd.toml";
+
+/// The user's config file, under `$XDG_CONFIG_HOME` (or `~/.config`)
+const USER_CONFIG_FILE: &str = "spinnrd.toml";
+
+/// Prefix for environment variables that set options
+const ENV_PREFIX: &str = "SPINNRD_";
+
+/// How a setting's value is written
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Kind {
+    /// One value; an array is joined with commas
+    Single,
+    /// A list of values; a string is split on the separator
+    List(char),
+    /// On or off
+    Flag,
+    /// Option strings, or sections of options by backend or frontend
+    Options,
+}
+
+/// Config keys, the command line arguments they stand for, and their kinds
+const KEYS: &[(&str, &str, Kind)] = &[
+    ("quiet",               "quiet",            Kind::Flag),
+    ("daemonize",           "daemonize",        Kind::Flag),
+    ("interval",            "period",           Kind::Single),
+    ("hysteresis",          "hysteresis",       Kind::Single),
+    ("sensitivity",         "sensitivity",      Kind::Single),
+    ("delay",               "delay",            Kind::Single),
+    ("flip_delay",          "flipdelay",        Kind::Single),
+    ("motion_threshold",    "motionthreshold",  Kind::Single),
+    ("veto_window",         "vetowindow",       Kind::Single),
+    ("allowed",             "allowed",          Kind::Single),
+    ("offset",              "offset",           Kind::Single),
+    ("suggest_only",        "suggestonly",      Kind::Flag),
+    ("backend",             "backend",          Kind::List(';')),
+    ("backend_options",     "backend_opts",     Kind::Options),
+    ("frontend",            "frontend",         Kind::List(';')),
+    ("frontend_options",    "frontend_opts",    Kind::Options),
+    ("working_directory",   "workingdir",       Kind::Single),
+    ("pid_file",            "pidfile",          Kind::Single),
+    ("no_pid_file",         "nopidfile",        Kind::Flag),
+    ("spin_file",           "spinfile",         Kind::Single),
+    ("log_file",            "logfile",          Kind::Single),
+    ("log_level",           "loglvl",           Kind::Single),
+    ("control_socket",      "control",          Kind::Single),
+    ("control_mode",        "controlmode",      Kind::Single),
+    ("no_control_socket",   "nocontrol",        Kind::Flag),
+    ("lock_file",           "lockfile",         Kind::Single),
+    ("lock_input",          "lockinput",        Kind::List(',')),
+    ("no_lock_input",       "nolockinput",      Kind::Flag),
+    ("plugin_dir",          "plugindir",        Kind::Single),
+    ("policy_script",       "policyscript",     Kind::Single),
+    ("user",                "user",             Kind::Single),
+    ("group",               "group",            Kind::Single),
+    ("keep_caps",           "keepcaps",         Kind::List(',')),
+];
+
+/// Where a setting came from
+#[derive(Debug,Clone,PartialEq)]
+pub enum Source {
+    Default,
+    File(PathBuf),
+    /// An environment variable
+    Env(String),
+    Cli,
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            Source::Default => write!(f, "default"),
+            Source::File(ref p) => write!(f, "{}", p.to_string_lossy()),
+            Source::Env(ref v)  => write!(f, "${}", v),
+            Source::Cli => write!(f, "command line"),
+        }
+    }
+}
+
+/// Errors reading the config
+#[derive(Debug)]
+pub enum ConfigError {
+    /// Couldn't read a config file
+    Io(IoError, PathBuf),
+    /// A config file isn't valid TOML
+    Parse(toml::de::Error, PathBuf),
+    /// A config file sets something it can't, or sets it to the wrong type
+    BadKey(String, PathBuf),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            ConfigError::Io(ref e, ref p)   => {
+                write!(f, "couldn't read config file '{}': {}", p.to_string_lossy(), e)
+            },
+            ConfigError::Parse(ref e, ref p)    => {
+                write!(f, "couldn't parse config file '{}': {}", p.to_string_lossy(), e)
+            },
+            ConfigError::BadKey(ref k, ref p)   => {
+                write!(f, "bad setting '{}' in config file '{}'", k, p.to_string_lossy())
+            },
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn description(&self) -> &str {
+        "couldn't read the config"
+    }
+
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            ConfigError::Io(ref e, _)   => Some(e),
+            ConfigError::Parse(ref e, _)    => Some(e),
+            ConfigError::BadKey(..) => None,
+        }
+    }
+}
+
+/// Options for each backend or frontend, and where each came from
+type Sections = BTreeMap<String, BTreeMap<String, (String, Source)>>;
+
+/// The merged configuration
+#[derive(Debug,Default)]
+pub struct Config {
+    /// Each argument's values, and where they came from
+    values: HashMap<&'static str, (Vec<String>, Source)>,
+    /// `[backend_options.*]` and `[frontend_options.*]`, by argument
+    sections: HashMap<&'static str, Sections>,
+}
+
+impl Config {
+    /// Just the command line `args` and their defaults
+    fn from_args(args: &ArgMatches) -> Config {
+        let mut config = Config::default();
+        for &(_, arg, kind) in KEYS {
+            let source = if 0 < args.occurrences_of(arg) {
+                Source::Cli
+            } else {
+                Source::Default
+            };
+            if Kind::Flag == kind {
+                if args.is_present(arg) {
+                    config.values.insert(arg, (vec!["true".to_owned()], source));
+                }
+            } else if let Some(vs) = args.values_of(arg) {
+                config.values.insert(arg, (vs.flat_map(|v| split(kind, v)).collect(), source));
+            }
+        }
+        config
+    }
+
+    /// Read every layer, with `args` as the command line and `vars` as the
+    /// environment.
+    fn load(args: &ArgMatches, vars: &HashMap<String, String>) -> Result<Config, ConfigError> {
+        let cli = Config::from_args(args);
+        // Start with the defaults, and let each layer override the last
+        let mut config = Config::default();
+        for (&arg, (vs, source)) in &cli.values {
+            if Source::Default == *source {
+                config.values.insert(arg, (vs.clone(), Source::Default));
+            }
+        }
+        for path in config_files(args)? {
+            config.read_file(&path)?;
+        }
+        config.read_env(vars);
+        for (arg, (vs, source)) in cli.values {
+            if Source::Cli == source {
+                config.values.insert(arg, (vs, source));
+            }
+        }
+        Ok(config)
+    }
+
+    /// Merge in a config file.
+    fn read_file(&mut self, path: &PathBuf) -> Result<(), ConfigError> {
+        let text = read_to_string(path).map_err(|e| ConfigError::Io(e, path.clone()))?;
+        let table = match text.parse::<Value>() {
+            Ok(Value::Table(t)) => t,
+            Ok(_)   => return Err(ConfigError::BadKey(String::new(), path.clone())),
+            Err(e)  => return Err(ConfigError::Parse(e, path.clone())),
+        };
+        let source = Source::File(path.clone());
+        for (key, value) in table {
+            let bad = || ConfigError::BadKey(key.clone(), path.clone());
+            let &(_, arg, kind) = KEYS.iter().find(|k| k.0 == key).ok_or_else(bad)?;
+            match (kind, value) {
+                (Kind::Options, Value::Table(sections))  => {
+                    let merged = self.sections.entry(arg).or_default();
+                    for (name, options) in sections {
+                        let options = match options {
+                            Value::Table(o) => o,
+                            _   => return Err(bad()),
+                        };
+                        let merged = merged.entry(name).or_default();
+                        for (option, v) in options {
+                            let v = scalar(&v).ok_or_else(bad)?;
+                            merged.insert(option, (v, source.clone()));
+                        }
+                    }
+                },
+                (Kind::Flag, Value::Boolean(b)) => {
+                    self.values.insert(arg, (vec![b.to_string()], source.clone()));
+                },
+                (Kind::Flag, _) => return Err(bad()),
+                (kind, Value::Array(vs))    => {
+                    let mut strings = vs.iter().map(scalar).collect::<Option<Vec<String>>>()
+                        .ok_or_else(bad)?;
+                    if Kind::Single == kind {
+                        strings = vec![strings.join(",")];
+                    }
+                    self.values.insert(arg, (strings, source.clone()));
+                },
+                (kind, v)   => {
+                    let s = scalar(&v).ok_or_else(bad)?;
+                    self.values.insert(arg, (split(kind, &s), source.clone()));
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn value(&self, arg: &str) -> Option<String> {
+        self.values(arg).and_then(|vs| vs.into_iter().next())
+    }
+
+    fn values(&self, arg: &str) -> Option<Vec<String>> {
+        self.values.get(arg).map(|v| v.0.clone())
+    }
+
+    fn flag(&self, arg: &str) -> bool {
+        Some("true") == self.value(arg).as_deref()
+    }
+
+    /// Merge in the `SPINNRD_*` variables in `vars`.
+    fn read_env(&mut self, vars: &HashMap<String, String>) {
+        for &(key, arg, kind) in KEYS {
+            let var = format!("{}{}", ENV_PREFIX, key.to_uppercase());
+            if let Some(s) = vars.get(&var) {
+                let vs = if Kind::Flag == kind {
+                    let on = ["1", "true", "yes", "on"].contains(&s.to_lowercase().as_str());
+                    vec![on.to_string()]
+                } else {
+                    split(kind, s)
+                };
+                self.values.insert(arg, (vs, Source::Env(var)));
+            }
+        }
+    }
+}
+
+impl Display for Config {
+    /// Shows the config as TOML, noting where each value came from.
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        for &(key, arg, kind) in KEYS {
+            if let Some((vs, source)) = self.values.get(arg) {
+                let value = match kind {
+                    Kind::Flag  => Value::Boolean("true" == vs[0]),
+                    Kind::Single    => to_value(&vs[0]),
+                    _   => Value::Array(vs.iter().map(|v| to_value(v)).collect()),
+                };
+                writeln!(f, "{} = {}  # {}", key, value, source)?;
+            }
+        }
+        for &(key, arg, _) in KEYS {
+            for (name, options) in self.sections.get(arg).into_iter().flat_map(|s| s.iter()) {
+                writeln!(f, "\n[{}.{}]", key, name)?;
+                for (option, (v, source)) in options {
+                    writeln!(f, "{} = {}  # {}", option, to_value(v), source)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A TOML value as an option string
+fn scalar(v: &Value) -> Option<String> {
+    match *v {
+        Value::String(ref s)    => Some(s.clone()),
+        Value::Integer(i)   => Some(i.to_string()),
+        Value::Float(x) => Some(x.to_string()),
+        Value::Boolean(b)   => Some(b.to_string()),
+        _   => None,
+    }
+}
+
+/// An option string as a TOML value, for printing
+fn to_value(s: &str) -> Value {
+    // Leading zeroes matter (modes are octal), so those stay strings
+    let zero_padded = s.starts_with('0') && s.len() > 1 && ! s.starts_with("0.");
+    match (s.parse::<i64>(), s.parse::<f64>()) {
+        (Ok(i), _) if ! zero_padded => Value::Integer(i),
+        (_, Ok(x)) if ! zero_padded => Value::Float(x),
+        _   => Value::String(s.to_owned()),
+    }
+}
+
+/// Split a string into a list's values. Escaped separators (`\;`) are
+/// left for the list's consumer to unescape.
+fn split(kind: Kind, s: &str) -> Vec<String> {
+    match kind {
+        Kind::List(sep) => split_unescaped(s, sep),
+        _   => vec![s.to_owned()],
+    }
+}
+
+/// The config files to read, lowest precedence first
+fn config_files(args: &ArgMatches) -> Result<Vec<PathBuf>, ConfigError> {
+    if let Some(path) = args.value_of("config") {
+        return Ok(vec![PathBuf::from(path)]);
+    }
+    let mut files = vec![PathBuf::from(SYSTEM_CONFIG_FILE)];
+    let user_dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")));
+    if let Some(dir) = user_dir {
+        files.push(dir.join(USER_CONFIG_FILE));
+    }
+    Ok(files.into_iter().filter(|p| p.is_file()).collect())
+}
+
+lazy_static! {
+    /// The config in effect
+    static ref CONFIG: RwLock<Config> = RwLock::new(Config::from_args(&CLI_ARGS));
+}
+
+/// Read the config again. On failure, the old config stays in effect.
+pub fn reload_config() -> Result<(), ConfigError> {
+    // Variables that aren't Unicode can't be settings anyway
+    let vars = env::vars_os()
+        .filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)))
+        .filter(|(k, _)| k.starts_with(ENV_PREFIX))
+        .collect();
+    let config = Config::load(&CLI_ARGS, &vars)?;
+    *CONFIG.write().unwrap() = config;
+    Ok(())
+}
+
+/// The config in effect, as TOML
+pub fn config_string() -> String {
+    CONFIG.read().unwrap().to_string()
+}
+
+/// The value of the setting for command line argument `arg`
+pub fn config_value(arg: &str) -> Option<String> {
+    CONFIG.read().unwrap().value(arg)
+}
+
+/// The values of the setting for command line argument `arg`
+pub fn config_values(arg: &str) -> Option<Vec<String>> {
+    CONFIG.read().unwrap().values(arg)
+}
+
+/// Whether the flag for command line argument `arg` is on
+pub fn config_flag(arg: &str) -> bool {
+    CONFIG.read().unwrap().flag(arg)
+}
+
+/// The options from config file sections for command line argument `arg`,
+/// by backend or frontend
+pub fn config_sections(arg: &str) -> HashMap<String, HashMap<String, String>> {
+    CONFIG.read().unwrap().sections.get(arg)
+        .map(|s| s.iter()
+             .map(|(name, opts)| (name.clone(), opts.iter()
+                                  .map(|(o, v)| (o.clone(), v.0.clone()))
+                                  .collect()))
+             .collect())
+        .unwrap_or_default()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, toml: &str, args: &[&str], vars: &[(&str, &str)])
+        -> (Result<Config, ConfigError>, PathBuf) {
+        let path = env::temp_dir().join(format!("spinnrd-test-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, toml).unwrap();
+        let mut argv = vec!["spinnrd", "--config", path.to_str().unwrap()];
+        argv.extend_from_slice(args);
+        let vars = vars.iter().map(|&(k, v)| (k.to_owned(), v.to_owned())).collect();
+        let config = Config::load(&cli().get_matches_from(argv), &vars);
+        remove_file(&path).unwrap();
+        (config, path)
+    }
+
+    #[test]
+    fn layers() {
+        let (config, path) = load("layers", r#"
+            delay = 100
+            hysteresis = 20
+            sensitivity = 2.5
+            frontend = ["file", "socket"]
+            lock_input = "/dev/a,/dev/b"
+            suggest_only = true
+            [frontend_options.socket]
+            mode = "0600"
+            "#, &["--delay", "250", "--no-pid-file"],
+            &[("SPINNRD_HYSTERESIS", "30"), ("SPINNRD_DELAY", "999"), ("SPINNRD_DAEMONIZE", "no"),
+              ("HYSTERESIS", "40")]);
+        let config = config.unwrap();
+
+        assert_eq!(config.value("delay").as_deref(), Some("250"));
+        assert_eq!(config.value("hysteresis").as_deref(), Some("30"));
+        assert_eq!(config.value("sensitivity").as_deref(), Some("2.5"));
+        assert_eq!(config.value("workingdir").as_deref(), Some(DEFAULT_WORKING_DIRECTORY));
+        assert_eq!(config.values("frontend"), Some(vec!["file".to_owned(), "socket".to_owned()]));
+        assert_eq!(config.values("lockinput"), Some(vec!["/dev/a".to_owned(), "/dev/b".to_owned()]));
+        assert!(config.flag("suggestonly") && config.flag("nopidfile"));
+        assert!(! config.flag("daemonize"));
+        assert_eq!(config.value("user"), None);
+
+        let printed = config.to_string();
+        let file = path.to_string_lossy();
+        for line in &[
+            "delay = 250  # command line".to_owned(),
+            "hysteresis = 30  # $SPINNRD_HYSTERESIS".to_owned(),
+            format!("sensitivity = 2.5  # {}", file),
+            format!("frontend = [\"file\", \"socket\"]  # {}", file),
+            format!("suggest_only = true  # {}", file),
+            format!("[frontend_options.socket]\nmode = \"0600\"  # {}", file),
+        ] {
+            assert!(printed.contains(line.as_str()), "no '{}' in:\n{}", line, printed);
+        }
+    }
+
+    #[test]
+    fn escaped_args() {
+        let args = cli().get_matches_from(vec![
+            "spinnrd", "--frontend", r"file,path=/tmp/a\;b|fbcon;socket",
+            "--backend", r"fsaccel,path=/x\;y"]);
+        let config = Config::from_args(&args);
+        assert_eq!(config.values("frontend"),
+                   Some(vec![r"file,path=/tmp/a\;b|fbcon".to_owned(), "socket".to_owned()]));
+        assert_eq!(config.values("backend"), Some(vec![r"fsaccel,path=/x\;y".to_owned()]));
+
+        let group = split_unescaped(&config.values("frontend").unwrap()[0], '|');
+        let mut opts = HashMap::new();
+        parse_options(&group[0]["file".len()..], &mut opts);
+        assert_eq!(opts.get("path").map(String::as_str), Some("/tmp/a;b"));
+    }
+
+    #[test]
+    fn bad_files() {
+        for (name, toml) in &[("unknown", "delya = 100"), ("flag", "quiet = \"yes\""),
+                              ("section", "frontend_options = { socket = 3 }")] {
+            match load(name, toml, &[], &[]).0 {
+                Err(ConfigError::BadKey(..))    => {},
+                r   => panic!("{}: {:?}", toml, r),
+            }
+        }
+        assert!(matches!(load("syntax", "delay = ", &[], &[]).0, Err(ConfigError::Parse(..))));
+    }
+}
@@ -0,0 +1,440 @@
+//! # control
+//! The control socket, which lets `spinnrctl` (or anything else that can
+//! write a line to a Unix socket) inspect and adjust a running daemon.
+//!
+//! The protocol is one request per line, answered with one line starting
+//! with `ok` or `err`:
+//!
+//! - `rotation`: the current and last-written rotation, and the lock
+//! - `lock [on|off|toggle]`: show or change the rotation lock
+//! - `vector`: the latest raw and filtered acceleration
+//! - `backend`, `frontends`: what's in use
+//! - `get SETTING`, `set SETTING VALUE`: read or change `delay`,
+//!   `flip_delay`, `motion_threshold`, `hysteresis`, `sensitivity` or
+//!   `veto_window`
+//! - `rotate ROTATION`: send a rotation to the frontends right away
+//! - `veto`: cancel the pending rotation (see `--veto-window`)
+//! - `accept`: commit the suggested or pending rotation now
+//! - `lease pin ROTATION`, `lease allow SET`, `lease forbid SET`: force or
+//!   restrict the rotation until the lease is released or the client
+//!   disconnects (see `lease`). Answered with the lease's id.
+//! - `release ID`: release a lease; `leases`: list them
+//!
+//! A request longer than `MAX_LINE` bytes is answered with an error, and
+//! the client is disconnected.
+
+use super::*;
+
+use std::io::{Read, Write};
+use std::io::ErrorKind as IoErrorKind;
+use std::net::Shutdown;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+
+/// The default control socket
+pub const DEFAULT_CONTROL_SOCKET: &str = "%d/spinnrd.ctl";
+
+/// The default control socket permissions
+pub const DEFAULT_CONTROL_MODE: &str = "600";
+
+/// A setting that can be changed at runtime
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub enum Setting {
+    Delay,
+    FlipDelay,
+    MotionThreshold,
+    Hysteresis,
+    Sensitivity,
+    VetoWindow,
+}
+
+impl Setting {
+    fn from_str(s: &str) -> Option<Setting> {
+        match s {
+            "delay"         => Some(Setting::Delay),
+            "flip_delay"    => Some(Setting::FlipDelay),
+            "motion_threshold"  => Some(Setting::MotionThreshold),
+            "hysteresis"    => Some(Setting::Hysteresis),
+            "sensitivity"   => Some(Setting::Sensitivity),
+            "veto_window"   => Some(Setting::VetoWindow),
+            _               => None,
+        }
+    }
+}
+
+/// Something a control client asked for
+#[derive(Debug,PartialEq)]
+pub enum Request {
+    Rotation,
+    Vector,
+    Backend,
+    Frontends,
+    Get(Setting),
+    Set(Setting, String),
+    Rotate(Rotation),
+    Veto,
+    Accept,
+    /// Show (`None`), set (`Some(Some(_))`) or toggle (`Some(None)`) the 
+    /// rotation lock
+    Lock(Option<Option<bool>>),
+    Lease(Restriction),
+    Release(u32),
+    Leases,
+    /// The client disconnected; sent by the control socket itself
+    Hangup,
+}
+
+impl Request {
+    /// Parse a request line.
+    pub fn parse(line: &str) -> Result<Request, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let setting = |s: &str| Setting::from_str(s)
+            .ok_or_else(|| format!("no such setting '{}'", s));
+        match words.as_slice() {
+            ["rotation"]    => Ok(Request::Rotation),
+            ["vector"]      => Ok(Request::Vector),
+            ["backend"]     => Ok(Request::Backend),
+            ["frontends"]   => Ok(Request::Frontends),
+            ["get", s]      => Ok(Request::Get(setting(s)?)),
+            ["set", s, v]   => Ok(Request::Set(setting(s)?, (*v).to_owned())),
+            ["lock"]        => Ok(Request::Lock(None)),
+            ["lock", "toggle"]  => Ok(Request::Lock(Some(None))),
+            ["lock", l]     => parse_state(l)
+                .map(|l| Request::Lock(Some(Some(l))))
+                .ok_or_else(|| format!("bad lock state '{}'", l)),
+            ["lease", how, what]    => Restriction::parse(how, what)
+                .map(Request::Lease),
+            ["release", id] => id.parse::<u32>()
+                .map(Request::Release)
+                .map_err(|_| format!("bad lease id '{}'", id)),
+            ["leases"]      => Ok(Request::Leases),
+            ["veto"]        => Ok(Request::Veto),
+            ["accept"]      => Ok(Request::Accept),
+            ["rotate", r]   => r.parse::<Rotation>()
+                .map(Request::Rotate)
+                .map_err(|e| format!("bad rotation '{}': {}", r, e)),
+            []  => Err("empty request".to_owned()),
+            _   => Err(format!("unknown request '{}'", line.trim())),
+        }
+    }
+}
+
+/// A request and who it's from
+pub type Query = (ClientId, Request);
+
+/// The reactor token for the listening socket; clients get
+/// `CLIENT_TOKENS` plus their id.
+const LISTENER_TOKEN: Token = 0x100;
+const CLIENT_TOKENS: Token = 1 << 32;
+
+/// The longest request line a client may send, in bytes
+const MAX_LINE: usize = 4096;
+
+/// A connected client, and whatever it's sent that isn't a whole line yet
+struct Client {
+    stream: UnixStream,
+    buf: Vec<u8>,
+}
+
+/// The listening control socket and its clients. Requests come out of
+/// `ready` and are answered with `reply` by whoever holds this.
+pub struct ControlServer {
+    path: PathBuf,
+    listener: UnixListener,
+    clients: HashMap<ClientId, Client>,
+    next_id: ClientId,
+}
+
+impl ControlServer {
+    /// Listen on `path` with permissions `mode`, waiting for clients on
+    /// `reactor`.
+    pub fn init(path: PathBuf, mode: u32, reactor: &Reactor) -> Result<ControlServer, IoError> {
+        let listener = bind_socket(&path, mode, None)?;
+        listener.set_nonblocking(true)?;
+        reactor.add(listener.as_raw_fd(), LISTENER_TOKEN)?;
+        Ok(ControlServer {
+            path,
+            listener,
+            clients: HashMap::new(),
+            next_id: 0,
+        })
+    }
+
+    /// Whether `token` is one of ours
+    pub fn owns(token: Token) -> bool {
+        LISTENER_TOKEN == token || CLIENT_TOKENS <= token
+    }
+
+    /// Deal with whatever woke up `token`: accept new clients, or read
+    /// what a client sent. Returns the requests that need answering; a
+    /// client hanging up is a `Request::Hangup`. Requests that don't parse
+    /// are answered here.
+    pub fn ready(&mut self, token: Token, reactor: &Reactor) -> Vec<Query> {
+        if LISTENER_TOKEN == token {
+            self.accept(reactor);
+            return Vec::new();
+        }
+        let id = token - CLIENT_TOKENS;
+        let (lines, open) = match self.clients.get_mut(&id) {
+            Some(c) => c.read(),
+            None    => return Vec::new(),
+        };
+        let mut queries = Vec::new();
+        for line in lines {
+            let req = line.and_then(|l| {
+                trace!("Control request: {}", l);
+                Request::parse(&l)
+            });
+            match req {
+                Ok(req) => queries.push((id, req)),
+                Err(e)  => self.reply(id, Err(e)),
+            }
+        }
+        if ! open {
+            self.clients.remove(&id);
+            queries.push((id, Request::Hangup));
+        }
+        queries
+    }
+
+    /// Answer a client's request.
+    pub fn reply(&mut self, id: ClientId, answer: Result<String, String>) {
+        let written = match (self.clients.get_mut(&id), answer) {
+            (Some(c), Ok(s))    => writeln!(c.stream, "ok {}", s),
+            (Some(c), Err(s))   => writeln!(c.stream, "err {}", s),
+            (None, _)   => return,
+        };
+        if let Err(e) = written {
+            // A client that won't take its answers gets dropped; its
+            // hangup comes through `ready`.
+            debug!("Control client error ({})", e);
+            if let Some(c) = self.clients.get(&id) {
+                let _ = c.stream.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    /// Take every client that's waiting.
+    fn accept(&mut self, reactor: &Reactor) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    let id = self.next_id;
+                    self.next_id += 1;
+                    let added = stream.set_nonblocking(true)
+                        .and_then(|_| reactor.add(stream.as_raw_fd(), CLIENT_TOKENS + id));
+                    match added {
+                        Ok(_)   => {
+                            self.clients.insert(id, Client { stream, buf: Vec::new() });
+                        },
+                        Err(e)  => warn!("Couldn't watch control client ({})", e),
+                    }
+                },
+                Err(ref e) if e.kind() == IoErrorKind::WouldBlock   => return,
+                Err(e)  => {
+                    warn!("Error accepting control client ({})", e);
+                    return;
+                },
+            }
+        }
+    }
+}
+
+impl Client {
+    /// Read what's waiting, returning the whole lines, and whether the
+    /// client's still connected. A line that's too long comes back as an
+    /// error, and the client is let go.
+    fn read(&mut self) -> (Vec<Result<String, String>>, bool) {
+        let mut chunk = [0u8; 1024];
+        let mut open = loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0)   => break false,
+                Ok(n)   => {
+                    self.buf.extend_from_slice(&chunk[..n]);
+                    // No point reading any more of a line that's too long
+                    let start = self.buf.iter().rposition(|&b| b'\n' == b).map_or(0, |i| i + 1);
+                    if self.buf.len() - start > MAX_LINE {
+                        break false;
+                    }
+                },
+                Err(ref e) if e.kind() == IoErrorKind::WouldBlock   => break true,
+                Err(ref e) if e.kind() == IoErrorKind::Interrupted  => {},
+                Err(e)  => {
+                    debug!("Control client error ({})", e);
+                    break false;
+                },
+            }
+        };
+        let mut lines = Vec::new();
+        while let Some(end) = self.buf.iter().position(|&b| b'\n' == b).filter(|&e| e <= MAX_LINE) {
+            let line: Vec<u8> = self.buf.drain(..=end).collect();
+            lines.push(Ok(String::from_utf8_lossy(&line[..end]).into_owned()));
+        }
+        if MAX_LINE < self.buf.len() {
+            lines.push(Err(format!("request too long (the limit is {} bytes)", MAX_LINE)));
+            self.buf.clear();
+            open = false;
+        }
+        (lines, open)
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        if let Err(e) = remove_file(&self.path) {
+            warn!("Couldn't remove control socket '{}' ({})",
+                  self.path.to_string_lossy(), e);
+        }
+    }
+}
+
+impl Display for ControlServer {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}", self.path.to_string_lossy())
+    }
+}
+
+/// Start the control socket, unless it's turned off.
+pub fn init_control(reactor: &Reactor) -> Option<ControlServer> {
+    if config_flag("nocontrol") {
+        return None;
+    }
+    let path = get_path("control", DEFAULT_CONTROL_SOCKET, false);
+    let modestr = config_value("controlmode").unwrap_or_else(|| DEFAULT_CONTROL_MODE.to_owned());
+    let mode = match u32::from_str_radix(&modestr, 8) {
+        Ok(m)   => m,
+        Err(e)  => {
+            error!("Bad control socket mode '{}' ({}); not listening.", modestr, e);
+            return None;
+        },
+    };
+    match ControlServer::init(path.clone(), mode, reactor) {
+        Ok(c)   => {
+            info!("Listening for control requests on {}", c);
+            Some(c)
+        },
+        Err(e)  => {
+            error!("Couldn't open control socket '{}' ({})", path.to_string_lossy(), e);
+            None
+        },
+    }
+}
+
+/// Format an optional value for an answer
+pub fn opt<D: Display>(o: Option<D>) -> String {
+    o.map_or_else(|| "none".to_owned(), |d| d.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+
+    #[test]
+    fn requests() {
+        let good = vec![
+            ("rotation", Request::Rotation),
+            ("  vector  ", Request::Vector),
+            ("backend", Request::Backend),
+            ("frontends", Request::Frontends),
+            ("get flip_delay", Request::Get(Setting::FlipDelay)),
+            ("set veto_window 200", Request::Set(Setting::VetoWindow, "200".to_owned())),
+            // Values are checked when they're applied
+            ("set sensitivity lots", Request::Set(Setting::Sensitivity, "lots".to_owned())),
+            ("lock", Request::Lock(None)),
+            ("lock toggle", Request::Lock(Some(None))),
+            ("lock on", Request::Lock(Some(Some(true)))),
+            ("lock unlocked", Request::Lock(Some(Some(false)))),
+            ("lease pin left", Request::Lease(Restriction::Pin(Rotation::Left))),
+            ("lease forbid portrait", Request::Lease(Restriction::Allow(vec![Rotation::Normal, Rotation::Inverted]))),
+            ("release 3", Request::Release(3)),
+            ("leases", Request::Leases),
+            ("veto", Request::Veto),
+            ("accept", Request::Accept),
+            ("rotate inverted", Request::Rotate(Rotation::Inverted)),
+        ];
+        for (line, req) in good {
+            assert_eq!(Request::parse(line), Ok(req), "{}", line);
+        }
+        let bad = vec![
+            ("", "empty request"),
+            ("spin", "unknown request 'spin'"),
+            ("ROTATION", "unknown request 'ROTATION'"),
+            ("get", "unknown request 'get'"),
+            ("get speed", "no such setting 'speed'"),
+            ("set delay", "unknown request 'set delay'"),
+            ("set delay 1 2", "unknown request 'set delay 1 2'"),
+            ("rotate", "unknown request 'rotate'"),
+            ("rotate up", "bad rotation 'up': expected normal, left, inverted or right"),
+            ("lock maybe", "bad lock state 'maybe'"),
+            ("lease pin", "unknown request 'lease pin'"),
+            ("lease hold left", "unknown lease type 'hold'"),
+            ("release", "unknown request 'release'"),
+            ("release one", "bad lease id 'one'"),
+            ("veto now", "unknown request 'veto now'"),
+        ];
+        for (line, err) in bad {
+            assert_eq!(Request::parse(line), Err(err.to_owned()), "{}", line);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = std::env::temp_dir().join(format!("spinnrd-test-control-rt-{}.ctl", std::process::id()));
+        let reactor = Reactor::new().unwrap();
+        let mut server = ControlServer::init(path.clone(), 0o600, &reactor).unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut answers = BufReader::new(client.try_clone().unwrap());
+        server.ready(LISTENER_TOKEN, &reactor);
+
+        let mut ask = |line: &str, answer: Option<Result<String, String>>| {
+            writeln!(client, "{}", line).unwrap();
+            let queries = server.ready(CLIENT_TOKENS, &reactor);
+            if let Some(a) = answer {
+                assert_eq!(queries.len(), 1, "{}", line);
+                server.reply(queries[0].0, a);
+            } else {
+                assert!(queries.is_empty(), "{}", line);
+            }
+            let mut reply = String::new();
+            answers.read_line(&mut reply).unwrap();
+            (queries.into_iter().next().map(|q| q.1), reply)
+        };
+        assert_eq!(ask("get delay", Some(Ok("350".to_owned()))),
+                   (Some(Request::Get(Setting::Delay)), "ok 350\n".to_owned()));
+        assert_eq!(ask("set delay 500", Some(Ok("500".to_owned()))),
+                   (Some(Request::Set(Setting::Delay, "500".to_owned())), "ok 500\n".to_owned()));
+        assert_eq!(ask("set delay soon", Some(Err("bad delay 'soon'".to_owned()))),
+                   (Some(Request::Set(Setting::Delay, "soon".to_owned())), "err bad delay 'soon'\n".to_owned()));
+        assert_eq!(ask("rotate left", Some(Ok("left".to_owned()))),
+                   (Some(Request::Rotate(Rotation::Left)), "ok left\n".to_owned()));
+        // Answered by the server itself
+        assert_eq!(ask("rotate", None), (None, "err unknown request 'rotate'\n".to_owned()));
+
+        drop(client);
+        drop(answers);
+        assert_eq!(server.ready(CLIENT_TOKENS, &reactor), vec![(0, Request::Hangup)]);
+    }
+
+    #[test]
+    fn long_lines_hang_up() {
+        let path = std::env::temp_dir().join(format!("spinnrd-test-control-{}.ctl", std::process::id()));
+        let reactor = Reactor::new().unwrap();
+        let mut server = ControlServer::init(path.clone(), 0o600, &reactor).unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        server.ready(LISTENER_TOKEN, &reactor);
+
+        // Right at the limit, so far
+        write!(client, "veto\n{}", "x".repeat(MAX_LINE)).unwrap();
+        assert_eq!(server.ready(CLIENT_TOKENS, &reactor), vec![(0, Request::Veto)]);
+        writeln!(client, "x").unwrap();
+        assert_eq!(server.ready(CLIENT_TOKENS, &reactor), vec![(0, Request::Hangup)]);
+
+        let mut answers = String::new();
+        BufReader::new(client).read_to_string(&mut answers).unwrap();
+        assert_eq!(answers, format!("err request too long (the limit is {} bytes)\n", MAX_LINE));
+    }
+}
@@ -0,0 +1,121 @@
+//! fbcon.rs
+//!
+//! A frontend for rotating the Linux framebuffer console through sysfs.
+
+use super::*;
+
+use std::fs::OpenOptions;
+
+/// The default sysfs mount point
+pub const DEFAULT_FBCON_ROOT: &str = "/sys";
+
+/// Where the fbcon attributes live, relative to the sysfs root
+const FBCON_CLASS_PATH: &str = "class/graphics/fbcon";
+
+/// The attribute that rotates the current console
+const FBCON_ROTATE: &str = "rotate";
+
+/// The attribute that rotates every console
+const FBCON_ROTATE_ALL: &str = "rotate_all";
+
+/// Whether to rotate all consoles by default
+pub const DEFAULT_FBCON_ALL: &str = "false";
+
+/// The fbcon frontend's options
+pub const FBCON_OPTIONS: Schema = &[
+    OptSpec {
+        name: "root",
+        kind: OptType::Path,
+        default: Some(DEFAULT_FBCON_ROOT),
+        help: "The sysfs mount point.",
+    },
+    OptSpec {
+        name: "all",
+        kind: OptType::Bool,
+        default: Some(DEFAULT_FBCON_ALL),
+        help: "Whether to rotate every console (rotate_all) instead of just \
+            the current one (rotate).",
+    },
+];
+
+/// Writes rotations to the fbcon `rotate` or `rotate_all` sysfs attribute.
+pub struct FbconSender {
+    path: PathBuf,
+}
+
+impl FbconSender {
+    /// Create a new `FbconSender` under the sysfs root `root`, writing to
+    /// `rotate_all` if `all` is set and `rotate` otherwise.
+    pub fn init(root: PathBuf, all: bool) -> InitResult<FbconSender> {
+        let path = root.join(FBCON_CLASS_PATH)
+            .join(if all { FBCON_ROTATE_ALL } else { FBCON_ROTATE });
+        // sysfs attributes can't be created, only written
+        if let Err(e) = OpenOptions::new().write(true).open(&path) {
+            return Err(FrontendError::Fbcon(e, path));
+        }
+        Ok(FbconSender {
+            path,
+        })
+    }
+}
+
+impl Display for FbconSender {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "FbconSender to {}", self.path.to_string_lossy())
+    }
+}
+
+/// Translate a rotation into the kernel's fbcon encoding (the number of
+/// 90° clockwise turns, i.e. `FB_ROTATE_UR`, `FB_ROTATE_CW`,
+/// `FB_ROTATE_UD`, `FB_ROTATE_CCW`).
+pub fn fbcon_rotation(rot: Rotation) -> u8 {
+    match rot {
+        Rotation::Normal    => 0,
+        Rotation::Right     => 1,
+        Rotation::Inverted  => 2,
+        Rotation::Left      => 3,
+    }
+}
+
+impl Frontend for FbconSender {
+    fn send(&mut self, orientation: Rotation) -> SendResult {
+        OpenOptions::new()
+            .write(true)
+            .open(&self.path)
+            .and_then(|mut f| write!(f, "{}", fbcon_rotation(orientation)))
+            .map_err(SendError::IoError)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fake sysfs with empty fbcon attributes
+    fn sysfs(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("spinnrd-test-{}-{}", name, std::process::id()));
+        let class = root.join(FBCON_CLASS_PATH);
+        std::fs::create_dir_all(&class).unwrap();
+        for attr in &[FBCON_ROTATE, FBCON_ROTATE_ALL] {
+            File::create(class.join(attr)).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn writes_turns() {
+        let root = sysfs("fbcon");
+        for &all in &[false, true] {
+            let path = root.join(FBCON_CLASS_PATH)
+                .join(if all { FBCON_ROTATE_ALL } else { FBCON_ROTATE });
+            let mut sender = FbconSender::init(root.clone(), all).unwrap();
+            for (rot, turns) in Rotation::ALL.iter().zip(&["0", "3", "2", "1"]) {
+                sender.send(*rot).unwrap();
+                assert_eq!(&std::fs::read_to_string(&path).unwrap(), turns);
+            }
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+        assert!(FbconSender::init(root, false).is_err());
+    }
+}
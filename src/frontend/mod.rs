@@ -8,80 +8,425 @@ use std::io::Error as IoError;
 use std::fs::File;
 use std::io::Write;
 
+use spinnr::plugin::{PluginFrontend, PluginError, SpinnrPluginKind};
+
+mod fbcon;
+mod policy;
+mod socket;
+mod worker;
+pub use self::fbcon::FbconSender;
+pub use self::socket::{SocketSender, bind_socket};
+pub use self::policy::{SendPolicy, OnError};
+use self::worker::Worker;
 
 // #[cfg(feature = "x11")]
 // type XSender = ???;
 // #[cfg(not(feature = "x11"))]
 // type XSender = DummySender;
 
-type SendResult = Result<(), SendError>;
+/// The frontend registry. Each entry declares a frontend once: its
+/// `FrontendKind` variant and type, its name, the cargo feature it needs
+/// (if any), its options (besides the send policy's), a line of help, and a
+/// function that builds it from its options. Frontends whose feature is off
+/// still get a name, so asking for them says so.
+macro_rules! frontends {
+    ( $(
+        $variant:ident($t:ty) {
+            name: $name:expr,
+            $(feature: $feature:tt,)*
+            options: $schema:expr,
+            help: $help:expr,
+            init: $init:expr $(,)*
+        }
+    ),+ $(,)* ) => {
+        pub enum FrontendKind {
+            $(
+                $(#[cfg(feature = $feature)])*
+                $variant($t),
+            )+
+            /// A frontend from a plugin
+            Plugin(PluginFrontend),
+        }
+
+        impl Frontend for FrontendKind {
+            fn send(&mut self, orientation: Rotation) -> SendResult {
+                match *self {
+                    $(
+                        $(#[cfg(feature = $feature)])*
+                        FrontendKind::$variant(ref mut f)    => f.send(orientation),
+                    )+
+                    FrontendKind::Plugin(ref mut f)    => f.send(orientation),
+                }
+            }
 
-pub fn frontend_help() -> String {
-    format!("{}", file_sender_help())
+            fn announce(&mut self, announcement: Announcement) -> SendResult {
+                match *self {
+                    $(
+                        $(#[cfg(feature = $feature)])*
+                        FrontendKind::$variant(ref mut f)    => f.announce(announcement),
+                    )+
+                    FrontendKind::Plugin(ref mut f)    => f.announce(announcement),
+                }
+            }
+        }
+
+        impl Display for FrontendKind {
+            fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+                match *self {
+                    $(
+                        $(#[cfg(feature = $feature)])*
+                        FrontendKind::$variant(ref f)    => write!(fmt, "{}", f),
+                    )+
+                    FrontendKind::Plugin(ref f)    => write!(fmt, "{}", f),
+                }
+            }
+        }
+
+        /// Initialize a single frontend and its send policy.
+        fn init_spec(spec: &FrontendSpec) -> InitResult<(FrontendKind, SendPolicy)> {
+            let &(ref frontend, ref opts) = spec;
+            match frontend.as_str() {
+                $(
+                    $(#[cfg(feature = $feature)])*
+                    $name => {
+                        let o = Options::parse($name, &[policy::POLICY_OPTIONS, $schema], opts)
+                            .map_err(FrontendError::Options)?;
+                        let f = $init(&o)?;
+                        Ok((FrontendKind::$variant(f), SendPolicy::from_opts(&o)))
+                    },
+                    $(
+                        #[cfg(not(feature = $feature))]
+                        $name => Err(FrontendError::NotCompiled($name)),
+                    )*
+                )+
+                _   => init_plugin(frontend, opts),
+            }
+        }
+
+        pub fn frontend_help() -> String {
+            let mut entries: Vec<(&str, &str, Schema)> = Vec::new();
+            $(
+                $(#[cfg(feature = $feature)])*
+                entries.push(($name, $help, $schema));
+            )+
+            format!("{}{}", policy::policy_help(), schemas_help(&entries))
+        }
+    }
 }
 
-fn file_sender_help() -> String {
-    format!("
-    For File:
-        path: The path to the spinfile. Defaults to {}.\n",
-        DEFAULT_SPINFILE
-        )
+frontends! {
+    File(FileSender) {
+        name: "file",
+        options: FILE_OPTIONS,
+        help: "Writes each rotation to a file.",
+        init: |o: &Options| FileSender::init(
+            o.path("path").unwrap_or_else(|| PathBuf::from(parse_path(DEFAULT_SPINFILE, false)))),
+    },
+    Fbcon(FbconSender) {
+        name: "fbcon",
+        options: fbcon::FBCON_OPTIONS,
+        help: "Rotates the Linux framebuffer console.",
+        init: |o: &Options| FbconSender::init(
+            o.path("root").unwrap_or_else(|| PathBuf::from(fbcon::DEFAULT_FBCON_ROOT)),
+            o.flag("all")),
+    },
+    Socket(SocketSender) {
+        name: "socket",
+        options: socket::SOCKET_OPTIONS,
+        help: "Sends rotations to the clients of a Unix socket.",
+        init: init_socket,
+    },
+    // X11(XSender) {
+    //     name: "x11",
+    //     feature: "x11",
+    //     ...
+    // },
 }
-        
 
-#[derive(Debug)]
-pub enum SendError {
-    IoError(IoError),
+/// The file frontend's options
+const FILE_OPTIONS: Schema = &[
+    OptSpec {
+        name: "path",
+        kind: OptType::Path,
+        default: Some(DEFAULT_SPINFILE),
+        help: "The path to the spinfile.",
+    },
+];
+
+/// A frontend in a `Frontends` set, along with its error handling state.
+struct Member<C: Clock> {
+    /// The frontend's description, since the frontend lives on its worker
+    name: String,
+    worker: Worker,
+    policy: SendPolicy,
+    /// Frontends to fail over to, in order
+    alternates: Vec<FrontendSpec>,
+    last_sent: Option<Rotation>,
+    /// The last rotation handed to the worker that hasn't been answered
+    in_flight: Option<Rotation>,
+    /// When a stuck send was last counted as timed out
+    timed_out: Option<Instant>,
+    /// Consecutive failures
+    failures: u32,
+    total_failures: u64,
+    sent: u64,
+    retry_at: Option<Instant>,
+    disabled: bool,
+    clock: C,
 }
 
-impl Display for SendError {
-    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            &SendError::IoError(ref e)  => {
-                write!(fmt, "io error sending rotation: {}", e)
+impl<C: Clock> Member<C> {
+    fn new<F>(frontend: F, policy: SendPolicy, alternates: Vec<FrontendSpec>, clock: C) -> Member<C>
+        where F: Frontend + Display + Send + 'static {
+        Member {
+            name: frontend.to_string(),
+            worker: Worker::spawn(frontend, clock.clone()),
+            policy,
+            alternates,
+            last_sent: None,
+            in_flight: None,
+            timed_out: None,
+            failures: 0,
+            total_failures: 0,
+            sent: 0,
+            retry_at: None,
+            disabled: false,
+            clock,
+        }
+    }
+
+    /// Hand `orientation` to the worker.
+    fn dispatch(&mut self, orientation: Rotation) {
+        self.in_flight = Some(orientation);
+        self.worker.post(orientation);
+    }
+
+    /// Collect finished and timed out sends, applying the member's policy
+    /// to failures. Returns each error and whether it should stop the
+    /// daemon.
+    fn collect(&mut self, now: Instant) -> Vec<(SendError, bool)> {
+        let mut errs = Vec::new();
+        loop {
+            match self.worker.result() {
+                Ok(Some((rot, Ok(_))))  => {
+                    if 0 < self.failures {
+                        info!("{} recovered after {} failures ({} total)",
+                              self.name, self.failures, self.total_failures);
+                    }
+                    if self.in_flight == Some(rot) { self.in_flight = None; }
+                    self.last_sent = Some(rot);
+                    self.failures = 0;
+                    self.retry_at = None;
+                    self.timed_out = None;
+                    self.sent += 1;
+                },
+                Ok(Some((rot, Err(e)))) => {
+                    if self.in_flight == Some(rot) { self.in_flight = None; }
+                    self.timed_out = None;
+                    let fatal = self.on_failure(rot, now);
+                    errs.push((e, fatal));
+                    if self.disabled { return errs; }
+                },
+                Ok(None)    => break,
+                Err(e)  => {
+                    // Nothing more can be sent through a dead worker.
+                    let fatal = OnError::Quit == self.policy.on_error;
+                    if ! (OnError::Failover == self.policy.on_error && self.fail_over()) {
+                        error!("Disabling {}: {}", self.name, e);
+                        self.disabled = true;
+                    }
+                    errs.push((e, fatal));
+                    return errs;
+                },
+            }
+        }
+        if let Some(rot) = self.in_flight {
+            let timeout = self.policy.timeout;
+            let stuck_since = match (self.worker.busy_since(), self.timed_out) {
+                (Some(b), Some(t))  => Some(if t > b { t } else { b }),
+                (b, _)  => b,
+            };
+            if stuck_since.map_or(false, |t| now.duration_since(t) >= timeout) {
+                self.timed_out = Some(now);
+                let fatal = self.on_failure(rot, now);
+                errs.push((SendError::Timeout(timeout), fatal));
+            }
+        }
+        errs
+    }
+
+    /// Apply the policy after a failure at `now`; returns true if we should
+    /// quit.
+    fn on_failure(&mut self, orientation: Rotation, now: Instant) -> bool {
+        self.failures += 1;
+        self.total_failures += 1;
+        let exhausted = self.failures >= self.policy.max_failures;
+        match self.policy.on_error {
+            OnError::Quit   => return true,
+            OnError::Disable if exhausted   => {
+                error!("Disabling {} after {} consecutive failures ({} total)",
+                       self.name, self.failures, self.total_failures);
+                self.disabled = true;
+                return false;
+            },
+            OnError::Failover if exhausted  => {
+                if self.fail_over() {
+                    return false;
+                }
+                error!("Disabling {}: no frontend left to fail over to",
+                       self.name);
+                self.disabled = true;
+                return false;
             },
+            _   => {},
         }
+        let wait = self.policy.backoff(self.failures);
+        warn!("{} failed to send {} ({} consecutive, {} total failures); retrying in {}ms",
+              self.name, orientation, self.failures, self.total_failures,
+              wait.as_secs() * 1000 + u64::from(wait.subsec_millis()));
+        self.retry_at = Some(now + wait);
+        false
     }
-}
 
-impl std::error::Error for SendError {
-    fn description(&self) -> &str {
-        "error sending rotation"
+    /// Replace the frontend with the first alternate that initializes.
+    fn fail_over(&mut self) -> bool {
+        while ! self.alternates.is_empty() {
+            let spec = self.alternates.remove(0);
+            match init_spec(&spec) {
+                Ok((frontend, policy))  => {
+                    warn!("{} failed {} times; failing over to {}",
+                          self.name, self.failures, frontend);
+                    let alternates = self.alternates.split_off(0);
+                    *self = Member::new(frontend, policy, alternates, self.clock.clone());
+                    return true;
+                },
+                Err(e)  => warn!("Error initializing frontend: {}", e),
+            }
+        }
+        false
     }
 
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        match self {
-            &SendError::IoError(ref e)  => Some(e),
+    /// Hand `announcement` to the worker. Announcements aren't retried,
+    /// so they don't count against the policy.
+    fn announce(&self, announcement: Announcement) {
+        if ! self.disabled {
+            self.worker.announce(announcement);
         }
     }
-}
 
+    /// Whether the member should be handed `orientation` now
+    fn wants(&self, orientation: Rotation, now: Instant) -> bool {
+        ! self.disabled
+            && Some(orientation) != self.last_sent
+            && Some(orientation) != self.in_flight
+            && self.retry_at.map_or(true, |t| t <= now)
+    }
+}
 
-pub trait Frontend {
-    fn send(&mut self, Rotation) -> SendResult;
+/// Every configured frontend; each one gets every rotation sent to the set.
+///
+/// Each frontend sends from its own worker thread, so sending never blocks;
+/// results (and timeouts) are picked up by the next `send` or `poll`.
+pub struct Frontends<C: Clock> {
+    members: Vec<Member<C>>,
+    current: Option<Rotation>,
+    /// What backoff and timeouts are measured by
+    clock: C,
 }
 
-//FIXME: why does this need display???
-pub enum FrontendKind {
-    File(FileSender),
-    //X11(???),
+impl<C: Clock> Frontends<C> {
+    fn new(members: Vec<Member<C>>, clock: C) -> Frontends<C> {
+        Frontends {
+            members,
+            current: None,
+            clock,
+        }
+    }
+
+    /// No frontends at all, for shutting down after losing them all
+    pub fn empty(clock: C) -> Frontends<C> {
+        Frontends::new(Vec::new(), clock)
+    }
+
+    /// Collect send results, and retry the current rotation on members
+    /// whose backoff is over.
+    pub fn poll(&mut self) -> SendResult {
+        let now = self.clock.now();
+        let mut errs = Vec::new();
+        let mut fatal = false;
+        for m in self.members.iter_mut() {
+            let name = m.name.clone();
+            for (e, f) in m.collect(now) {
+                errs.push((name.clone(), e));
+                fatal |= f;
+            }
+            // A member that failed over wants the rotation right away
+            if let Some(r) = self.current {
+                if m.wants(r, now) {
+                    m.dispatch(r);
+                }
+            }
+        }
+        if errs.is_empty() {
+            Ok(())
+        } else if fatal {
+            Err(SendError::Fatal(Box::new(SendError::Members(errs))))
+        } else {
+            Err(SendError::Members(errs))
+        }
+    }
+
+    /// Log how many rotations each member sent and failed to send.
+    pub fn log_counters(&self) {
+        for m in self.members.iter() {
+            info!("{}: {} rotations sent, {} failures{}",
+                  m.name, m.sent, m.total_failures,
+                  if m.disabled { " (disabled)" } else { "" });
+        }
+    }
 }
 
-impl Frontend for FrontendKind {
+impl<C: Clock> Frontend for Frontends<C> {
+    /// Hands `orientation` to every member that doesn't have it yet, so a
+    /// retry after a partial failure doesn't repeat successful writes.
+    /// A new rotation is tried right away even on members that are
+    /// backing off.
     fn send(&mut self, orientation: Rotation) -> SendResult {
-        match self {
-            &mut FrontendKind::File(ref mut s)  => s.send(orientation),
+        if self.current != Some(orientation) {
+            self.current = Some(orientation);
+            for m in self.members.iter_mut() {
+                m.retry_at = None;
+            }
         }
+        self.poll()
+    }
+
+    /// Tells every member about `announcement`. Announcements go through
+    /// the workers and aren't retried, so this doesn't fail.
+    fn announce(&mut self, announcement: Announcement) -> SendResult {
+        for m in self.members.iter() {
+            m.announce(announcement);
+        }
+        Ok(())
     }
 }
 
-impl std::fmt::Display for FrontendKind {
+impl<C: Clock> std::fmt::Display for Frontends<C> {
     fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            &FrontendKind::File(ref fs)   => {
-                write!(fmt, "FileSender to {}", fs.to_string_lossy())
-            },
+        for (i, m) in self.members.iter().enumerate() {
+            if 0 < i { write!(fmt, ", ")?; }
+            write!(fmt, "{}", m.name)?;
         }
+        Ok(())
+    }
+}
+
+/// Keep signals away from helper threads, so they reach the main loop's trap.
+pub fn block_signals() {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigfillset(&mut set);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
     }
 }
 
@@ -105,8 +450,11 @@ impl FileSender {
             path: path,
         })
     }
-    pub fn to_string_lossy(&self) -> std::borrow::Cow<str> {
-        self.path.to_string_lossy()
+}
+
+impl Display for FileSender {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "FileSender to {}", self.path.to_string_lossy())
     }
 }
 
@@ -127,7 +475,13 @@ pub enum FrontendError {
     #[allow(dead_code)]
     NotCompiled(&'static str),
     NoSuchFrontend(String),
+    /// A frontend option was unknown or had an invalid value
+    Options(OptionError),
     FileSender(IoError, PathBuf),
+    Fbcon(IoError, PathBuf),
+    Socket(IoError, PathBuf),
+    /// A plugin frontend couldn't start
+    Plugin(PluginError),
     // X11(???),
 }
 
@@ -140,65 +494,95 @@ impl Display for FrontendError {
             &FrontendError::NoSuchFrontend(ref s)   => {
                 write!(fmt, "frontend '{}' does not exist!", s)
             },
+            &FrontendError::Options(ref e)  => write!(fmt, "{}", e),
             &FrontendError::FileSender(ref e, ref p)    => {
                 write!(fmt, "can't use file '{}' ({})", p.to_string_lossy(), e)
             },
+            &FrontendError::Fbcon(ref e, ref p)    => {
+                write!(fmt, "can't use fbcon attribute '{}' ({})", p.to_string_lossy(), e)
+            },
+            &FrontendError::Socket(ref e, ref p)   => {
+                write!(fmt, "can't listen on socket '{}' ({})", p.to_string_lossy(), e)
+            },
+            FrontendError::Plugin(e)   => write!(fmt, "{}", e),
         }
     }
 }
 
 impl std::error::Error for FrontendError {
     fn description(&self) -> &str {
-        "couldn't initialize backend"
+        "couldn't initialize frontend"
     }
 
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             &FrontendError::NotCompiled(_)  => None,
             &FrontendError::NoSuchFrontend(_)   => None,
+            &FrontendError::Options(ref e)  => Some(e),
             &FrontendError::FileSender(ref e, _)   => Some(e),
+            &FrontendError::Fbcon(ref e, _)    => Some(e),
+            &FrontendError::Socket(ref e, _)   => Some(e),
+            FrontendError::Plugin(e)   => Some(e),
         }
     }
 }
 
 type InitResult<T> = Result<T, FrontendError>;
 
-macro_rules! frinit {
-    ( $tomatch:ident, $opts:ident: $( $name:expr, $init:ident );+ $(;)* ) => {
-        match $tomatch.as_str() {
-            $(
-                $name => {
-                    if ! $opts.contains_key($name) {
-                        $opts.insert($name.to_owned(), HashMap::new());
-                    }
-                    $init($opts.get_mut($name).unwrap())
-                }),*,
-                _     => Err(FrontendError::NoSuchFrontend($tomatch)),
+/// Initialize every frontend group, using the first frontend in each group
+/// that initializes. The rest of the group is kept for failing over.
+/// Backoff and send timeouts go by `clock`.
+pub fn init_frontend<C: Clock>(groups: Vec<Vec<FrontendSpec>>, clock: C) -> Result<Frontends<C>, i32> {
+    let mut members = Vec::new();
+    for mut group in groups {
+        let mut member = None;
+        while ! group.is_empty() {
+            let spec = group.remove(0);
+            match init_spec(&spec) {
+                Ok((frontend, policy))  => {
+                    member = Some(Member::new(frontend, policy, group, clock.clone()));
+                    break;
+                },
+                Err(e)  => warn!("Error initializing frontend: {}", e),
+            }
         }
-    }
-}
-/// Initialize a frontend
-pub fn init_frontend() -> Result<FrontendKind, i32> {
-    let (frontends, mut opts) = get_frontend_options();
-    for frontend in frontends {
-        let last_output = frinit!(frontend, opts:
-            // "x11", init_x11;
-            "file", init_file;
-            );
-        match last_output {
-            Ok(o)   => return Ok(o),
-            Err(e)  => warn!("Error initializing backend: {}", e),
+        match member {
+            Some(m) => members.push(m),
+            None    => error!("No frontend in group initialized!"),
         }
     }
-    return Err(ERR_NO_FRONTEND);
+    if members.is_empty() {
+        Err(ERR_NO_FRONTEND)
+    } else {
+        Ok(Frontends::new(members, clock))
+    }
 }
 
-fn init_file(opts: &mut HashMap<String, String>) -> InitResult<FrontendKind> {
-    let def = DEFAULT_SPINFILE.to_owned();
-    Ok(FrontendKind::File(FileSender::init(
-        PathBuf::from(parse_path(opts.get("path").unwrap_or(&def),false))
-        )?))
+/// Initialize a frontend from a plugin, if there's one called `frontend`.
+/// The send policy's options are taken out; the rest go to the plugin.
+fn init_plugin(frontend: &str, opts: &HashMap<String, String>)
+    -> InitResult<(FrontendKind, SendPolicy)> {
+    let plugin = find_plugin(frontend, SpinnrPluginKind::Frontend)
+        .ok_or_else(|| FrontendError::NoSuchFrontend(frontend.to_owned()))?;
+    let (policy, rest): (HashMap<String, String>, HashMap<String, String>) = opts.iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .partition(|(k, _)| policy::POLICY_OPTIONS.iter().any(|s| s.name == k));
+    let policy = Options::parse(frontend, &[policy::POLICY_OPTIONS], &policy)
+        .map_err(FrontendError::Options)?;
+    let f = plugin.open_frontend(&rest).map_err(FrontendError::Plugin)?;
+    Ok((FrontendKind::Plugin(f), SendPolicy::from_opts(&policy)))
 }
 
-
-
+fn init_socket(opts: &Options) -> InitResult<SocketSender> {
+    let gid = match opts.get("group") {
+        Some(g) => Some(socket::parse_group(g)
+            .ok_or_else(|| FrontendError::Options(opts.invalid("group", "a group name or GID")))?),
+        None    => None,
+    };
+    SocketSender::init(
+        opts.path("path").unwrap_or_else(|| PathBuf::from(parse_path(socket::DEFAULT_SOCKET_PATH, false))),
+        opts.octal("mode").unwrap_or(0o666),
+        gid,
+        opts.flag("announce")
+        )
+}
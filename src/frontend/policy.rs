@@ -0,0 +1,245 @@
+//! policy.rs
+//!
+//! What to do when a frontend fails to send a rotation.
+
+use super::*;
+
+use std::time::Duration;
+
+/// Options every frontend takes
+pub const POLICY_OPTIONS: Schema = &[
+    OptSpec {
+        name: "on_error",
+        kind: OptType::Choice(&["retry", "disable", "quit", "failover"]),
+        default: Some("retry"),
+        help: "What to do when sending a rotation fails: 'retry' with \
+            exponential backoff, 'disable' the frontend or 'failover' to the \
+            next frontend in its '|' group after max_failures consecutive \
+            failures, or 'quit'.",
+    },
+    OptSpec {
+        name: "max_failures",
+        kind: OptType::Uint,
+        default: Some("5"),
+        help: "Consecutive failures before disabling or failing over.",
+    },
+    OptSpec {
+        name: "backoff",
+        kind: OptType::Uint,
+        default: Some("1000"),
+        help: "Milliseconds to wait before the first retry. Doubles with \
+            every consecutive failure.",
+    },
+    OptSpec {
+        name: "max_backoff",
+        kind: OptType::Uint,
+        default: Some("60000"),
+        help: "The longest to wait between retries, in milliseconds.",
+    },
+    OptSpec {
+        name: "timeout",
+        kind: OptType::Uint,
+        default: Some("5000"),
+        help: "Milliseconds a send may take before it counts as a failure. \
+            Sends happen on a separate thread, so a slow frontend never \
+            holds up polling.",
+    },
+];
+
+/// What a frontend does when sending fails
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub enum OnError {
+    /// Keep retrying, backing off exponentially
+    Retry,
+    /// Retry, but give up on the frontend after `max_failures` failures
+    Disable,
+    /// Quit the daemon
+    Quit,
+    /// Retry, but switch to the next frontend in the failover group after
+    /// `max_failures` failures
+    Failover,
+}
+
+impl OnError {
+    fn from_str(s: &str) -> Option<OnError> {
+        match s {
+            "retry"     => Some(OnError::Retry),
+            "disable"   => Some(OnError::Disable),
+            "quit"      => Some(OnError::Quit),
+            "failover"  => Some(OnError::Failover),
+            _           => None,
+        }
+    }
+}
+
+/// How a frontend handles send errors
+#[derive(Debug,Clone,Copy)]
+pub struct SendPolicy {
+    pub on_error: OnError,
+    pub max_failures: u32,
+    pub timeout: Duration,
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl SendPolicy {
+    /// Read the policy from a frontend's options.
+    pub fn from_opts(opts: &Options) -> SendPolicy {
+        let ms = |name| Duration::from_millis(opts.uint(name).unwrap_or(0));
+        SendPolicy {
+            on_error: opts.get("on_error").and_then(OnError::from_str).unwrap_or(OnError::Retry),
+            max_failures: opts.uint("max_failures").map_or(u32::MAX, |n| n.min(u64::from(u32::MAX)) as u32),
+            timeout: ms("timeout"),
+            backoff: ms("backoff"),
+            max_backoff: ms("max_backoff"),
+        }
+    }
+
+    /// How long to wait before retrying after `failures` consecutive failures.
+    pub fn backoff(&self, failures: u32) -> Duration {
+        // Doubling more than 31 times overflows long before max_backoff
+        // could matter.
+        let shift = failures.saturating_sub(1).min(31);
+        self.backoff.checked_mul(1 << shift)
+            .map_or(self.max_backoff, |d| d.min(self.max_backoff))
+    }
+}
+
+pub fn policy_help() -> String {
+    schema_help("all frontends", POLICY_OPTIONS)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(opts: &[(&str, &str)]) -> SendPolicy {
+        let raw = opts.iter().map(|&(k, v)| (k.to_owned(), v.to_owned())).collect();
+        SendPolicy::from_opts(&Options::parse("test", &[POLICY_OPTIONS], &raw).unwrap())
+    }
+
+    /// A file frontend's spec, writing to `path`
+    fn file_spec(path: &Path, opts: &[(&str, &str)]) -> FrontendSpec {
+        let mut raw: HashMap<String, String> = opts.iter()
+            .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+            .collect();
+        raw.insert("path".to_owned(), path.to_string_lossy().into_owned());
+        ("file".to_owned(), raw)
+    }
+
+    fn dir(name: &str) -> PathBuf {
+        let d = std::env::temp_dir().join(format!("spinnrd-test-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&d).unwrap();
+        d
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let p = policy(&[("backoff", "100"), ("max_backoff", "1000")]);
+        let ms: Vec<u128> = [1, 2, 3, 4, 5, 40, u32::MAX].iter()
+            .map(|&n| p.backoff(n).as_millis())
+            .collect();
+        assert_eq!(ms, vec![100, 200, 400, 800, 1000, 1000, 1000]);
+        let p = policy(&[]);
+        assert_eq!(p.backoff(1), Duration::from_secs(1));
+        assert_eq!(p.backoff(7), Duration::from_secs(60));
+    }
+
+    /// Poll until the first member is `done`. Only the worker thread is
+    /// waited for; the clock doesn't move.
+    fn wait_for<F>(frontends: &mut Frontends<VirtualClock>, done: F)
+        where F: Fn(&Member<VirtualClock>) -> bool {
+        let start = Instant::now();
+        while ! done(&frontends.members[0]) {
+            assert!(start.elapsed() < Duration::from_secs(5), "the worker never answered");
+            thread::sleep(Duration::from_millis(1));
+            let _ = frontends.poll();
+        }
+    }
+
+    #[test]
+    fn gives_up_after_max_failures() {
+        let d = dir("policy");
+        let clock = VirtualClock::new();
+        let member = |on_error| {
+            let spec = file_spec(&d.join("spin"), &[("on_error", on_error), ("max_failures", "3")]);
+            let (f, p) = init_spec(&spec).unwrap();
+            Member::new(f, p, Vec::new(), clock.clone())
+        };
+
+        let mut m = member("disable");
+        assert!(! m.on_failure(Rotation::Left, clock.now()) && ! m.disabled);
+        assert_eq!(m.retry_at, Some(clock.now() + Duration::from_secs(1)));
+        assert!(! m.on_failure(Rotation::Left, clock.now()) && ! m.disabled);
+        assert_eq!(m.retry_at, Some(clock.now() + Duration::from_secs(2)));
+        assert!(! m.on_failure(Rotation::Left, clock.now()) && m.disabled);
+
+        // Nothing to fail over to
+        let mut m = member("failover");
+        for _ in 0..3 { m.on_failure(Rotation::Left, clock.now()); }
+        assert!(m.disabled);
+
+        let mut m = member("retry");
+        for _ in 0..10 { assert!(! m.on_failure(Rotation::Left, clock.now())); }
+        assert!(! m.disabled);
+        assert_eq!(m.failures, 10);
+
+        assert!(member("quit").on_failure(Rotation::Left, clock.now()));
+        std::fs::remove_dir_all(&d).unwrap();
+    }
+
+    #[test]
+    fn backs_off_before_retrying() {
+        let d = dir("backoff");
+        let clock = VirtualClock::new();
+        let spec = file_spec(&d.join("spin"), &[("on_error", "retry"), ("backoff", "100"), ("max_backoff", "150")]);
+        let mut frontends = init_frontend(vec![vec![spec]], clock.clone()).unwrap();
+        std::fs::remove_dir_all(&d).unwrap();
+
+        let _ = frontends.send(Rotation::Left);
+        wait_for(&mut frontends, |m| 1 == m.failures);
+        clock.advance(Duration::from_millis(99));
+        let _ = frontends.poll();
+        assert_eq!(frontends.members[0].in_flight, None);
+
+        clock.advance(Duration::from_millis(1));
+        let _ = frontends.poll();
+        assert_eq!(frontends.members[0].in_flight, Some(Rotation::Left));
+        wait_for(&mut frontends, |m| 2 == m.failures);
+        assert_eq!(frontends.members[0].retry_at, Some(clock.now() + Duration::from_millis(150)));
+
+        // A new rotation doesn't wait out the backoff
+        let _ = frontends.send(Rotation::Right);
+        assert_eq!(frontends.members[0].in_flight, Some(Rotation::Right));
+    }
+
+    #[test]
+    fn fails_over_to_next() {
+        let first = dir("failover-first");
+        let last = dir("failover-last");
+        let group = vec![
+            file_spec(&first.join("spin"), &[("on_error", "failover"), ("max_failures", "2"), ("backoff", "10")]),
+            // Skipped, since it can't start
+            file_spec(&first.join("gone").join("spin"), &[]),
+            file_spec(&last.join("spin"), &[]),
+        ];
+        let clock = VirtualClock::new();
+        let mut frontends = init_frontend(vec![group], clock.clone()).unwrap();
+        assert!(frontends.to_string().contains("failover-first"));
+        std::fs::remove_dir_all(&first).unwrap();
+
+        let _ = frontends.send(Rotation::Left);
+        wait_for(&mut frontends, |m| 1 == m.failures);
+        assert!(frontends.to_string().contains("failover-first"));
+
+        // The retry fails too, which fails over and sends to the next one
+        clock.advance(Duration::from_millis(10));
+        let _ = frontends.poll();
+        wait_for(&mut frontends, |m| 1 == m.sent);
+        assert!(frontends.to_string().contains("failover-last"));
+        assert_eq!(frontends.members[0].failures, 0);
+        assert_eq!(std::fs::read_to_string(last.join("spin")).unwrap(), "left");
+        std::fs::remove_dir_all(&last).unwrap();
+    }
+}
@@ -0,0 +1,336 @@
+//! socket.rs
+//!
+//! A frontend that broadcasts rotations to every client of a Unix stream
+//! socket.
+
+use super::*;
+
+use std::ffi::{CString, CStr};
+use std::fs::{Permissions, set_permissions};
+use std::os::unix::ffi::OsStrExt;
+use std::io::ErrorKind;
+use std::net::Shutdown;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+
+/// The default socket path
+pub const DEFAULT_SOCKET_PATH: &str = "%d/spinnrd.sock";
+
+/// The default socket permissions
+pub const DEFAULT_SOCKET_MODE: &str = "666";
+
+/// Whether to send announcements by default
+pub const DEFAULT_SOCKET_ANNOUNCE: &str = "false";
+
+/// The socket frontend's options
+pub const SOCKET_OPTIONS: Schema = &[
+    OptSpec {
+        name: "path",
+        kind: OptType::Path,
+        default: Some(DEFAULT_SOCKET_PATH),
+        help: "The path to the Unix socket. Clients get the current rotation \
+            when they connect, then a line per change.",
+    },
+    OptSpec {
+        name: "mode",
+        kind: OptType::Octal,
+        default: Some(DEFAULT_SOCKET_MODE),
+        help: "The socket's permissions, in octal.",
+    },
+    OptSpec {
+        name: "group",
+        kind: OptType::Text,
+        default: None,
+        help: "The group (name or GID) to give the socket.",
+    },
+    OptSpec {
+        name: "announce",
+        kind: OptType::Bool,
+        default: Some(DEFAULT_SOCKET_ANNOUNCE),
+        help: "Whether to also send 'pending ROTATION', 'suggest ROTATION' \
+            and 'cancel ROTATION' lines (see --veto-window and \
+            --suggest-only).",
+    },
+];
+
+/// How long a write to a client may block before the client is dropped (in ms)
+const CLIENT_WRITE_TIMEOUT: u64 = 1000;
+
+/// Reactor tokens for the listening thread
+const LISTENER_TOKEN: Token = 0;
+const STOP_TOKEN: Token = 1;
+
+/// What the sender and its listening thread share.
+struct Subscribers {
+    /// The last rotation sent, for new clients
+    current: Option<Rotation>,
+    clients: Vec<UnixStream>,
+}
+
+impl Subscribers {
+    /// Write `msg` to every client, dropping the ones that have gone away.
+    fn broadcast<D: Display>(&mut self, msg: D) {
+        let line = format!("{}\n", msg);
+        self.clients.retain(|mut c| match c.write_all(line.as_bytes()) {
+            Ok(_)   => true,
+            Err(e)  => {
+                debug!("Dropping socket client ({})", e);
+                false
+            },
+        });
+    }
+}
+
+/// Writes a line with each rotation to every connected client.
+pub struct SocketSender {
+    path: PathBuf,
+    subscribers: Arc<Mutex<Subscribers>>,
+    /// Whether clients get announcements too
+    announce: bool,
+    /// Tells the listening thread to stop
+    stop: Arc<Waker>,
+    listening: Option<thread::JoinHandle<()>>,
+}
+
+impl SocketSender {
+    /// Listen on `path`, with permissions `mode` and (if given) group `gid`.
+    pub fn init(path: PathBuf, mode: u32, gid: Option<libc::gid_t>, announce: bool) -> InitResult<SocketSender> {
+        let err = |e| FrontendError::Socket(e, path.clone());
+        let listener = bind_socket(&path, mode, gid).map_err(err)?;
+        // The listener is only ever accepted from when it's ready, and the
+        // thread stops when `stop` is woken.
+        let stop = Arc::new(Waker::new().map_err(err)?);
+        let reactor = listener.set_nonblocking(true)
+            .and_then(|_| Reactor::new())
+            .and_then(|r| r.add(listener.as_raw_fd(), LISTENER_TOKEN).map(|_| r))
+            .and_then(|r| r.add(stop.fd(), STOP_TOKEN).map(|_| r))
+            .map_err(err)?;
+        let subscribers = Arc::new(Mutex::new(Subscribers {
+            current: None,
+            clients: Vec::new(),
+        }));
+        let theirs = subscribers.clone();
+        let listening = thread::Builder::new()
+            .name(format!("socket {}", path.to_string_lossy()))
+            .spawn(move || {
+                block_signals();
+                accept_clients(reactor, listener, theirs)
+            })
+            .map_err(err)?;
+        Ok(SocketSender {
+            path,
+            subscribers,
+            announce,
+            stop,
+            listening: Some(listening),
+        })
+    }
+}
+
+impl Display for SocketSender {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "SocketSender on {}", self.path.to_string_lossy())
+    }
+}
+
+impl Frontend for SocketSender {
+    fn send(&mut self, orientation: Rotation) -> SendResult {
+        let mut subs = self.subscribers.lock().unwrap();
+        subs.current = Some(orientation);
+        subs.broadcast(orientation);
+        trace!("{} socket clients", subs.clients.len());
+        Ok(())
+    }
+
+    fn announce(&mut self, announcement: Announcement) -> SendResult {
+        if self.announce {
+            self.subscribers.lock().unwrap().broadcast(announcement);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SocketSender {
+    /// Stop listening and hang up on every client, so they know to
+    /// reconnect (to whatever replaces this).
+    fn drop(&mut self) {
+        self.stop.wake();
+        if let Some(h) = self.listening.take() {
+            let _ = h.join();
+        }
+        if let Ok(mut subs) = self.subscribers.lock() {
+            for c in subs.clients.drain(..) {
+                let _ = c.shutdown(Shutdown::Both);
+            }
+        }
+        if let Err(e) = remove_file(&self.path) {
+            warn!("Couldn't remove socket '{}' ({})", self.path.to_string_lossy(), e);
+        }
+    }
+}
+
+/// Bind a listening socket at `path`, replacing a stale socket if there is
+/// one. A socket something's still listening on isn't stale.
+pub fn bind_socket(path: &PathBuf, mode: u32, gid: Option<libc::gid_t>) -> Result<UnixListener, IoError> {
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if meta.file_type().is_socket() {
+            match UnixStream::connect(path) {
+                Ok(_)   => return Err(IoError::new(ErrorKind::AddrInUse,
+                                                   "something is already listening on it")),
+                Err(ref e) if Some(libc::ECONNREFUSED) == e.raw_os_error()  => {
+                    debug!("Removing stale socket {}", path.to_string_lossy());
+                    remove_file(path)?;
+                },
+                Err(e)  => return Err(e),
+            }
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    set_permissions(path, Permissions::from_mode(mode))?;
+    if let Some(g) = gid {
+        let cpath = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| IoError::new(std::io::ErrorKind::InvalidInput, e))?;
+        if 0 != unsafe { libc::chown(cpath.as_ptr(), !0, g) } {
+            return Err(IoError::last_os_error());
+        }
+    }
+    Ok(listener)
+}
+
+/// Accept clients until told to stop, greeting each with the current
+/// rotation.
+fn accept_clients(reactor: Reactor, listener: UnixListener, subscribers: Arc<Mutex<Subscribers>>) {
+    loop {
+        let ready = match reactor.wait(None) {
+            Ok(r)   => r,
+            Err(e)  => {
+                error!("Error waiting for socket clients; no more will be accepted ({})", e);
+                return;
+            },
+        };
+        if ready.contains(&STOP_TOKEN) {
+            return;
+        }
+        loop {
+            let mut client = match listener.accept() {
+                Ok((c, _))  => c,
+                Err(ref e) if ErrorKind::WouldBlock == e.kind() => break,
+                Err(e)  => {
+                    warn!("Error accepting socket client ({})", e);
+                    break;
+                },
+            };
+            // Clients don't inherit the listener's non-blocking mode.
+            let timeout = Some(Duration::from_millis(CLIENT_WRITE_TIMEOUT));
+            if let Err(e) = client.set_write_timeout(timeout) {
+                warn!("Couldn't set socket client timeout ({})", e);
+                continue;
+            }
+            let mut subs = subscribers.lock().unwrap();
+            if let Some(rot) = subs.current {
+                if let Err(e) = client.write_all(format!("{}\n", rot).as_bytes()) {
+                    debug!("Dropping socket client ({})", e);
+                    continue;
+                }
+            }
+            subs.clients.push(client);
+            debug!("New socket client ({} total)", subs.clients.len());
+        }
+    }
+}
+
+/// Look up a group by name or number.
+pub fn parse_group(group: &str) -> Option<libc::gid_t> {
+    if let Ok(n) = group.parse::<libc::gid_t>() {
+        return Some(n);
+    }
+    let cname = CString::new(group).ok()?;
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::group = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let rc = unsafe {
+        libc::getgrnam_r(cname.as_ptr(), &mut grp, buf.as_mut_ptr(),
+                         buf.len(), &mut result)
+    };
+    if 0 != rc || result.is_null() {
+        None
+    } else {
+        debug!("group {} is {}", unsafe { CStr::from_ptr(grp.gr_name) }.to_string_lossy(), grp.gr_gid);
+        Some(grp.gr_gid)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read};
+
+    fn path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("spinnrd-test-{}-{}.sock", name, std::process::id()))
+    }
+
+    #[test]
+    fn hangs_up_when_dropped() {
+        let p = path("drop");
+        let mut sender = SocketSender::init(p.clone(), 0o600, None, false).unwrap();
+        sender.send(Rotation::Left).unwrap();
+        let client = UnixStream::connect(&p).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut lines = BufReader::new(client);
+        let mut line = String::new();
+        lines.read_line(&mut line).unwrap();
+        assert_eq!(line, "left\n");
+        drop(sender);
+        let mut rest = Vec::new();
+        assert_eq!(lines.read_to_end(&mut rest).unwrap(), 0);
+        assert!(! p.exists());
+    }
+
+    #[test]
+    fn broadcasts_to_everyone_still_there() {
+        let p = path("broadcast");
+        let mut sender = SocketSender::init(p.clone(), 0o600, None, false).unwrap();
+        let clients: Vec<UnixStream> = (0..2).map(|_| UnixStream::connect(&p).unwrap()).collect();
+        let start = Instant::now();
+        while sender.subscribers.lock().unwrap().clients.len() < 2 {
+            assert!(start.elapsed() < Duration::from_secs(5), "clients weren't accepted");
+            thread::sleep(Duration::from_millis(1));
+        }
+        sender.send(Rotation::Right).unwrap();
+        let mut readers: Vec<BufReader<UnixStream>> = clients.into_iter().map(|c| {
+            c.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            BufReader::new(c)
+        }).collect();
+        for r in readers.iter_mut() {
+            let mut line = String::new();
+            r.read_line(&mut line).unwrap();
+            assert_eq!(line, "right\n");
+        }
+
+        drop(readers.remove(0));
+        sender.send(Rotation::Normal).unwrap();
+        let mut line = String::new();
+        readers[0].read_line(&mut line).unwrap();
+        assert_eq!(line, "normal\n");
+        assert_eq!(sender.subscribers.lock().unwrap().clients.len(), 1);
+    }
+
+    #[test]
+    fn leaves_live_sockets_alone() {
+        let p = path("live");
+        let sender = SocketSender::init(p.clone(), 0o600, None, false).unwrap();
+        match bind_socket(&p, 0o600, None) {
+            Err(ref e) if ErrorKind::AddrInUse == e.kind()   => {},
+            r   => panic!("bound over a live socket: {:?}", r),
+        }
+        drop(sender);
+        // A socket nobody's listening on is stale.
+        let stale = UnixListener::bind(&p).unwrap();
+        drop(stale);
+        drop(bind_socket(&p, 0o600, None).unwrap());
+        let _ = remove_file(&p);
+    }
+}
@@ -0,0 +1,249 @@
+//! worker.rs
+//!
+//! Runs a frontend on its own thread, so a slow or hung consumer can't
+//! stall the main loop.
+
+use super::*;
+
+use std::sync::{Arc, Mutex, Condvar};
+
+/// What the main loop and a worker share.
+struct Mailbox {
+    /// The latest rotation the worker hasn't picked up yet. Newer rotations
+    /// replace older ones, so a busy worker only ever catches up with the
+    /// latest.
+    pending: Option<Rotation>,
+    /// The latest announcement the worker hasn't picked up yet; it goes
+    /// out before `pending`.
+    announcement: Option<Announcement>,
+    /// When the worker started the send it's working on, if any
+    busy_since: Option<Instant>,
+    /// Whether the worker should exit once it's done
+    stop: bool,
+}
+
+/// A handle to a frontend running on its own thread.
+pub struct Worker {
+    mailbox: Arc<(Mutex<Mailbox>, Condvar)>,
+    results: mpsc::Receiver<(Rotation, SendResult)>,
+    handle: Option<thread::JoinHandle<()>>,
+    /// The frontend's description, for saying what's left behind
+    name: String,
+}
+
+impl Worker {
+    /// Start a thread that sends whatever is posted to `frontend`, timing
+    /// sends by `clock`.
+    pub fn spawn<F, C>(mut frontend: F, clock: C) -> Worker
+        where F: Frontend + Display + Send + 'static, C: Clock {
+        let mailbox = Arc::new((
+                Mutex::new(Mailbox {
+                    pending: None,
+                    announcement: None,
+                    busy_since: None,
+                    stop: false,
+                }),
+                Condvar::new()));
+        let (tx, rx) = mpsc::channel();
+        let theirs = mailbox.clone();
+        let name = frontend.to_string();
+        let their_name = name.clone();
+        let spawned = thread::Builder::new()
+            .name(format!("frontend {}", name))
+            .spawn(move || {
+                block_signals();
+                let (ref lock, ref cvar) = *theirs;
+                loop {
+                    let (announcement, rot) = {
+                        let mut mb = lock.lock().unwrap();
+                        while mb.pending.is_none() && mb.announcement.is_none() && ! mb.stop {
+                            mb = cvar.wait(mb).unwrap();
+                        }
+                        match (mb.announcement.take(), mb.pending.take()) {
+                            (None, None)    => return,
+                            (a, r)  => {
+                                mb.busy_since = Some(clock.now());
+                                (a, r)
+                            },
+                        }
+                    };
+                    if let Some(a) = announcement {
+                        if let Err(e) = frontend.announce(a) {
+                            debug!("{} couldn't announce '{}' ({})", their_name, a, e);
+                        }
+                    }
+                    let rot = match rot {
+                        Some(r) => r,
+                        None    => {
+                            lock.lock().unwrap().busy_since = None;
+                            continue;
+                        },
+                    };
+                    let result = frontend.send(rot);
+                    lock.lock().unwrap().busy_since = None;
+                    if tx.send((rot, result)).is_err() {
+                        return;
+                    }
+                }
+            });
+        let handle = match spawned {
+            Ok(h)   => Some(h),
+            Err(e)  => {
+                // Without a thread, the results channel hangs up, which is
+                // reported the same as the worker dying.
+                error!("Couldn't start worker for {} ({})", name, e);
+                None
+            },
+        };
+        Worker {
+            mailbox,
+            results: rx,
+            handle,
+            name,
+        }
+    }
+
+    /// Hand `rot` to the worker, replacing anything it hasn't started on.
+    pub fn post(&self, rot: Rotation) {
+        let (ref lock, ref cvar) = *self.mailbox;
+        lock.lock().unwrap().pending = Some(rot);
+        cvar.notify_one();
+    }
+
+    /// Hand `announcement` to the worker, replacing any it hasn't started on.
+    pub fn announce(&self, announcement: Announcement) {
+        let (ref lock, ref cvar) = *self.mailbox;
+        lock.lock().unwrap().announcement = Some(announcement);
+        cvar.notify_one();
+    }
+
+    /// The result of a finished send, if there is one, or an error if the
+    /// worker is gone.
+    pub fn result(&self) -> Result<Option<(Rotation, SendResult)>, SendError> {
+        match self.results.try_recv() {
+            Ok(r)   => Ok(Some(r)),
+            Err(mpsc::TryRecvError::Empty)  => Ok(None),
+            Err(mpsc::TryRecvError::Disconnected)   => Err(SendError::WorkerDied),
+        }
+    }
+
+    /// When the send in progress started, if there is one.
+    pub fn busy_since(&self) -> Option<Instant> {
+        self.mailbox.0.lock().unwrap().busy_since
+    }
+}
+
+impl Drop for Worker {
+    /// Let the worker finish what it has and exit, waiting for it (and
+    /// so for its frontend to clean up) if it's idle. A worker that's busy
+    /// might be stuck, so it's left behind rather than holding up the main
+    /// loop.
+    fn drop(&mut self) {
+        let (ref lock, ref cvar) = *self.mailbox;
+        let idle = match lock.lock() {
+            Ok(mut mb)  => {
+                mb.stop = true;
+                mb.busy_since.is_none() && mb.pending.is_none()
+                    && mb.announcement.is_none()
+            },
+            Err(_)  => false,
+        };
+        cvar.notify_one();
+        if idle {
+            if let Some(h) = self.handle.take() {
+                let _ = h.join();
+            }
+        } else if self.handle.is_some() {
+            warn!("Not waiting for {}, which is still busy", self.name);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frontend whose sends each wait to be let through
+    struct Gated(mpsc::Receiver<()>);
+
+    impl Frontend for Gated {
+        fn send(&mut self, _: Rotation) -> SendResult {
+            let _ = self.0.recv();
+            Ok(())
+        }
+    }
+
+    impl Display for Gated {
+        fn fmt(&self, f: &mut Formatter) -> FmtResult {
+            write!(f, "gated")
+        }
+    }
+
+    fn gated() -> (Gated, mpsc::Sender<()>) {
+        let (tx, rx) = mpsc::channel();
+        (Gated(rx), tx)
+    }
+
+    /// Wait for the worker to be busy (or not).
+    fn wait_busy(worker: &Worker, busy: bool) {
+        let start = Instant::now();
+        while worker.busy_since().is_some() != busy {
+            assert!(start.elapsed() < Duration::from_secs(5), "worker never got {}", if busy { "busy" } else { "idle" });
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn keeps_only_the_latest() {
+        let (frontend, gate) = gated();
+        let worker = Worker::spawn(frontend, SystemClock);
+        worker.post(Rotation::Left);
+        wait_busy(&worker, true);
+        for &r in &[Rotation::Right, Rotation::Inverted, Rotation::Normal] {
+            worker.post(r);
+        }
+        gate.send(()).unwrap();
+        gate.send(()).unwrap();
+        let mut sent = Vec::new();
+        while sent.len() < 2 {
+            match worker.result().unwrap() {
+                Some((r, res))  => { res.unwrap(); sent.push(r); },
+                None    => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        wait_busy(&worker, false);
+        assert_eq!(sent, vec![Rotation::Left, Rotation::Normal]);
+        assert!(worker.result().unwrap().is_none());
+    }
+
+    #[test]
+    fn stuck_sends_time_out() {
+        let (frontend, gate) = gated();
+        let raw = [("timeout", "20"), ("on_error", "disable"), ("max_failures", "2")].iter()
+            .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+            .collect();
+        let policy = SendPolicy::from_opts(&Options::parse("test", &[policy::POLICY_OPTIONS], &raw).unwrap());
+        let clock = VirtualClock::new();
+        let mut m = Member::new(frontend, policy, Vec::new(), clock.clone());
+        m.dispatch(Rotation::Left);
+        wait_busy(&m.worker, true);
+        clock.advance(Duration::from_millis(19));
+        assert!(m.collect(clock.now()).is_empty());
+
+        clock.advance(Duration::from_millis(1));
+        let errs = m.collect(clock.now());
+        assert_eq!(errs.len(), 1);
+        assert!(matches!(errs[0], (SendError::Timeout(_), false)));
+        assert_eq!(m.failures, 1);
+        assert!(! m.wants(Rotation::Left, clock.now()));
+
+        // Still stuck a timeout later, which is another failure
+        clock.advance(Duration::from_millis(19));
+        assert!(m.collect(clock.now()).is_empty());
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(m.collect(clock.now()).len(), 1);
+        assert!(m.disabled);
+        drop(gate);
+    }
+}
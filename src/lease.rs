@@ -0,0 +1,291 @@
+//! # lease
+//! Orientation leases: a control client can pin the display to a rotation,
+//! or restrict which rotations it may take, for as long as it holds the
+//! lease. Leases are dropped when their client disconnects, so a crashed
+//! application can't leave the display stuck.
+//!
+//! `--allowed` works like a lease nobody can release.
+
+use super::*;
+
+/// Identifies a control client connection
+pub type ClientId = u64;
+
+/// What a lease does
+#[derive(Debug,PartialEq,Clone)]
+pub enum Restriction {
+    /// Always use this rotation
+    Pin(Rotation),
+    /// Only use these rotations
+    Allow(Vec<Rotation>),
+}
+
+impl Restriction {
+    /// Parse `pin ROTATION`, `allow SET` or `forbid SET`, where a set is
+    /// a comma-separated list of rotations, `landscape` or `portrait`.
+    pub fn parse(how: &str, what: &str) -> Result<Restriction, String> {
+        let set = parse_set(what)?;
+        match how {
+            "pin" if 1 == set.len() => Ok(Restriction::Pin(set[0])),
+            "pin"       => Err(format!("can only pin one rotation, not '{}'", what)),
+            "allow"     => Ok(Restriction::Allow(set)),
+            "forbid"    => {
+                let allowed: Vec<Rotation> = Rotation::ALL.iter()
+                    .filter(|r| ! set.contains(r))
+                    .cloned()
+                    .collect();
+                if allowed.is_empty() {
+                    Err("can't forbid every rotation".to_owned())
+                } else {
+                    Ok(Restriction::Allow(allowed))
+                }
+            },
+            _   => Err(format!("unknown lease type '{}'", how)),
+        }
+    }
+}
+
+impl Display for Restriction {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            Restriction::Pin(r) => write!(f, "pin {}", r),
+            Restriction::Allow(ref rs)  => {
+                let names: Vec<String> = rs.iter().map(Rotation::to_string).collect();
+                write!(f, "allow {}", names.join(","))
+            },
+        }
+    }
+}
+
+/// Parse a comma-separated set of rotations
+pub fn parse_set(s: &str) -> Result<Vec<Rotation>, String> {
+    let mut set = Vec::new();
+    for name in s.split(',') {
+        let rots = match name {
+            "landscape" => vec![Rotation::Normal, Rotation::Inverted],
+            "portrait"  => vec![Rotation::Left, Rotation::Right],
+            _   => vec![name.parse::<Rotation>()
+                        .map_err(|e| format!("bad rotation '{}': {}", name, e))?],
+        };
+        for r in rots {
+            if ! set.contains(&r) { set.push(r); }
+        }
+    }
+    Ok(set)
+}
+
+/// A lease held by a control client
+struct Lease {
+    id: u32,
+    client: ClientId,
+    restriction: Restriction,
+}
+
+/// The leases currently held, and the rotations allowed at all
+pub struct Leases {
+    next_id: u32,
+    leases: Vec<Lease>,
+    allowed: Vec<Rotation>,
+}
+
+impl Leases {
+    /// No leases, and only ever `allowed`.
+    pub fn new(allowed: Vec<Rotation>) -> Leases {
+        let mut leases = Leases {
+            next_id: 1,
+            leases: Vec::new(),
+            allowed: Vec::new(),
+        };
+        leases.set_allowed(allowed);
+        leases
+    }
+
+    /// Only ever use `allowed`.
+    pub fn set_allowed(&mut self, allowed: Vec<Rotation>) {
+        if allowed.len() < Rotation::ALL.len() {
+            let names: Vec<String> = allowed.iter().map(Rotation::to_string).collect();
+            info!("Only rotating to {}", names.join(", "));
+        }
+        self.allowed = allowed;
+    }
+
+    /// Grant `client` a lease, returning its id.
+    pub fn take(&mut self, client: ClientId, restriction: Restriction) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        info!("Lease {} taken: {}", id, restriction);
+        self.leases.push(Lease { id, client, restriction });
+        id
+    }
+
+    /// Drop `client`'s lease `id`. Returns false if it doesn't hold one.
+    pub fn release(&mut self, client: ClientId, id: u32) -> bool {
+        let before = self.leases.len();
+        self.leases.retain(|l| l.id != id || l.client != client);
+        if before != self.leases.len() {
+            info!("Lease {} released", id);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Drop every lease `client` holds.
+    pub fn release_client(&mut self, client: ClientId) {
+        for l in self.leases.iter().filter(|l| l.client == client) {
+            info!("Lease {} released (client disconnected)", l.id);
+        }
+        self.leases.retain(|l| l.client != client);
+    }
+
+    /// The rotation to use, given what the sensor says and what's on the
+    /// display now.
+    ///
+    /// The newest allowed pin wins. Otherwise the sensed rotation is used
+    /// if every restriction allows it; if not, the display stays put if it
+    /// can, or moves to the nearest allowed rotation. Restrictions that
+    /// conflict with older ones (or `--allowed`) are ignored.
+    pub fn apply(&self, sensed: Option<Rotation>, current: Option<Rotation>) -> Option<Rotation> {
+        if let Some(pin) = self.leases.iter().rev().filter_map(|l| match l.restriction {
+            Restriction::Pin(r) if self.allowed.contains(&r)    => Some(r),
+            _   => None,
+        }).next() {
+            return Some(pin);
+        }
+        let mut allowed = self.allowed.clone();
+        for l in &self.leases {
+            if let Restriction::Allow(ref rs) = l.restriction {
+                let narrowed: Vec<Rotation> = allowed.iter()
+                    .filter(|r| rs.contains(r))
+                    .cloned()
+                    .collect();
+                if narrowed.is_empty() {
+                    debug!("Ignoring lease {} ({}); it conflicts with older leases", l.id, l.restriction);
+                } else {
+                    allowed = narrowed;
+                }
+            }
+        }
+        match (sensed, current) {
+            (Some(s), _) if allowed.contains(&s)    => Some(s),
+            (None, Some(c)) if allowed.contains(&c) => None,
+            (Some(_), Some(c)) if allowed.contains(&c)  => Some(c),
+            _   => {
+                let from = sensed.or(current).unwrap_or_default();
+                allowed.iter().cloned().min_by_key(|&r| from.quarter_turns(r))
+            },
+        }
+    }
+}
+
+impl Display for Leases {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let leases: Vec<String> = self.leases.iter()
+            .map(|l| format!("{}:{}", l.id, l.restriction.to_string().replace(' ', "=")))
+            .collect();
+        if leases.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", leases.join(" "))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::Rotation::{Normal, Left, Inverted, Right};
+
+    fn allow(set: &str) -> Restriction {
+        Restriction::Allow(parse_set(set).unwrap())
+    }
+
+    #[test]
+    fn sets() {
+        assert_eq!(parse_set("landscape,left"), Ok(vec![Normal, Inverted, Left]));
+        assert_eq!(parse_set("left,portrait,left"), Ok(vec![Left, Right]));
+        for bad in &["", "up", "left,", "left;right", "Left", "left, right"] {
+            assert!(parse_set(bad).is_err(), "'{}' parsed", bad);
+        }
+    }
+
+    #[test]
+    fn restrictions() {
+        let parse = |how, what| Restriction::parse(how, what);
+        assert_eq!(parse("pin", "left"), Ok(Restriction::Pin(Left)));
+        assert_eq!(parse("pin", "left,left"), Ok(Restriction::Pin(Left)));
+        assert_eq!(parse("allow", "portrait,normal"), Ok(Restriction::Allow(vec![Left, Right, Normal])));
+        assert_eq!(parse("forbid", "portrait"), Ok(Restriction::Allow(vec![Normal, Inverted])));
+        assert_eq!(parse("forbid", "left").unwrap().to_string(), "allow normal,inverted,right");
+        for &(how, what) in &[("pin", "portrait"), ("pin", "up"), ("forbid", "landscape,portrait"),
+                              ("allow", ""), ("lock", "left")] {
+            assert!(parse(how, what).is_err(), "{} {} parsed", how, what);
+        }
+    }
+
+    #[test]
+    fn newest_pin_wins() {
+        let mut leases = Leases::new(Rotation::ALL.to_vec());
+        assert_eq!(leases.apply(Some(Right), Some(Normal)), Some(Right));
+        assert_eq!(leases.apply(None, Some(Normal)), None);
+
+        leases.take(1, allow("portrait"));
+        let pin = leases.take(2, Restriction::Pin(Normal));
+        assert_eq!(leases.apply(Some(Left), Some(Left)), Some(Normal));
+        let newer = leases.take(3, Restriction::Pin(Inverted));
+        assert_eq!(leases.apply(Some(Left), Some(Normal)), Some(Inverted));
+        assert_eq!(leases.to_string(), "1:allow=left,right 2:pin=normal 3:pin=inverted");
+
+        assert!(! leases.release(2, newer));
+        assert!(leases.release(3, newer));
+        assert_eq!(leases.apply(Some(Left), Some(Inverted)), Some(Normal));
+        assert!(leases.release(2, pin));
+        // Back to the allow, which keeps the display where it is if it can
+        assert_eq!(leases.apply(Some(Normal), Some(Left)), Some(Left));
+        assert_eq!(leases.apply(Some(Right), Some(Left)), Some(Right));
+    }
+
+    #[test]
+    fn restrictions_narrow() {
+        let mut leases = Leases::new(Rotation::ALL.to_vec());
+        leases.take(1, allow("portrait"));
+        leases.take(2, allow("left,normal"));
+        assert_eq!(leases.apply(Some(Right), Some(Right)), Some(Left));
+        // Conflicts with both, so it's ignored
+        leases.take(3, allow("landscape"));
+        assert_eq!(leases.apply(Some(Normal), None), Some(Left));
+
+        let mut leases = Leases::new(Rotation::ALL.to_vec());
+        leases.take(1, Restriction::parse("forbid", "inverted").unwrap());
+        // The nearest allowed, ties going to the first
+        assert_eq!(leases.apply(Some(Inverted), None), Some(Left));
+        assert_eq!(leases.apply(None, Some(Inverted)), Some(Left));
+        assert_eq!(leases.apply(Some(Inverted), Some(Right)), Some(Right));
+    }
+
+    #[test]
+    fn allowed_beats_leases() {
+        let mut leases = Leases::new(parse_set("landscape").unwrap());
+        leases.take(1, Restriction::Pin(Left));
+        assert_eq!(leases.apply(Some(Left), Some(Normal)), Some(Normal));
+        leases.take(1, allow("portrait"));
+        assert_eq!(leases.apply(Some(Inverted), Some(Normal)), Some(Inverted));
+        assert_eq!(leases.apply(Some(Right), None), Some(Normal));
+    }
+
+    #[test]
+    fn leases_end_with_their_client() {
+        let mut leases = Leases::new(Rotation::ALL.to_vec());
+        leases.take(1, Restriction::Pin(Left));
+        leases.take(1, allow("landscape"));
+        let other = leases.take(2, allow("normal,left"));
+        assert_eq!(leases.apply(Some(Right), Some(Normal)), Some(Left));
+        leases.release_client(1);
+        assert_eq!(leases.to_string(), format!("{}:allow=normal,left", other));
+        assert_eq!(leases.apply(Some(Left), Some(Normal)), Some(Left));
+        leases.release_client(2);
+        assert_eq!(leases.to_string(), "none");
+        assert_eq!(leases.apply(Some(Right), Some(Normal)), Some(Right));
+    }
+}
@@ -0,0 +1,195 @@
+//! # spinnr
+//! Orientation detection, as used by spinnrd: accelerometers, turning
+//! their readings into a screen rotation, and the `Frontend` trait that
+//! rotations are sent to. Everything is configured explicitly, so it can be
+//! embedded without the daemon. C programs can use it through `capi`, and
+//! `plugin` loads backends and frontends from shared objects.
+
+#[macro_use] extern crate log;
+extern crate libc;
+#[cfg(test)]
+extern crate cbindgen;
+
+// For fs-accel
+#[cfg(feature = "fsaccel")]
+#[macro_use] extern crate lazy_static;
+#[cfg(feature = "fsaccel")]
+extern crate regex;
+#[cfg(feature = "fsaccel")]
+extern crate glob;
+
+pub mod accel;
+pub mod capi;
+pub mod plugin;
+mod send;
+
+pub use accel::{Accelerometer, AccelerationVector, FilteredAccelerometer};
+#[cfg(feature = "fsaccel")]
+pub use accel::{FsAccelerometer, FsAccelConfig};
+pub use send::{Frontend, Announcement, SendError, SendResult};
+
+use std::fmt::{Display, Formatter};
+use std::fmt::Result as FmtResult;
+use std::str::FromStr;
+
+/// Something that can give the device's orientation.
+pub trait Orientator {
+    /// Returns the current orientation, if it can figure it out.
+    fn orientation(&mut self) -> Option<Rotation>;
+
+    /// Returns the latest unfiltered acceleration, if there is one.
+    fn raw_vector(&self) -> Option<AccelerationVector<f64>> {
+        None
+    }
+
+    /// Returns the latest filtered acceleration, if there is one.
+    fn filtered_vector(&self) -> Option<AccelerationVector<f64>> {
+        None
+    }
+
+    /// Changes the low-pass filter multiplier. Returns false if there's no 
+    /// filter.
+    fn set_filter_mult(&mut self, _mult: f64) -> bool {
+        false
+    }
+
+    /// Changes the sensitivity (the higher it is, the flatter the device
+    /// can be held and still count as turned). Returns false if there's no
+    /// such thing.
+    fn set_sensitivity(&mut self, _sensitivity: f64) -> bool {
+        false
+    }
+}
+
+
+#[derive(Debug,PartialEq,Clone,Copy,Default)]
+pub enum Rotation {
+    #[default]
+    Normal,
+    Left,
+    Inverted,
+    Right,
+}
+use self::Rotation::*;
+
+// pub struct RotParseErr (
+#[derive(Debug)]
+pub enum RotParseErrKind {
+    TooShort,
+    TooLong,
+    NoMatch,
+}
+
+impl Display for RotParseErrKind {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            RotParseErrKind::TooShort   => write!(f, "too short"),
+            RotParseErrKind::TooLong    => write!(f, "too long"),
+            RotParseErrKind::NoMatch    => {
+                write!(f, "expected normal, left, inverted or right")
+            },
+        }
+    }
+}
+
+impl FromStr for Rotation {
+    type Err = RotParseErrKind;
+    fn from_str(s: &str) -> Result<Rotation, RotParseErrKind> {
+        match s {
+            "normal"    => Ok(Normal),
+            "left"      => Ok(Left),
+            "inverted"  => Ok(Inverted),
+            "right"     => Ok(Right),
+            ""  => Err(RotParseErrKind::TooShort),
+            s if s.len() > "inverted".len() => Err(RotParseErrKind::TooLong),
+            _   => Err(RotParseErrKind::NoMatch),
+        }
+    }
+}
+
+impl Rotation {
+    /// Every rotation, each a quarter turn on from the last
+    pub const ALL: [Rotation; 4] = [Normal, Left, Inverted, Right];
+
+    /// How many quarter turns on from `Normal` this is
+    fn turns(self) -> usize {
+        match self {
+            Normal  => 0,
+            Left    => 1,
+            Inverted    => 2,
+            Right   => 3,
+        }
+    }
+
+    /// This rotation, measured from `base` instead of `Normal`
+    pub fn relative_to(self, base: Rotation) -> Rotation {
+        Rotation::ALL[(self.turns() + 4 - base.turns()) % 4]
+    }
+
+    /// How many quarter turns apart two rotations are (at most 2)
+    pub fn quarter_turns(self, other: Rotation) -> usize {
+        let d = (self.turns() + 4 - other.turns()) % 4;
+        d.min(4 - d)
+    }
+}
+
+impl Display for Rotation {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            &Normal => write!(f, "normal"),
+            &Left   => write!(f, "left"),
+            &Inverted   => write!(f, "inverted"),
+            &Right  => write!(f, "right"),
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_rotations() {
+        // A panel whose sensor says left when it's upright
+        let table = [(Left, Normal), (Inverted, Left), (Right, Inverted), (Normal, Right)];
+        for &(sensed, shown) in table.iter() {
+            assert_eq!(sensed.relative_to(Left), shown);
+        }
+        let undo = |base: Rotation| Normal.relative_to(base);
+        for &base in Rotation::ALL.iter() {
+            assert_eq!(undo(undo(base)), base);
+            for &r in Rotation::ALL.iter() {
+                let rel = r.relative_to(base);
+                assert_eq!(rel.relative_to(undo(base)), r, "{} from {}", r, base);
+                assert_eq!(rel.quarter_turns(Normal), r.quarter_turns(base));
+            }
+            assert_eq!(base.relative_to(base), Normal);
+            assert_eq!(base.relative_to(Normal), base);
+        }
+    }
+
+    #[test]
+    fn quarter_turns() {
+        for (i, &a) in Rotation::ALL.iter().enumerate() {
+            for (j, &b) in Rotation::ALL.iter().enumerate() {
+                let d = (i + 4 - j) % 4;
+                assert_eq!(a.quarter_turns(b), d.min(4 - d));
+                assert_eq!(a.quarter_turns(b), b.quarter_turns(a));
+            }
+        }
+        assert_eq!(Left.quarter_turns(Right), 2);
+        assert_eq!(Right.quarter_turns(Normal), 1);
+    }
+
+    #[test]
+    fn names() {
+        for &r in Rotation::ALL.iter() {
+            assert_eq!(r.to_string().parse::<Rotation>().unwrap(), r);
+        }
+        assert!(matches!("".parse::<Rotation>(), Err(RotParseErrKind::TooShort)));
+        assert!(matches!("upside-down".parse::<Rotation>(), Err(RotParseErrKind::TooLong)));
+        assert!(matches!("Left".parse::<Rotation>(), Err(RotParseErrKind::NoMatch)));
+    }
+}
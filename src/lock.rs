@@ -0,0 +1,450 @@
+//! # lock
+//! The rotation lock: while it's on, orientation is still worked out but
+//! nothing is sent to the frontends.
+//!
+//! The lock's state lives in a file in the working directory, so it
+//! survives restarts and consumers can see it. Writing `locked` or
+//! `unlocked` to that file changes it, as do `SIGUSR2` (toggle), the
+//! control socket, and the rotation lock key or switch many tablets have.
+//!
+//! Convertibles' tablet mode switch is watched here too, for the posture.
+
+use super::*;
+
+use std::fs::read_to_string;
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// The default rotation lock state file
+pub const DEFAULT_LOCK_FILE: &str = "%d/spinnrd.rotlock";
+
+/// Where to look for input devices with rotation lock keys and switches
+const INPUT_DIR: &str = "/dev/input";
+
+/// What's written to the state file
+const LOCKED: &str = "locked";
+const UNLOCKED: &str = "unlocked";
+
+/// `EV_KEY`, `EV_SW` and the lock key and switch, from
+/// `linux/input-event-codes.h`
+const EV_KEY: u16 = 0x01;
+const EV_SW: u16 = 0x05;
+const KEY_ROTATE_LOCK_TOGGLE: u16 = 0x231;
+const SW_ROTATE_LOCK: u16 = 0x0c;
+const SW_TABLET_MODE: u16 = 0x01;
+/// Big enough for `KEY_MAX` and `SW_MAX` bits
+const KEY_BITS_LEN: usize = 0x300 / 8;
+const SW_BITS_LEN: usize = 8;
+
+/// The reactor token for lock input devices
+pub const LOCK_INPUT_TOKEN: Token = 0x200;
+
+/// Something from an input device that affects the lock
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub enum LockInput {
+    /// The lock key was pressed
+    Toggle,
+    /// The lock switch was set
+    Switch(bool),
+    /// The tablet mode switch was set
+    TabletMode(bool),
+}
+
+/// How a convertible is being held, going by its tablet mode switch
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub enum Posture {
+    /// There's no tablet mode switch
+    Unknown,
+    /// The keyboard's in use
+    Laptop,
+    Tablet,
+}
+
+impl Display for Posture {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            Posture::Unknown    => write!(f, "unknown"),
+            Posture::Laptop     => write!(f, "laptop"),
+            Posture::Tablet     => write!(f, "tablet"),
+        }
+    }
+}
+
+/// The rotation lock
+pub struct RotationLock {
+    locked: bool,
+    path: PathBuf,
+    /// When we last saw (or wrote) the state file change
+    modified: Option<SystemTime>,
+    /// Input devices with a lock key or switch, or a tablet mode switch
+    inputs: Vec<File>,
+    posture: Posture,
+}
+
+impl RotationLock {
+    /// Load the lock's state from `path` (unlocked if there isn't one), and
+    /// watch the input devices in `inputs` that have a lock key or switch,
+    /// or a tablet mode switch, on `reactor`.
+    pub fn init(path: PathBuf, inputs: Vec<PathBuf>, reactor: &Reactor) -> RotationLock {
+        let locked = read_state(&path).unwrap_or(false);
+        let (inputs, switch, tablet) = watch_inputs(inputs, reactor);
+        let mut lock = RotationLock {
+            locked: switch.unwrap_or(locked),
+            path,
+            modified: None,
+            inputs,
+            posture: posture(tablet),
+        };
+        if tablet.is_some() {
+            info!("Posture is {}", lock.posture);
+        }
+        info!("Rotation is {}", lock);
+        lock.save();
+        lock
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn posture(&self) -> Posture {
+        self.posture
+    }
+
+    /// The state file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Lock or unlock, saying why in the log.
+    pub fn set(&mut self, locked: bool, why: &str) {
+        if locked != self.locked {
+            self.locked = locked;
+            info!("Rotation {} ({})", self, why);
+            self.save();
+        }
+    }
+
+    /// Flip the lock, saying why in the log.
+    pub fn toggle(&mut self, why: &str) {
+        let locked = ! self.locked;
+        self.set(locked, why);
+    }
+
+    /// Pick up changes from input devices and the state file.
+    pub fn poll(&mut self) {
+        let mut events = Vec::new();
+        // Devices that went away are dropped, which takes them out of the
+        // reactor.
+        self.inputs.retain(|dev| read_events(dev, &mut events));
+        for input in events {
+            match input {
+                LockInput::Toggle   => self.toggle("lock key"),
+                LockInput::Switch(l)    => self.set(l, "lock switch"),
+                LockInput::TabletMode(t)    => if posture(Some(t)) != self.posture {
+                    self.posture = posture(Some(t));
+                    info!("Posture is {}", self.posture);
+                },
+            }
+        }
+        let modified = std::fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified.is_some() && modified != self.modified {
+            self.modified = modified;
+            match read_state(&self.path) {
+                Some(l) => {
+                    let why = format!("{} changed", self.path.to_string_lossy());
+                    self.set(l, &why);
+                },
+                None    => warn!("Ignoring unrecognized rotation lock state in {}",
+                                 self.path.to_string_lossy()),
+            }
+        }
+    }
+
+    /// Write the state file, so it survives restarts.
+    fn save(&mut self) {
+        match File::create(&self.path).and_then(|mut f| writeln!(f, "{}", self)) {
+            Ok(_)   => {
+                self.modified = std::fs::metadata(&self.path)
+                    .and_then(|m| m.modified()).ok();
+            },
+            Err(e)  => warn!("Couldn't save rotation lock state to {} ({})",
+                             self.path.to_string_lossy(), e),
+        }
+    }
+}
+
+impl Display for RotationLock {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}", if self.locked { LOCKED } else { UNLOCKED })
+    }
+}
+
+/// Parse a lock state
+pub fn parse_state(s: &str) -> Option<bool> {
+    match s.trim() {
+        LOCKED | "lock" | "on" | "1"        => Some(true),
+        UNLOCKED | "unlock" | "off" | "0"   => Some(false),
+        _   => None,
+    }
+}
+
+/// Read the lock state from `path`
+fn read_state(path: &PathBuf) -> Option<bool> {
+    read_to_string(path).ok().and_then(|s| parse_state(&s))
+}
+
+/// The posture the tablet mode switch says, if there is one
+fn posture(tablet: Option<bool>) -> Posture {
+    match tablet {
+        Some(true)  => Posture::Tablet,
+        Some(false) => Posture::Laptop,
+        None    => Posture::Unknown,
+    }
+}
+
+/// Watch every input device in `paths` that has a rotation lock key or
+/// switch, or a tablet mode switch, on `reactor`. Returns the devices, and
+/// the switches' states if there are switches.
+fn watch_inputs(paths: Vec<PathBuf>, reactor: &Reactor) -> (Vec<File>, Option<bool>, Option<bool>) {
+    let mut devices = Vec::new();
+    let mut switch = None;
+    let mut tablet = None;
+    for path in paths {
+        let opened = OpenOptions::new().read(true)
+            .custom_flags(libc::O_NONBLOCK).open(&path);
+        let dev = match opened {
+            Ok(f)   => f,
+            Err(e)  => {
+                debug!("Can't open {} ({})", path.to_string_lossy(), e);
+                continue;
+            },
+        };
+        let has_key = ev_bits(&dev, EV_KEY, KEY_BITS_LEN)
+            .map(|b| test_bit(&b, KEY_ROTATE_LOCK_TOGGLE)).unwrap_or(false);
+        let sw_bits = ev_bits(&dev, EV_SW, SW_BITS_LEN);
+        let has_sw = sw_bits.as_ref().map(|b| test_bit(b, SW_ROTATE_LOCK)).unwrap_or(false);
+        let has_tablet = sw_bits.as_ref().map(|b| test_bit(b, SW_TABLET_MODE)).unwrap_or(false);
+        if ! (has_key || has_sw || has_tablet) { continue; }
+        if let Some(b) = sw_state(&dev) {
+            if has_sw { switch = Some(test_bit(&b, SW_ROTATE_LOCK)); }
+            if has_tablet { tablet = Some(test_bit(&b, SW_TABLET_MODE)); }
+        }
+        if let Err(e) = reactor.add(dev.as_raw_fd(), LOCK_INPUT_TOKEN) {
+            warn!("Couldn't watch {} ({})", path.to_string_lossy(), e);
+            continue;
+        }
+        if has_key || has_sw {
+            info!("Watching {} for the rotation lock {}", path.to_string_lossy(),
+                  if has_sw { "switch" } else { "key" });
+        }
+        if has_tablet {
+            info!("Watching {} for the tablet mode switch", path.to_string_lossy());
+        }
+        devices.push(dev);
+    }
+    (devices, switch, tablet)
+}
+
+/// Pick up the lock key presses and switch changes waiting on `dev`.
+/// Returns false if the device has gone away.
+fn read_events(mut dev: &File, events: &mut Vec<LockInput>) -> bool {
+    let mut buf = [0u8; std::mem::size_of::<libc::input_event>()];
+    loop {
+        match dev.read(&mut buf) {
+            Ok(n) if n == buf.len() => {},
+            Ok(_)   => return false,
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock  => return true,
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e)  => {
+                debug!("Lost lock input device ({})", e);
+                return false;
+            },
+        }
+        let ev: libc::input_event = unsafe { std::ptr::read_unaligned(buf.as_ptr() as *const _) };
+        match (ev.type_, ev.code, ev.value) {
+            (EV_KEY, KEY_ROTATE_LOCK_TOGGLE, 1) => events.push(LockInput::Toggle),
+            (EV_SW, SW_ROTATE_LOCK, v)          => events.push(LockInput::Switch(0 != v)),
+            (EV_SW, SW_TABLET_MODE, v)          => events.push(LockInput::TabletMode(0 != v)),
+            _   => {},
+        }
+    }
+}
+
+/// Build an evdev read ioctl request (`_IOC(_IOC_READ, 'E', nr, len)`)
+fn eviocg(nr: u32, len: usize) -> u32 {
+    (2 << 30) | ((len as u32) << 16) | ((b'E' as u32) << 8) | nr
+}
+
+/// Get the bitmap of codes the device supports for event type `ev`
+/// (`EVIOCGBIT`)
+fn ev_bits(dev: &File, ev: u16, len: usize) -> Option<Vec<u8>> {
+    let mut bits = vec![0u8; len];
+    let rc = unsafe {
+        libc::ioctl(dev.as_raw_fd(), eviocg(0x20 + u32::from(ev), len) as _, bits.as_mut_ptr())
+    };
+    if 0 > rc { None } else { Some(bits) }
+}
+
+/// Get the bitmap of switch states (`EVIOCGSW`)
+fn sw_state(dev: &File) -> Option<Vec<u8>> {
+    let mut bits = vec![0u8; SW_BITS_LEN];
+    let rc = unsafe {
+        libc::ioctl(dev.as_raw_fd(), eviocg(0x1b, SW_BITS_LEN) as _, bits.as_mut_ptr())
+    };
+    if 0 > rc { None } else { Some(bits) }
+}
+
+fn test_bit(bits: &[u8], bit: u16) -> bool {
+    let bit = bit as usize;
+    match bits.get(bit / 8) {
+        Some(b) => 0 != b & (1 << (bit % 8)),
+        None    => false,
+    }
+}
+
+/// Every event device in `/dev/input`
+fn all_inputs() -> Vec<PathBuf> {
+    match std::fs::read_dir(INPUT_DIR) {
+        Ok(entries) => entries.filter_map(Result::ok)
+            .filter(|e| e.file_name().to_string_lossy().starts_with("event"))
+            .map(|e| e.path())
+            .collect(),
+        Err(e)  => {
+            debug!("Can't list {} ({})", INPUT_DIR, e);
+            Vec::new()
+        },
+    }
+}
+
+/// Set up the rotation lock, watching its input devices on `reactor`.
+pub fn init_lock(reactor: &Reactor) -> RotationLock {
+    let inputs = if config_flag("nolockinput") {
+        Vec::new()
+    } else {
+        config_values("lockinput")
+            .map_or_else(all_inputs, |v| v.into_iter().map(PathBuf::from).collect())
+    };
+    RotationLock::init(get_path("lockfile", DEFAULT_LOCK_FILE, false), inputs, reactor)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("spinnrd-test-{}-{}.rotlock", name, std::process::id()))
+    }
+
+    /// Write `state` to the state file, looking newer than anything before
+    fn write_state(path: &Path, state: &str, age: u64) {
+        let f = File::create(path).unwrap();
+        write!(&f, "{}", state).unwrap();
+        f.set_modified(SystemTime::now() + Duration::from_secs(age)).unwrap();
+    }
+
+    #[test]
+    fn states() {
+        for s in &["locked", "lock", "on", "1", " locked\n"] {
+            assert_eq!(parse_state(s), Some(true), "{}", s);
+        }
+        for s in &["unlocked", "unlock", "off", "0", "unlocked\n"] {
+            assert_eq!(parse_state(s), Some(false), "{}", s);
+        }
+        for s in &["", "yes", "Locked", "locked unlocked", "2"] {
+            assert_eq!(parse_state(s), None, "{}", s);
+        }
+    }
+
+    #[test]
+    fn state_file() {
+        let p = path("file");
+        let reactor = Reactor::new().unwrap();
+        let _ = remove_file(&p);
+        let mut lock = RotationLock::init(p.clone(), Vec::new(), &reactor);
+        assert!(! lock.is_locked());
+        assert_eq!(read_to_string(&p).unwrap(), "unlocked\n");
+        lock.toggle("test");
+        assert!(lock.is_locked());
+        assert_eq!(read_to_string(&p).unwrap(), "locked\n");
+        lock.set(true, "test");
+        assert!(lock.is_locked());
+        drop(lock);
+
+        // Survives a restart; junk in the file doesn't count
+        assert!(RotationLock::init(p.clone(), Vec::new(), &reactor).is_locked());
+        std::fs::write(&p, "maybe").unwrap();
+        assert!(! RotationLock::init(p.clone(), Vec::new(), &reactor).is_locked());
+        remove_file(&p).unwrap();
+    }
+
+    #[test]
+    fn follows_the_file() {
+        let p = path("poll");
+        let reactor = Reactor::new().unwrap();
+        write_state(&p, "locked", 0);
+        let mut lock = RotationLock::init(p.clone(), Vec::new(), &reactor);
+        assert!(lock.is_locked());
+
+        // Unchanged since we wrote it, even if the contents say otherwise
+        let modified = std::fs::metadata(&p).unwrap().modified().unwrap();
+        let f = File::create(&p).unwrap();
+        write!(&f, "unlocked").unwrap();
+        f.set_modified(modified).unwrap();
+        lock.poll();
+        assert!(lock.is_locked());
+
+        write_state(&p, "unlocked", 10);
+        lock.poll();
+        assert!(! lock.is_locked());
+        lock.poll();
+        assert!(! lock.is_locked());
+
+        // An unrecognized state is ignored
+        write_state(&p, "sideways", 20);
+        lock.poll();
+        assert!(! lock.is_locked());
+        write_state(&p, "on", 30);
+        lock.poll();
+        assert!(lock.is_locked());
+        remove_file(&p).unwrap();
+    }
+
+    #[test]
+    fn input_events() {
+        let p = path("events");
+        let event = |type_, code, value| {
+            let ev = libc::input_event {
+                time: libc::timeval { tv_sec: 0, tv_usec: 0 },
+                type_, code, value,
+            };
+            let bytes = unsafe { std::slice::from_raw_parts(
+                    &ev as *const _ as *const u8, std::mem::size_of::<libc::input_event>()) };
+            bytes.to_vec()
+        };
+        let mut raw = Vec::new();
+        for &(t, c, v) in &[(EV_KEY, KEY_ROTATE_LOCK_TOGGLE, 1), (EV_KEY, KEY_ROTATE_LOCK_TOGGLE, 0),
+                            (EV_SW, SW_ROTATE_LOCK, 1), (EV_KEY, 0x1e, 1),
+                            (EV_SW, SW_TABLET_MODE, 0), (EV_SW, SW_ROTATE_LOCK, 0)] {
+            raw.extend(event(t, c, v));
+        }
+        std::fs::write(&p, raw).unwrap();
+        let mut events = Vec::new();
+        // A file runs out like a device that's gone away
+        assert!(! read_events(&File::open(&p).unwrap(), &mut events));
+        assert_eq!(events, vec![LockInput::Toggle, LockInput::Switch(true),
+                                LockInput::TabletMode(false), LockInput::Switch(false)]);
+        remove_file(&p).unwrap();
+
+        assert_eq!(eviocg(0x1b, SW_BITS_LEN), 0x8008_451b);
+        let bits = [0b0000_0010, 0b0001_0000];
+        assert!(test_bit(&bits, SW_TABLET_MODE) && test_bit(&bits, SW_ROTATE_LOCK));
+        assert!(! test_bit(&bits, 0) && ! test_bit(&bits, KEY_ROTATE_LOCK_TOGGLE));
+        assert_eq!(posture(Some(true)), Posture::Tablet);
+        assert_eq!(posture(None), Posture::Unknown);
+    }
+}
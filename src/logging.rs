@@ -8,6 +8,12 @@ use systemd::journal::JournalLog;
 use log::{LevelFilter,SetLoggerError};
 use simplelog::WriteLogger;
 use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+lazy_static! {
+    /// The log file and where it is, if we're logging to one
+    static ref LOG_FILE: Mutex<Option<(PathBuf, Arc<Mutex<File>>)>> = Mutex::new(None);
+}
 
 
 /// The type returned by init_logger upon failure to initialize a logger.
@@ -109,10 +115,11 @@ pub type LogInitResult = Result<LogLocation, LoggingError>;
 
 /// Globally initialize the logger.
 pub fn init_logger() -> LogInitResult {
-    let logfile = CLI_ARGS.value_of("logfile").unwrap_or(DEFAULT_LOG_FILE);
+    let logfile = config_value("logfile").unwrap_or_else(|| DEFAULT_LOG_FILE.to_owned());
+    let logfile = logfile.as_str();
 
-    let loglvl = match CLI_ARGS.value_of("loglvl") {
-        Some(s) => s.parse().map_err(|e| LoggingError::LogLevel(e,s.to_owned()))?,
+    let loglvl = match config_value("loglvl") {
+        Some(s) => s.parse().map_err(|e| LoggingError::LogLevel(e,s.clone()))?,
         None    => DEFAULT_LOG_LEVEL,
     };
 
@@ -188,11 +195,44 @@ fn init_logfile(loglvl: LevelFilter, logfile: &str) -> LogInitResult {
             },
         }
     }
+    let file = Arc::new(Mutex::new(open_log_file(&logpath)?));
     WriteLogger::init(loglvl,
                       simplelog::Config::default(),
-                      open_log_file(&logpath)?)
-        .map_err(|e| LoggingError::FileDup(e))
-        .map(|_| LogLocation::File(logpath))
+                      SharedFile(file.clone()))
+        .map_err(|e| LoggingError::FileDup(e))?;
+    *LOG_FILE.lock().unwrap() = Some((logpath.clone(), file));
+    Ok(LogLocation::File(logpath))
+}
+
+/// Reopen the log file, so logs can be rotated. Returns the log file, or
+/// `None` if we aren't logging to one.
+pub fn reopen_log() -> Result<Option<LogLocation>, LoggingError> {
+    match *LOG_FILE.lock().unwrap() {
+        Some((ref path, ref file))  => {
+            let reopened = open_log_file(path)?;
+            *file.lock().unwrap() = reopened;
+            Ok(Some(LogLocation::File(path.clone())))
+        },
+        None    => Ok(None),
+    }
+}
+
+/// The log file, if we're logging to one
+pub fn log_file() -> Option<PathBuf> {
+    LOG_FILE.lock().unwrap().as_ref().map(|l| l.0.clone())
+}
+
+/// The log file, shared between the logger and `reopen_log`
+struct SharedFile(Arc<Mutex<File>>);
+
+impl Write for SharedFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
 }
 
 /// Open the log file
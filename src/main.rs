@@ -7,20 +7,20 @@
 extern crate daemonize;
 extern crate simplelog;
 extern crate chrono;
-extern crate signal;
 extern crate syslog;
 // extern crate errno;
 extern crate regex;
 extern crate clap;
 extern crate libc;
+extern crate toml;
 #[macro_use] extern crate log;
+extern crate spinnr;
 
 #[cfg(feature = "sysd")]
 extern crate systemd;
 
-// For fs-accel
-#[cfg(feature = "fsaccel")]
-extern crate glob;
+#[cfg(feature = "scripting")]
+extern crate rhai;
 
 
 macro_rules! qprintln {
@@ -38,8 +38,21 @@ macro_rules! qprinterr {
 mod logging;
 mod frontend;
 mod backend;
-#[cfg(any(feature = "fsaccel", feature = "iioaccel"))]
-mod accel;
+mod control;
+mod lock;
+mod lease;
+mod state;
+mod settings;
+mod config;
+mod options;
+mod reactor;
+mod clock;
+mod spinner;
+mod plugins;
+mod script;
+mod notify;
+mod pidfile;
+mod privileges;
 #[allow(dead_code)]
 mod metadata {
     include!(concat!(env!("OUT_DIR"), "/built.rs"));
@@ -49,18 +62,36 @@ mod metadata {
 use frontend::*;
 use backend::*;
 use logging::*;
+use control::*;
+use lock::*;
+use lease::*;
+use state::*;
+use settings::*;
+use config::*;
+use options::*;
+use spinnr::{Rotation, Orientator, Frontend, Announcement, SendError, SendResult};
+use spinnr::accel::{AccelerationVector, DEFAULT_SENSITIVITY};
+use reactor::*;
+use clock::*;
+use spinner::*;
+use plugins::*;
+use script::*;
+use notify::*;
+use pidfile::*;
+use privileges::*;
 
 use std::collections::HashMap;
 use std::time::{Duration, Instant};
-use std::thread::sleep;
 use std::thread;
 use std::sync::mpsc;
+use std::sync::RwLock;
+use std::os::unix::fs::MetadataExt;
 use std::fs::{File,remove_file,OpenOptions};
 // use std::ffi::CStr;
 // use std::os::unix::io::AsRawFd;
 // #[allow(unused_imports)] // for File.write()
 // use std::io::Write;
-use std::path::{PathBuf};
+use std::path::{Path,PathBuf};
 use std::io::Error as IoError;
 // use std::io::ErrorKind as IoErrorKind;
 // use std::io::SeekFrom;
@@ -70,8 +101,6 @@ use std::fmt::Result as FmtResult;
 // use c_fixed_string::CFixedStr;
 use daemonize::Daemonize;
 use clap::{Arg,ArgMatches};
-use signal::trap::Trap;
-use signal::Signal;
 use chrono::{DateTime, Utc, Local};
 // Apparantly I need this for some of the things I'm doing, even though it 
 // never gets used explicitly.
@@ -80,7 +109,7 @@ use chrono::TimeZone;
 // use errno::{errno,Errno};
 use regex::{Regex,Captures};
 // use libc::{uid_t,gid_t,getuid,getgid};
-use libc::{geteuid,getegid};
+use libc::geteuid;
 // use libc::{getpwuid_r,getgrgid_r};
 // use libc::group as CGroup;
 // use libc::passwd as CPasswd;
@@ -110,13 +139,20 @@ const DEFAULT_HYSTERESIS_STR: &str  = "1000";
 const DEFAULT_DELAY: u32    = 350;
 const DEFAULT_DELAY_STR: &str   = "350";
 
+/// The default speed the device has to turn at to count as moving (in
+/// degrees per second)
+const DEFAULT_MOTION_THRESHOLD_STR: &str = "120";
+
+/// The default time consumers get to veto a rotation (in ms); 0 commits
+/// rotations without announcing them first
+const DEFAULT_VETO_WINDOW_STR: &str = "0";
+
 /// Multiply by the delay to get nanoseconds
 const DELAY_NS_MULT: u32    = 1000000;
 
 /// Divide the delay by this to get seconds
 const DELAY_SEC_DIV: u32   = 1000;
 
-const DEFAULT_SENSITIVITY: f64 = 5.0;
 const DEFAULT_SENSITIVITY_STR: &str = "5.0";
 
 /// The default pid file
@@ -161,10 +197,6 @@ const BACKUP_LOG_FILE: &str = "%d/spinnrd.log";
 /// The file to (try) to write the logging fail message to
 const LOG_FAIL_FILE: &'static str = "/tmp/spinnrd.%t.logfail";
 
-/// Whether to quit by default if an error occurs when sending rotation.
-const DEFAULT_QUIT_ON_ROTATION_SEND_ERR: bool = false;
-// this gets logged and it shouldn't really happy anyway.
-
 ///# Formatting Arguments
 ///`strftime` string for basic ISO 8601
 const STRF_8601_BASIC: &'static str = "%Y%m%dT%H%M%S%z";
@@ -178,6 +210,23 @@ const ERR_NO_FRONTEND: i32 = -1314;
 /// Error indicating no backend
 const ERR_NO_ORIENTATOR: i32 = -1313;
 
+/// Error indicating the main loop couldn't wait on its events
+const ERR_NO_REACTOR: i32 = -1315;
+
+/// Error indicating the pid file couldn't be taken (usually because
+/// another instance has it)
+const ERR_PID_FILE: i32 = -1316;
+
+/// Error indicating daemonizing failed
+const ERR_DAEMONIZE: i32 = -1317;
+
+/// Error indicating privileges couldn't be dropped
+const ERR_PRIVILEGES: i32 = -1318;
+
+/// Reactor tokens for the sampling timer and signals
+const TICK_TOKEN: Token = 0;
+const SIGNAL_TOKEN: Token = 1;
+
 lazy_static!{
     static ref VERSION: String = format!("{} ({})", metadata::PKG_VERSION, metadata::FEATURES_STR);
     static ref AFTER_HELP_STR: String = format!("FILENAME FORMATTING
@@ -202,8 +251,18 @@ Filenames accept a few variables that will be expanded as follows:
         https://docs.rs/chrono/{}/chrono/format/strftime/index.html for 
         details). Use '%}}' to embed a '}}' in the format string.
 
+CONFIG FILE
+Settings are read from /etc/spinnrd.toml, then $XDG_CONFIG_HOME/spinnrd.toml 
+(or ~/.config/spinnrd.toml), then SPINNRD_* environment variables, then the 
+command line, each overriding the last. Keys are the long option names with 
+'_' for '-' (e.g. flip_delay = 600, or SPINNRD_FLIP_DELAY=600). Backend and 
+frontend options can go in [backend_options.NAME] and 
+[frontend_options.NAME] sections. --print-config shows the result.
+
 BACKEND OPTIONS
-The available backend options are as follows:{}
+In option values, ',', ';' and '|' can be escaped with '\\'. Unknown options 
+and values of the wrong type are errors. The available backend options are 
+as follows:{}
 
 FRONTEND OPTIONS
 The available frontend options are as follows:{}
@@ -213,7 +272,12 @@ The available frontend options are as follows:{}
 // the part where we define the command line arguments
 lazy_static!{
     /// The command line arguments
-    static ref CLI_ARGS: ArgMatches<'static> = clap::App::new("Spinnr")
+    static ref CLI_ARGS: ArgMatches<'static> = cli().get_matches();
+}
+
+/// The command line arguments spinnrd takes
+fn cli() -> clap::App<'static, 'static> {
+    clap::App::new("Spinnr")
 
         .version((*VERSION).as_str())
         .author("James Wescott <james@wescottdesign.com>")
@@ -252,7 +316,7 @@ lazy_static!{
              .number_of_values(1)
              .value_name("PIDFILE")
              .default_value(DEFAULT_PID_FILE)
-             .help("Location of the pid file (if daemonizing). Uses filename formatting.")
+             .help("Location of the pid file. Only one spinnrd can use it at a time. Uses filename formatting.")
             )
         .arg(Arg::with_name("workingdir")
              .long("working-directory")
@@ -281,23 +345,88 @@ lazy_static!{
              .long("no-pid-file")
              .help("Don't make a pid file")
             )
+        .arg(Arg::with_name("replace")
+             .long("replace")
+             .conflicts_with("nopidfile")
+             .help("Stop the spinnrd using the pid file, and take over from it.")
+            )
+        .arg(Arg::with_name("user")
+             .long("user")
+             .value_name("USER")
+             .help("The user to run as, once the sensors and input devices are open. A name or a uid.")
+             .long_help("The user to run as, once the sensors and input devices are open. A name or a uid. Backends reinitialized on SIGHUP are opened as this user too, so if it can't open them, the old backend is kept.")
+            )
+        .arg(Arg::with_name("group")
+             .long("group")
+             .value_name("GROUP")
+             .help("The group to run as, once the sensors and input devices are open. Defaults to the user's.")
+            )
+        .arg(Arg::with_name("keepcaps")
+             .long("keep-caps")
+             .value_name("CAPS")
+             .multiple(true)
+             .number_of_values(1)
+             .use_delimiter(true)
+             .help("Capabilities to keep after dropping privileges (e.g. dac_override for fbcon). The rest go.")
+            )
         .arg(Arg::with_name("daemonize")
              .short("D")
              .long("daemonize")
              .help("Run as background daemon.")
             )
+        .arg(Arg::with_name("allowed")
+             .long("allowed")
+             .value_name("ROTATIONS")
+             .validator(validate_rotations)
+             .help("Only ever rotate to these: a comma-separated list of rotations, 'landscape' or 'portrait'.")
+             )
+        .arg(Arg::with_name("offset")
+             .long("offset")
+             .value_name("ROTATION")
+             .validator(validate_rotation)
+             .default_value("normal")
+             .help("What the sensor reports when the device is in its natural orientation.")
+             .long_help("What the sensor reports when the device is in its natural orientation, for panels mounted differently from their accelerometer (such as portrait tablets whose sensor says 'left' when they're upright). Rotations are taken relative to this.")
+             )
+        .arg(Arg::with_name("suggestonly")
+             .long("suggest-only")
+             .help("Suggest rotations instead of committing them.")
+             .long_help("Suggest rotations instead of committing them, as happens anyway while rotation is locked. Frontends that take announcements (such as socket with announce=true) get 'suggest ROTATION' and 'cancel ROTATION', and 'spinnrctl accept' commits the suggestion.")
+            )
         .arg(Arg::with_name("delay")
              .long("delay")
              .short("d")
              .value_name("DELAY")
              .validator(validate_u32)
              .help("Wait for orientation to be stable for DELAY milliseconds before rotating display.")
+             .long_help("Wait for orientation to be stable for DELAY milliseconds before rotating display a quarter turn. See also --flip-delay.")
              .default_value(DEFAULT_DELAY_STR)
             )
+        .arg(Arg::with_name("flipdelay")
+             .long("flip-delay")
+             .value_name("DELAY")
+             .validator(validate_u32)
+             .help("Like --delay, but for turning the display upside down. Defaults to --delay.")
+            )
+        .arg(Arg::with_name("motionthreshold")
+             .long("motion-threshold")
+             .value_name("DEG_PER_SEC")
+             .validator(validate_f64)
+             .default_value(DEFAULT_MOTION_THRESHOLD_STR)
+             .help("While the device turns faster than this, orientation doesn't count as stable. 0 turns this off.")
+             .long_help("While the device turns faster than this (in degrees per second), orientation doesn't count as stable, so the orientations it passes through on the way to being flipped aren't committed. 0 turns this off.")
+            )
+        .arg(Arg::with_name("vetowindow")
+             .long("veto-window")
+             .value_name("WINDOW")
+             .validator(validate_u32)
+             .help("Announce each rotation as pending, and only commit it if nobody vetoes it within WINDOW milliseconds.")
+             .long_help("Announce each rotation as pending, and only commit it if nobody vetoes it (see spinnrctl) within WINDOW milliseconds. Frontends that take announcements (such as socket with announce=true) get 'pending ROTATION', then the rotation or 'cancel ROTATION'. 0 commits rotations right away.")
+             .default_value(DEFAULT_VETO_WINDOW_STR)
+            )
         .arg(Arg::with_name("backend")
              .long("backend")
              .value_name("BACKEND[[,OPT=VALUE]...][;BACKEND[[,OPT=VALUE]...]]...")
-             .value_delimiter(";")
              .help("Choose which backend(s) to get data from and set options")
              )
         .arg(Arg::with_name("backend_opts")
@@ -309,9 +438,9 @@ lazy_static!{
              )
         .arg(Arg::with_name("frontend")
              .long("frontend")
-             .value_name("FRONTEND[[,OPT=VALUE]...][;FRONTEND[[,OPT=VALUE]...]]")
-             .value_delimiter(";")
-             .help("Choose which frontend(s) to get data from and set options")
+             .value_name("FRONTEND[[,OPT=VALUE]...][|FRONTEND...][;FRONTEND...]...")
+             .help("Choose which frontend(s) to send rotations to and set options")
+             .long_help("Every ';'-separated entry gets each rotation. Within an entry, '|' separates a failover group: the first frontend in the group that initializes is used.")
              )
         .arg(Arg::with_name("frontend_opts")
              .long("frontend-options")
@@ -320,6 +449,66 @@ lazy_static!{
              .number_of_values(1)
              .help("Set options for various frontends without changing which frontend(s) to use.")
              )
+        .arg(Arg::with_name("control")
+             .long("control-socket")
+             .number_of_values(1)
+             .value_name("SOCKET")
+             .default_value(DEFAULT_CONTROL_SOCKET)
+             .help("Where to listen for control requests (see spinnrctl). Uses filename formatting.")
+             )
+        .arg(Arg::with_name("controlmode")
+             .long("control-mode")
+             .number_of_values(1)
+             .value_name("MODE")
+             .default_value(DEFAULT_CONTROL_MODE)
+             .help("The control socket's permissions, in octal.")
+             )
+        .arg(Arg::with_name("nocontrol")
+             .long("no-control-socket")
+             .help("Don't listen for control requests")
+             )
+        .arg(Arg::with_name("lockfile")
+             .long("lock-file")
+             .number_of_values(1)
+             .value_name("LOCKFILE")
+             .default_value(DEFAULT_LOCK_FILE)
+             .help("Where to keep the rotation lock state ('locked' or 'unlocked'). Uses filename formatting.")
+             .long_help("Writing to this file locks or unlocks rotation, as does SIGUSR2 (toggle), the control socket, and rotation lock keys and switches.")
+             )
+        .arg(Arg::with_name("lockinput")
+             .long("lock-input")
+             .value_name("DEVICE")
+             .multiple(true)
+             .number_of_values(1)
+             .help("An input device to watch for rotation lock keys and switches. Defaults to every /dev/input/event* device that has one.")
+             )
+        .arg(Arg::with_name("nolockinput")
+             .long("no-lock-input")
+             .help("Don't watch input devices for rotation lock keys and switches")
+             )
+        .arg(Arg::with_name("plugindir")
+             .long("plugin-dir")
+             .number_of_values(1)
+             .value_name("DIR")
+             .default_value(DEFAULT_PLUGIN_DIR)
+             .help("Load backend and frontend plugins (*.so) from DIR at startup. Uses filename formatting.")
+             )
+        .arg(Arg::with_name("policyscript")
+             .long("policy-script")
+             .number_of_values(1)
+             .value_name("SCRIPT")
+             .help("Let a Rhai script decide which rotations to go with. Uses filename formatting.")
+             .long_help("The script defines policy(vector, candidate, locked, posture), which is called with each sample and returns the rotation to settle on, or () to stay put. Reloaded on SIGHUP. Needs the 'scripting' feature.")
+             )
+        .arg(Arg::with_name("config")
+             .long("config")
+             .value_name("FILE")
+             .help("Read settings from FILE instead of /etc/spinnrd.toml and the user's config file.")
+             )
+        .arg(Arg::with_name("printconfig")
+             .long("print-config")
+             .help("Print the settings in effect, and where each came from, then exit.")
+             )
         .arg(Arg::with_name("loglvl")
              .long("log-level")
              .value_name("LOG_LEVEL")
@@ -329,7 +518,6 @@ lazy_static!{
              )
         //TODO: add --log-fmt
         .after_help((*AFTER_HELP_STR).as_str())
-        .get_matches();
 }
 
 fn chrono_ver() -> &'static str {
@@ -342,11 +530,9 @@ fn chrono_ver() -> &'static str {
 }
 
 lazy_static! {
-    static ref SENSITIVITY: f64 = get_f64_arg_val("sensitivity").unwrap_or(DEFAULT_SENSITIVITY);
-    static ref IS_QUIET: bool = CLI_ARGS.is_present("quiet");
+    static ref IS_QUIET: bool = config_flag("quiet");
 }
 
-
 fn main() {
     // lets us exit with status - important for running under systemd, etc.
     ::std::process::exit(mainprog());
@@ -354,6 +540,15 @@ fn main() {
 
 /// The actual main body of the program
 fn mainprog() -> i32 {
+    if let Err(e) = reload_config() {
+        qprinterr!("{}", e);
+        return 2i32
+    }
+    if CLI_ARGS.is_present("printconfig") {
+        print!("{}", config_string());
+        return 0i32
+    }
+
     match init_logger() {
         Ok(l)   => {
             qprintln!("Logging initialized to {}", l);
@@ -366,144 +561,392 @@ fn mainprog() -> i32 {
         }
     }
 
-    let rval;
-    let mut pidfile: Option<PathBuf> = None;
+    // Taken before daemonizing, so whoever started us hears about it.
+    let mut pidfile = if config_flag("nopidfile") {
+        None
+    } else {
+        match PidFile::take(get_pid_file(), CLI_ARGS.is_present("replace")) {
+            Ok(p)   => Some(p),
+            Err(e)  => {
+                qprinterr!("{}", e);
+                error!("{}", e);
+                return ERR_PID_FILE;
+            },
+        }
+    };
     if is_daemon() {
         info!("Attempting abyssal arachnid generation...");
-        pidfile = Some(get_pid_file());
         let daemon = Daemonize::new()
-            .pid_file(match &pidfile {Some(ref s) => s, None => panic!()})
-            .chown_pid_file(true)
             .working_directory((*WORKING_DIR).clone())
-            .user(get_user())
-            .group(get_group())
             .umask(0o023)
             ;
 
-        match daemon.start() {
-            Ok(_)   => (),
-            Err(e)  => {qprinterr!("Failed to daemonize! {}", e);},
+        if let Err(e) = daemon.start() {
+            qprinterr!("Failed to daemonize! {}", e);
+            error!("Failed to daemonize! ({})", e);
+            return ERR_DAEMONIZE;
+        }
+        if let Some(Err(e)) = pidfile.as_mut().map(|p| p.update()) {
+            error!("{}", e);
         }
     } // if is_daemon()
 
 
-    match init_frontend() {
-        Ok(frontend)    => {
-            let hyst = get_u32_arg_val("hysteresis").unwrap_or(DEFAULT_HYSTERESIS);
-            let period = get_u32_arg_val("period").unwrap_or(DEFAULT_PERIOD);
-            let delay = get_u32_arg_val("delay").unwrap_or(DEFAULT_DELAY);
-            // a_now = m * (measurement - a_last)
-            // where m is the amount of time we're low-pass filtering over
-            // times the frequency with which we're polling
-            // (AKA the time we're filtering over divided by the period)
-            match init_orientator(period as f64 / (hyst as f64)) {
-                Ok(orientator) => {
-                    rval = runloop(frontend, orientator, period, delay);
-                },
-                Err(e)  => {
-                    rval = e;
-                },
-            }
-        },
-        Err(e)  => {
-            rval = e;
-        },
-    }
+    load_plugins(&get_path("plugindir", DEFAULT_PLUGIN_DIR, false));
+    let settings = Settings::load();
+    let rval = match init_orientator(settings.filter_mult(), settings.sensitivity,
+                                     settings.backends.clone()) {
+        Ok(orientator)  => runloop(orientator, settings, pidfile.as_ref().map(PidFile::path)),
+        Err(e)  => e,
+    };
 
-    if let Some(p) = pidfile {
-        rm_pid_file(&p)
-    }
+    drop(pidfile);
     return rval;
 }
 
 fn runloop(
-    mut frontend: FrontendKind,
     mut orient: OrientatorKind,
-    period: u32,
-    delay: u32
+    mut settings: Settings,
+    pidfile: Option<&Path>,
     ) -> i32
 {
-    let (handle, sigrx) = init_sigtrap(&[Signal::SIGHUP,Signal::SIGINT,Signal::SIGTERM]);
-
-    // period is in ms, so multiply by 10^6 to get ns
-    let period = Duration::new(
-        (period / PERIOD_SEC_DIV) as u64,
-        (period % PERIOD_SEC_DIV) * PERIOD_NS_MULT);
-    let delay = Duration::new(
-        (delay / DELAY_SEC_DIV) as u64,
-        (delay % DELAY_SEC_DIV) * DELAY_NS_MULT);
+    let (reactor, timer, signals) = match init_reactor(settings.period(),
+            &[libc::SIGHUP, libc::SIGINT, libc::SIGTERM, libc::SIGUSR1, libc::SIGUSR2]) {
+        Ok(r)   => r,
+        Err(e)  => {
+            error!("Couldn't set up the main loop! Quitting. ({})", e);
+            return ERR_NO_REACTOR;
+        },
+    };
+    let mut lock = init_lock(&reactor);
+
+    // The sensors and input devices are open, so whatever else gets made
+    // (spinfiles, sockets) belongs to the user we're going to be.
+    let log = log_file();
+    let mut hand_over = vec![lock.path()];
+    hand_over.extend(log.as_deref());
+    hand_over.extend(pidfile);
+    if is_private(&WORKING_DIR) {
+        hand_over.push(&WORKING_DIR);
+    }
+    if let Err(e) = drop_privileges(&hand_over) {
+        error!("Couldn't drop privileges! Quitting. ({})", e);
+        return ERR_PRIVILEGES;
+    }
+    let mut frontend = match init_frontend(settings.frontends.clone(), SystemClock) {
+        Ok(f)   => f,
+        Err(e)  => return e,
+    };
+    let mut control = init_control(&reactor);
+    let mut spinner = Spinner::new(SystemClock, &settings);
+    spinner.policy = init_policy();
+    let mut notifier = Notifier::from_env();
+    if let Some(n) = notifier.as_mut() {
+        if let Some(w) = n.watchdog() {
+            if settings.period() >= w / 2 {
+                warn!("The period ({}ms) is too long for the watchdog ({}ms)", settings.period, millis(w));
+            }
+        }
+        n.ready(status(&spinner, &lock));
+    }
 
-    let mut orientation: Option<Rotation>;
-    let mut last_written: Option<Rotation> = None;
-    let mut last_change: Option<Rotation> = None;
-    let mut last_change_time = Instant::now();
+    let mut period_ms = settings.period;
+    let mut hyst = settings.hysteresis;
+    let mut sens = settings.sensitivity;
 
     let mut rval = 0;
     info!("Spinning...");
     'mainloop: loop {
-        match sigrx.try_recv() {
-            Ok(s)   => {
-                warn!("Recieved {:?}, closing...", s);
-                break 'mainloop
-            },
-            Err(mpsc::TryRecvError::Empty)  => {},
-            Err(mpsc::TryRecvError::Disconnected)   => {
-                error!("Signal handler died unexpectedly! Aborting!");
-                rval = 17;
+        let ready = match reactor.wait(None) {
+            Ok(r)   => r,
+            Err(e)  => {
+                error!("Error waiting for events! Quitting. ({})", e);
+                rval = ERR_NO_REACTOR;
                 break 'mainloop
             },
-        } // match sigrx.try_recv()
-
-        orientation = orient.orientation();
-        if orientation.is_some() {
-            trace!("Orientation is {}", orientation.unwrap());
-            if last_change != orientation {
-                last_change = orientation;
-                last_change_time = Instant::now();
-            } else {
-                if last_change != last_written && last_change_time.elapsed() >= delay {
-                    info!("Writing {} to {}", orientation.unwrap(), frontend);
-                    // `unwrap` is safe here because we've already checked 
-                    // that orientation isn't None.
-                    match frontend.send(orientation.unwrap()) {
-                        Ok(_)   => { last_written = orientation; },
-                        Err(e)  => {
-                            error!("Error sending rotation! ({})", e);
-                            if quit_on_rotation_send_error() {
-                                rval = 4;
-                                break 'mainloop
+        };
+        // Sampling only happens on the timer; everything else is dealt
+        // with as soon as it comes in.
+        let mut tick = false;
+        let mut queries = Vec::new();
+        for token in ready {
+            match token {
+                TICK_TOKEN  => tick = 0 < timer.expirations(),
+                SIGNAL_TOKEN    => while let Some(s) = signals.next() {
+                    match s {
+                        libc::SIGUSR2   => lock.toggle("SIGUSR2"),
+                        libc::SIGUSR1   => {
+                            info!("Status: backend {}; frontends {}", orient, frontend);
+                            info!("Status: orientation {} (sensed {}, candidate {}), last written {}",
+                                  opt(spinner.orientation()), opt(spinner.sensed()),
+                                  opt(spinner.state.candidate()), opt(spinner.last_written()));
+                            info!("Status: rotation {}; posture {}; pending {}; suggested {}; leases {}",
+                                  lock, lock.posture(), opt(spinner.pending()), opt(spinner.suggested()),
+                                  spinner.leases);
+                            info!("Status: period {}ms; delay {}ms; flip delay {}ms; hysteresis {}ms; \
+                                  sensitivity {}; motion threshold {}; veto window {}ms",
+                                  period_ms, millis(spinner.state.delay), millis(spinner.state.flip_delay),
+                                  hyst, sens, spinner.motion.threshold,
+                                  millis(spinner.veto_window));
+                            frontend.log_counters();
+                        },
+                        libc::SIGHUP    => {
+                            info!("Recieved SIGHUP, reloading...");
+                            if let Some(n) = notifier.as_ref() {
+                                n.reloading();
                             }
-                        }
+                            match reopen_log() {
+                                Ok(Some(l)) => info!("Reopened log file {}", l),
+                                Ok(None)    => {},
+                                Err(e)  => error!("Couldn't reopen log file ({})", e),
+                            }
+                            if let Err(e) = reload_config() {
+                                error!("Keeping the old config ({})", e);
+                            }
+                            let new = Settings::load();
+                            if new.frontends != settings.frontends {
+                                info!("Frontends changed; reinitializing them");
+                                // The old frontends have to go first, so they let
+                                // go of their files and sockets.
+                                frontend.log_counters();
+                                drop(frontend);
+                                match init_frontend(new.frontends.clone(), SystemClock)
+                                    .or_else(|_| {
+                                        error!("Falling back to the old frontends");
+                                        init_frontend(settings.frontends.clone(), SystemClock)
+                                    }) {
+                                    Ok(f)   => frontend = f,
+                                    Err(e)  => {
+                                        error!("No frontends left! Quitting.");
+                                        frontend = Frontends::empty(SystemClock);
+                                        rval = e;
+                                        break 'mainloop
+                                    },
+                                }
+                                if let Some(r) = spinner.last_written() {
+                                    let _ = frontend.send(r);
+                                }
+                            }
+                            if new.backends != settings.backends {
+                                info!("Backends changed; reinitializing them");
+                                match init_orientator(new.filter_mult(), new.sensitivity,
+                                                      new.backends.clone()) {
+                                    Ok(o)   => {
+                                        orient = o;
+                                        sens = new.sensitivity;
+                                    },
+                                    Err(_)  => error!("Keeping the old backend"),
+                                }
+                            } else if new.filter_mult() != settings.filter_mult() {
+                                orient.set_filter_mult(new.filter_mult());
+                            }
+                            if new.period != settings.period {
+                                match timer.set_period(new.period()) {
+                                    Ok(_)   => period_ms = new.period,
+                                    Err(e)  => error!("Couldn't change the period ({})", e),
+                                }
+                            }
+                            if new.hysteresis != settings.hysteresis {
+                                hyst = new.hysteresis;
+                            }
+                            if new.sensitivity != settings.sensitivity {
+                                orient.set_sensitivity(new.sensitivity);
+                                sens = new.sensitivity;
+                            }
+                            spinner.reconfigure(&settings, &new);
+                            spinner.policy = init_policy();
+                            settings = new;
+                            if let Some(n) = notifier.as_mut() {
+                                n.ready(status(&spinner, &lock));
+                            }
+                        },
+                        _   => {
+                            warn!("Recieved {}, closing...", signal_name(s));
+                            break 'mainloop
+                        },
                     }
-                } // if last_change_time.elapsed() >= delay
-            } // if last_change != orientation
-        } // if orientation.is_some()
-        sleep(period);
+                }, // while let Some(s) = signals.next()
+                LOCK_INPUT_TOKEN    => lock.poll(),
+                t if ControlServer::owns(t) => if let Some(c) = control.as_mut() {
+                    queries.extend(c.ready(t, &reactor));
+                },
+                t   => trace!("Unexpected event {}", t),
+            }
+        } // for token in ready
+
+        let mut sent = None;
+        for (client, req) in queries {
+            let answer = match req {
+                Request::Rotation   => Ok(format!("current={} sensed={} last_written={} lock={}",
+                                              opt(spinner.current()), opt(spinner.sensed()),
+                                              opt(spinner.last_written()), lock)),
+                Request::Lock(None) => Ok(lock.to_string()),
+                Request::Lock(Some(l))  => {
+                    match l {
+                        Some(l) => lock.set(l, "control request"),
+                        None    => lock.toggle("control request"),
+                    }
+                    Ok(lock.to_string())
+                },
+                Request::Vector     => Ok(format!("raw={} filtered={}",
+                                              opt(orient.raw_vector().map(csv)),
+                                              opt(orient.filtered_vector().map(csv)))),
+                Request::Backend    => Ok(orient.to_string()),
+                Request::Frontends  => Ok(frontend.to_string()),
+                Request::Get(Setting::Delay)    => Ok(millis(spinner.state.delay).to_string()),
+                Request::Get(Setting::FlipDelay)    => Ok(millis(spinner.state.flip_delay).to_string()),
+                Request::Get(Setting::MotionThreshold)  => Ok(spinner.motion.threshold.to_string()),
+                Request::Get(Setting::Hysteresis)   => Ok(hyst.to_string()),
+                Request::Get(Setting::Sensitivity)  => Ok(sens.to_string()),
+                Request::Get(Setting::VetoWindow)   => Ok(millis(spinner.veto_window).to_string()),
+                Request::Set(Setting::Delay, v) => v.parse::<u32>()
+                    .map(|d| {
+                        spinner.state.delay = Duration::from_millis(u64::from(d));
+                        info!("Delay set to {}ms", d);
+                        d.to_string()
+                    })
+                    .map_err(|e| format!("bad delay '{}': {}", v, e)),
+                Request::Set(Setting::FlipDelay, v) => v.parse::<u32>()
+                    .map(|d| {
+                        spinner.state.flip_delay = Duration::from_millis(u64::from(d));
+                        info!("Flip delay set to {}ms", d);
+                        d.to_string()
+                    })
+                    .map_err(|e| format!("bad flip delay '{}': {}", v, e)),
+                Request::Set(Setting::MotionThreshold, v)   => match v.parse::<f64>() {
+                    Ok(t) if 0.0 <= t   => {
+                        spinner.motion.threshold = t;
+                        info!("Motion threshold set to {} degrees per second", t);
+                        Ok(t.to_string())
+                    },
+                    _   => Err(format!("bad motion threshold '{}'", v)),
+                },
+                Request::Set(Setting::VetoWindow, v)    => v.parse::<u32>()
+                    .map(|w| {
+                        spinner.veto_window = Duration::from_millis(u64::from(w));
+                        info!("Veto window set to {}ms", w);
+                        w.to_string()
+                    })
+                    .map_err(|e| format!("bad veto window '{}': {}", v, e)),
+                Request::Set(Setting::Hysteresis, v)    => match v.parse::<u32>() {
+                    Ok(h) if 0 < h  => {
+                        if orient.set_filter_mult(period_ms as f64 / (h as f64)) {
+                            hyst = h;
+                            info!("Hysteresis set to {}ms", h);
+                            Ok(h.to_string())
+                        } else {
+                            Err(format!("{} isn't filtered", orient))
+                        }
+                    },
+                    _   => Err(format!("bad hysteresis '{}'", v)),
+                },
+                Request::Set(Setting::Sensitivity, v)   => match v.parse::<f64>() {
+                    Ok(s) if 0.0 < s    => {
+                        if orient.set_sensitivity(s) {
+                            sens = s;
+                            info!("Sensitivity set to {}", s);
+                            Ok(s.to_string())
+                        } else {
+                            Err(format!("{} has no sensitivity", orient))
+                        }
+                    },
+                    _   => Err(format!("bad sensitivity '{}'", v)),
+                },
+                Request::Lease(r)   => Ok(spinner.leases.take(client, r).to_string()),
+                Request::Release(id)    => if spinner.leases.release(client, id) {
+                    Ok(id.to_string())
+                } else {
+                    Err(format!("no lease {} held by this client", id))
+                },
+                Request::Leases     => Ok(spinner.leases.to_string()),
+                Request::Hangup     => {
+                    spinner.leases.release_client(client);
+                    Ok(String::new())
+                },
+                Request::Veto       => spinner.veto(&mut frontend)
+                    .map(|r| r.to_string())
+                    .ok_or_else(|| "no rotation pending".to_owned()),
+                Request::Accept     => match spinner.accept(&mut frontend) {
+                    Some((r, s))    => {
+                        sent = Some(s);
+                        Ok(r.to_string())
+                    },
+                    None    => Err("no rotation suggested or pending".to_owned()),
+                },
+                Request::Rotate(r)  => {
+                    sent = Some(spinner.rotate(r, &mut frontend));
+                    Ok(r.to_string())
+                },
+            };
+            if let Some(c) = control.as_mut() {
+                c.reply(client, answer);
+            }
+        }
+        if tick {
+            lock.poll();
+            spinner.posture = lock.posture();
+            // Leases taken or released above count right away.
+            if let Some(s) = spinner.tick(&mut orient, &mut frontend, lock.is_locked()) {
+                sent = Some(s);
+            }
+            // Only ticks count, so a wedged sensor read starves the watchdog.
+            if let Some(n) = notifier.as_mut() {
+                n.alive(Instant::now());
+            }
+        }
+        if let Some(n) = notifier.as_mut() {
+            n.status(status(&spinner, &lock));
+        }
+        match sent.unwrap_or_else(|| frontend.poll()) {
+            Ok(_)   => {},
+            Err(SendError::Fatal(e))    => {
+                error!("Error sending rotation! Quitting. ({})", e);
+                rval = 4;
+                break 'mainloop
+            },
+            Err(e)  => error!("Error sending rotation! ({})", e),
+        }
     } // 'mainloop: loop
-    // unwrapping because it should rejoin nicely
-    // and it doesn't matter TOO much if it panics.
-    handle.join().unwrap();
+    if let Some(n) = notifier.as_ref() {
+        n.stopping();
+    }
+    frontend.log_counters();
     return rval;
 }
 
+/// What to tell systemd we're doing
+fn status<C: Clock>(spinner: &Spinner<C>, lock: &RotationLock) -> String {
+    format!("Rotation {}, {}", opt(spinner.last_written()), lock)
+}
+
 
 /// Returns true if we are to daemonize
 #[inline]
 fn is_daemon() -> bool {
-    CLI_ARGS.is_present("daemonize")
+    config_flag("daemonize")
 }
 
-/// Initializes the signal handler
-fn init_sigtrap(sigs: &[Signal]) -> (thread::JoinHandle<()>, mpsc::Receiver<Signal>) {
-    debug!("initializing signal trap...");
-    let mut sigtrap = Trap::trap(sigs);
-    let (tx, rx) = mpsc::sync_channel::<Signal>(1);
-    let handle = thread::spawn(move || tx.send(sigtrap.next().unwrap()).unwrap());
-    (handle, rx)
+/// Set up the reactor, with a timer going off every `period` and `sigs`
+/// coming in through it.
+fn init_reactor(period: Duration, sigs: &[libc::c_int])
+    -> Result<(Reactor, Timer, Signals), IoError> {
+    debug!("initializing reactor...");
+    let reactor = Reactor::new()?;
+    let signals = Signals::new(sigs)?;
+    reactor.add(signals.fd(), SIGNAL_TOKEN)?;
+    let timer = Timer::new(period)?;
+    reactor.add(timer.fd(), TICK_TOKEN)?;
+    Ok((reactor, timer, signals))
 }
 
-/// Get frontend options from command line
-fn get_frontend_options() -> (Vec<String>, HashMap<String, HashMap<String, String>>) {
+/// A frontend to try, along with its options
+pub type FrontendSpec = (String, HashMap<String, String>);
+
+/// Get frontend options from the config.
+///
+/// Returns a list of failover groups; every group gets each rotation, and
+/// within a group the first frontend that initializes is used.
+fn get_frontend_options() -> Vec<Vec<FrontendSpec>> {
     lazy_static! {
         static ref FRONTEND_RE: Regex = Regex::new(r"^(?x)
         (?P<frontend>\w+)
@@ -516,35 +959,58 @@ fn get_frontend_options() -> (Vec<String>, HashMap<String, HashMap<String, Strin
             [^;]+
         )?").unwrap();
     }
-    let mut frontlist = Vec::new();
-    let mut optmap = HashMap::new();
-    let frontends = CLI_ARGS.value_of("frontend").unwrap_or(DEFAULT_FRONTEND);
-    let frontend_options = CLI_ARGS.value_of("frontend_opts").unwrap_or(DEFAULT_FRONTEND_OPTS);
-    for caps in FRONTEND_RE.captures_iter(frontend_options) {
-        let frontend = &caps["frontend"];
-        if ! optmap.contains_key(frontend) {
-            optmap.insert(frontend.to_owned(), HashMap::new());
-        }
+    let mut groups = Vec::new();
+    let mut optmap = config_sections("frontend_opts");
+    let frontend_options = config_values("frontend_opts")
+        .unwrap_or_else(|| vec![DEFAULT_FRONTEND_OPTS.to_owned()]);
+    for caps in frontend_options.iter().filter_map(|o| FRONTEND_RE.captures(o)) {
         parse_options(
-            &caps.name("options").map(|m| m.as_str()).unwrap_or(""),
-            optmap.get_mut(frontend).unwrap());
+            caps.name("options").map_or("", |m| m.as_str()),
+            optmap.entry(caps["frontend"].to_owned()).or_insert_with(HashMap::new));
     }
-    for caps in FRONTEND_RE.captures_iter(frontends) {
-        let frontend = &caps["frontend"];
-        if ! optmap.contains_key(frontend) {
-            optmap.insert(frontend.to_owned(), HashMap::new());
+    let frontends = config_values("frontend")
+        .unwrap_or_else(|| vec![DEFAULT_FRONTEND.to_owned()]);
+    for entry in frontends {
+        let mut group = Vec::new();
+        for alt in split_unescaped(&entry, '|') {
+            if let Some(caps) = FRONTEND_RE.captures(&alt) {
+                let frontend = &caps["frontend"];
+                // Options given with the frontend override --frontend-options
+                let mut opts = optmap.get(frontend).cloned().unwrap_or_default();
+                parse_options(
+                    caps.name("options").map_or("", |m| m.as_str()),
+                    &mut opts);
+                group.push((frontend.to_owned(), opts));
+            }
         }
-        frontlist.push(frontend.to_owned());
-        parse_options(
-            &caps.name("options").map(|m| m.as_str()).unwrap_or(""),
-            optmap.get_mut(frontend).unwrap());
+        if ! group.is_empty() {
+            groups.push(group);
+        }
+    }
+    groups
+}
 
+/// Split `s` on every `sep` that isn't escaped with a backslash.
+/// The escapes are left in place.
+fn split_unescaped(s: &str, sep: char) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut escaped = false;
+    for c in s.chars() {
+        if c == sep && ! escaped {
+            parts.push(current);
+            current = String::new();
+        } else {
+            current.push(c);
+        }
+        escaped = '\\' == c && ! escaped;
     }
-    return (frontlist,optmap)
+    parts.push(current);
+    parts
 }
 
-/// Get backend options from command line
-fn get_backend_options() -> (Vec<String>, HashMap<String, HashMap<String, String>>) {
+/// Get backend options from the config
+fn get_backend_options() -> BackendOptions {
     lazy_static! {
         static ref BACKEND_RE: Regex = Regex::new(r"^(?x)
         (?P<backend>\w+)
@@ -558,28 +1024,29 @@ fn get_backend_options() -> (Vec<String>, HashMap<String, HashMap<String, String
         )?").unwrap();
     }
     let mut backlist = Vec::new();
-    let mut optmap = HashMap::new();
-    let backends = CLI_ARGS.value_of("backend").unwrap_or(DEFAULT_BACKEND);
-    let backend_options = CLI_ARGS.value_of("backend_opts").unwrap_or(DEFAULT_BACKEND_OPTS);
-    for caps in BACKEND_RE.captures_iter(backend_options) {
-        let backend = &caps["backend"];
-        if ! optmap.contains_key(backend) {
-            optmap.insert(backend.to_owned(), HashMap::new());
+    let mut optmap = config_sections("backend_opts");
+    let backends = config_values("backend")
+        .unwrap_or_else(|| vec![DEFAULT_BACKEND.to_owned()]);
+    let backend_options = config_values("backend_opts")
+        .unwrap_or_else(|| vec![DEFAULT_BACKEND_OPTS.to_owned()]);
+    // The pattern is anchored, so each ';'-separated entry is matched alone
+    let entries = |list: &[String]| list.iter()
+        .flat_map(|l| split_unescaped(l, ';'))
+        .collect::<Vec<String>>();
+    for entry in entries(&backend_options) {
+        if let Some(caps) = BACKEND_RE.captures(&entry) {
+            parse_options(
+                caps.name("options").map_or("", |m| m.as_str()),
+                optmap.entry(caps["backend"].to_owned()).or_insert_with(HashMap::new));
         }
-        parse_options(
-            &caps.name("options").map(|m| m.as_str()).unwrap_or(""),
-            optmap.get_mut(backend).unwrap());
     }
-    for caps in BACKEND_RE.captures_iter(backends) {
-        let backend = &caps["backend"];
-        if ! optmap.contains_key(backend) {
-            optmap.insert(backend.to_owned(), HashMap::new());
+    for entry in entries(&backends) {
+        if let Some(caps) = BACKEND_RE.captures(&entry) {
+            backlist.push(caps["backend"].to_owned());
+            parse_options(
+                caps.name("options").map_or("", |m| m.as_str()),
+                optmap.entry(caps["backend"].to_owned()).or_insert_with(HashMap::new));
         }
-        backlist.push(backend.to_owned());
-        parse_options(
-            &caps.name("options").map(|m| m.as_str()).unwrap_or(""),
-            optmap.get_mut(backend).unwrap());
-
     }
     return (backlist,optmap)
 }
@@ -601,51 +1068,25 @@ fn parse_options<'s>(optstr: &'s str, optmap: &mut HashMap<String, String>) {
         [^,;]+)").unwrap();
     }
     for caps in OPT_RE.captures_iter(optstr) {
-        //FIXME: Un-escape commas and semicolons
-        optmap.insert((&caps["name"]).to_owned(), (&caps["value"]).to_owned());
-
+        optmap.insert((&caps["name"]).to_owned(), unescape(&caps["value"]));
     }
 }
 
-/// Something that can give the device's orientation.
-pub trait Orientator {
-    /// Returns the current orientation, if it can figure it out.
-    fn orientation(&mut self) -> Option<Rotation>;
-}
-
-
-#[derive(Debug,PartialEq,Clone,Copy)]
-pub enum Rotation {
-    Normal,
-    Left,
-    Inverted,
-    Right,
-}
-use self::Rotation::*;
-
-// pub struct RotParseErr (
-#[derive(Debug)]
-pub enum RotParseErrKind {
-    TooShort,
-    TooLong,
-    NoMatch,
-}
-
-impl Default for Rotation {
-    fn default() -> Rotation {
-        Rotation::Normal
-    }
-}
-
-impl Display for Rotation {
-    fn fmt(&self, f: &mut Formatter) -> FmtResult {
-        match self {
-            &Normal => write!(f, "normal"),
-            &Left   => write!(f, "left"),
-            &Inverted   => write!(f, "inverted"),
-            &Right  => write!(f, "right"),
+/// Undo the escaping of separators (`\,`, `\;`, `\|`) and backslashes
+/// in an option value. Other backslashes are left alone.
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some(&n)) if ",;|\\".contains(n) => {
+                out.push(n);
+                chars.next();
+            },
+            _   => out.push(c),
         }
     }
+    out
 }
 
 lazy_static! {
@@ -659,44 +1100,6 @@ fn get_pid_file() -> PathBuf {
     get_path("pidfile", DEFAULT_PID_FILE, false)
 }
 
-/// Gets the user spinnrd should run as
-fn get_user() -> daemonize::User {
-    CLI_ARGS.value_of("user")
-        .map_or_else(
-            || daemonize::User::from(unsafe{geteuid()}),
-            |s| match s.parse::<u32>() {
-                    Ok(n)   => daemonize::User::from(n),
-                    Err(_)  => daemonize::User::from(s)
-            })
-}
-
-/// Gets the group spinnrd should run as
-fn get_group() -> daemonize::Group {
-    CLI_ARGS.value_of("group")
-        .map_or_else(
-            || daemonize::Group::from(unsafe{getegid()}),
-            |s| match s.parse::<u32>() {
-                    Ok(n)   => daemonize::Group::from(n),
-                    Err(_)  => daemonize::Group::from(s)
-            })
-}
-
-/*
- * /// Gets the name corresponding to a uid
- * fn get_uid_name(uid: uid_t) -> String {
- *     //TODO: writeme
- * 	let mut buf = mem::uninitialized::<[c_char; 256]>();
- * 	let ptr = buf.as_ptr();
- * 	let len = buf.len();
- *     String::new();
- * }
- * /// Gets the group name corresponding to a gid
- * fn get_gid_name(gid: gid_t) -> String {
- *     //TODO: writeme
- *     String::new();
- * }
- */
-
 macro_rules! timef {
     ( $now:ident, $str:expr, $($func:ident),* ) => {
         timef!(@inner $now, $str, $($func),*$(,)*)
@@ -720,7 +1123,7 @@ fn parse_path(input: &str, isdir: bool) -> String {
         static ref PATH_RE: Regex = Regex::new(r"(?x)
         % (
             # The underscore-able basic matches
-            _?[eEtT] |
+            _?[eEtTuUgG] |
             # The other basic matches
             [dx%] |
             # A custom format string
@@ -763,6 +1166,14 @@ fn parse_path(input: &str, isdir: bool) -> String {
             "_t" => timef!(NOW_LOCAL: STRF_8601_BASIC_NS),
             "T"  => timef!(NOW_UTC: STRF_8601_BASIC),
             "_T" => timef!(NOW_UTC: STRF_8601_BASIC_NS),
+            "u"  => running().user.clone(),
+            "_u" => calling().user.clone(),
+            "U"  => running().uid.to_string(),
+            "_U" => calling().uid.to_string(),
+            "g"  => running().group.clone(),
+            "_g" => calling().group.clone(),
+            "G"  => running().gid.to_string(),
+            "_G" => calling().gid.to_string(),
             "x"  => std::env::var("XDG_RUNTIME_DIR")
                 .unwrap_or_else(|_| "/tmp".to_owned()),
             x    => {
@@ -782,13 +1193,19 @@ fn parse_path(input: &str, isdir: bool) -> String {
     }).into_owned()
 }
 fn get_path(name: &str, default: &str, isdir: bool) -> PathBuf {
-    PathBuf::from(parse_path(CLI_ARGS.value_of(name).unwrap_or(default), isdir))
+    PathBuf::from(parse_path(&config_value(name).unwrap_or_else(|| default.to_owned()), isdir))
 }
 
 lazy_static! {
     static ref WORKING_DIR: PathBuf = get_working_dir().unwrap();
 }
 
+/// Whether `dir` is spinnrd's alone to give away, i.e. it isn't shared
+/// (sticky or world-writable, like the `/tmp` fallback)
+fn is_private(dir: &Path) -> bool {
+    dir.metadata().is_ok_and(|m| 0 == m.mode() & 0o1002)
+}
+
 /// Get the working directory (where files go by default)
 #[inline]
 fn get_working_dir() -> Result<PathBuf, IoError> {
@@ -823,10 +1240,10 @@ fn get_working_dir() -> Result<PathBuf, IoError> {
 /// Get the list of orientators to try
 
 
-/// Get the u32 value of an argument to a command-line option.
+/// Get the u32 value of a setting.
 /// Returns `None` if parsing fails.
 fn get_u32_arg_val(name: &str) -> Option<u32> {
-    if let Some(s) = CLI_ARGS.value_of(name) {
+    if let Some(s) = config_value(name) {
         s.parse::<u32>().map_err(|e|
                                  warn!("Can't parse '{}' as a uint ({})!", s, e)
                                 )
@@ -834,10 +1251,10 @@ fn get_u32_arg_val(name: &str) -> Option<u32> {
     } else { None }
 }
 
-/// Get the f64 value of an argument to a command-line option.
+/// Get the f64 value of a setting.
 /// Returns `None` if parsing fails.
 fn get_f64_arg_val(name: &str) -> Option<f64> {
-    if let Some(s) = CLI_ARGS.value_of(name) {
+    if let Some(s) = config_value(name) {
         s.parse::<f64>().map_err(|e|
                                  warn!("Can't parse '{}' as a float ({})!", s, e)
                                 )
@@ -845,6 +1262,16 @@ fn get_f64_arg_val(name: &str) -> Option<f64> {
     } else { None }
 }
 
+/// Get a duration in whole milliseconds
+fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + u64::from(d.subsec_millis())
+}
+
+/// Format a vector as comma-separated components
+fn csv(v: AccelerationVector<f64>) -> String {
+    format!("{},{},{}", v.x, v.y, v.z)
+}
+
 /// Check that an argument is a valid u32
 fn validate_u32(v: String) -> Result<(), String> {
     if "" == v { return Ok(()) };
@@ -854,6 +1281,18 @@ fn validate_u32(v: String) -> Result<(), String> {
     }
 }
 
+/// Check that an argument is a rotation
+fn validate_rotation(v: String) -> Result<(), String> {
+    v.parse::<Rotation>()
+        .map(|_| ())
+        .map_err(|e| format!("bad rotation '{}': {}", v, e))
+}
+
+/// Check that an argument is a set of rotations
+fn validate_rotations(v: String) -> Result<(), String> {
+    parse_set(&v).map(|_| ())
+}
+
 /// Check that an argument is a valid f64
 fn validate_f64(v: String) -> Result<(), String> {
     if "" == v { return Ok(()) };
@@ -863,20 +1302,33 @@ fn validate_f64(v: String) -> Result<(), String> {
     }
 }
 
-/// Returns true if we should quit if an error occurs
-/// when sending rotation.
-fn quit_on_rotation_send_error() -> bool {
-    //TODO: addopt //addopt means add command line option
-    DEFAULT_QUIT_ON_ROTATION_SEND_ERR
-}
 
-/// Remove the pid file at `p`.
-fn rm_pid_file(p: &PathBuf) {
-    match remove_file(p) {
-        Ok(_)   => {},
-        Err(e)  => {
-            error!("Error removing PID file '{}': {}", p.display(), e);
-            // return 7i32;
-        },
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaped_separators() {
+        assert_eq!(split_unescaped(r"file|socket\|x|fbcon", '|'), vec!["file", r"socket\|x", "fbcon"]);
+        assert_eq!(split_unescaped(r"a\\|b", '|'), vec![r"a\\", "b"]);
+        assert_eq!(split_unescaped(r"a\;b;c;", ';'), vec![r"a\;b", "c", ""]);
+        assert_eq!(split_unescaped("", ';'), vec![""]);
+
+        assert_eq!(unescape(r"a\|b\;c\,d"), "a|b;c,d");
+        assert_eq!(unescape(r"a\\|b"), r"a\|b");
+        assert_eq!(unescape(r"C:\dir\"), r"C:\dir\");
+    }
+
+    #[test]
+    fn escaped_options() {
+        let parts = split_unescaped(r"file,path=/tmp/a\|b\;c|socket,mode=600", '|');
+        assert_eq!(parts.len(), 2);
+        let mut opts = HashMap::new();
+        parse_options(&parts[0]["file".len()..], &mut opts);
+        assert_eq!(opts.get("path").map(String::as_str), Some("/tmp/a|b;c"));
+        opts.clear();
+        parse_options(&parts[1]["socket".len()..], &mut opts);
+        assert_eq!(opts.get("mode").map(String::as_str), Some("600"));
     }
 }
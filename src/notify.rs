@@ -0,0 +1,166 @@
+//! # notify
+//! Tells systemd how spinnrd is doing (the `sd_notify` protocol): that it's
+//! ready, what it's showing, that the main loop is still turning over (for
+//! `WatchdogSec=`), and that it's reloading or stopping. Each notification
+//! is a datagram of `KEY=value` lines sent to `$NOTIFY_SOCKET`, so it
+//! doesn't need libsystemd. Without `$NOTIFY_SOCKET`, nothing is sent.
+
+use super::*;
+
+use std::env;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+/// Tells systemd what's going on
+pub struct Notifier {
+    sock: UnixDatagram,
+    addr: SocketAddr,
+    /// How often systemd wants to hear from the watchdog, if it does
+    watchdog: Option<Duration>,
+    last_ping: Option<Instant>,
+    /// The last status sent, so it's only sent when it changes
+    status: String,
+}
+
+impl Notifier {
+    /// Set up notifications to `$NOTIFY_SOCKET`, if systemd set it. The
+    /// variables are cleared so child processes don't pick them up.
+    pub fn from_env() -> Option<Notifier> {
+        let path = env::var_os("NOTIFY_SOCKET")?;
+        let watchdog = watchdog_from_env();
+        for var in &["NOTIFY_SOCKET", "WATCHDOG_USEC", "WATCHDOG_PID"] {
+            env::remove_var(var);
+        }
+        let path = path.to_string_lossy().into_owned();
+        let addr = if let Some(name) = path.strip_prefix('@') {
+            SocketAddr::from_abstract_name(name.as_bytes())
+        } else {
+            SocketAddr::from_pathname(&path)
+        };
+        match addr.and_then(|a| Notifier::new(a, watchdog)) {
+            Ok(n)   => Some(n),
+            Err(e)  => {
+                warn!("Can't notify systemd at {} ({})", path, e);
+                None
+            },
+        }
+    }
+
+    fn new(addr: SocketAddr, watchdog: Option<Duration>) -> Result<Notifier, IoError> {
+        let sock = UnixDatagram::unbound()?;
+        sock.set_nonblocking(true)?;
+        Ok(Notifier { sock, addr, watchdog, last_ping: None, status: String::new() })
+    }
+
+    /// Send `state` (`KEY=value` lines).
+    fn send(&self, state: &str) {
+        debug!("Notifying systemd: {}", state.replace('\n', "; "));
+        if let Err(e) = self.sock.send_to_addr(state.as_bytes(), &self.addr) {
+            warn!("Couldn't notify systemd ({})", e);
+        }
+    }
+
+    /// How often the watchdog has to hear from us, if it's on
+    pub fn watchdog(&self) -> Option<Duration> {
+        self.watchdog
+    }
+
+    /// Startup's done.
+    pub fn ready(&mut self, status: String) {
+        self.send(&format!("READY=1\nSTATUS={}", status));
+        self.status = status;
+    }
+
+    /// Say what's going on, if it's changed.
+    pub fn status(&mut self, status: String) {
+        if status != self.status {
+            self.send(&format!("STATUS={}", status));
+            self.status = status;
+        }
+    }
+
+    /// The main loop's alive; pings the watchdog every half period.
+    pub fn alive(&mut self, now: Instant) {
+        if let Some(period) = self.watchdog {
+            if self.last_ping.is_none_or(|t| now.duration_since(t) >= period / 2) {
+                self.send("WATCHDOG=1");
+                self.last_ping = Some(now);
+            }
+        }
+    }
+
+    /// Settings are being reloaded; `ready` says when that's done.
+    pub fn reloading(&self) {
+        self.send(&format!("RELOADING=1\nMONOTONIC_USEC={}", monotonic_usec()));
+    }
+
+    pub fn stopping(&self) {
+        self.send("STOPPING=1");
+    }
+}
+
+/// The watchdog period systemd asked for, if it's meant for us
+fn watchdog_from_env() -> Option<Duration> {
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    match env::var("WATCHDOG_PID").ok().and_then(|p| p.parse::<u32>().ok()) {
+        Some(pid) if pid != std::process::id()  => None,
+        _ if 0 == usec  => None,
+        _   => Some(Duration::from_micros(usec)),
+    }
+}
+
+/// `CLOCK_MONOTONIC` in microseconds, as `RELOADING=1` wants
+fn monotonic_usec() -> u64 {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1000
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A notifier, and the socket its notifications go to
+    fn pair(name: &str, watchdog: Option<Duration>) -> (Notifier, UnixDatagram) {
+        let path = env::temp_dir().join(format!("spinnrd-notify-{}-{}", name, std::process::id()));
+        let _ = remove_file(&path);
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        systemd.set_nonblocking(true).unwrap();
+        let n = Notifier::new(SocketAddr::from_pathname(&path).unwrap(), watchdog).unwrap();
+        (n, systemd)
+    }
+
+    /// Everything sent so far
+    fn received(sock: &UnixDatagram) -> Vec<String> {
+        let mut buf = [0u8; 256];
+        let mut got = Vec::new();
+        while let Ok(n) = sock.recv(&mut buf) {
+            got.push(String::from_utf8_lossy(&buf[..n]).into_owned());
+        }
+        got
+    }
+
+    #[test]
+    fn ready_and_status() {
+        let (mut n, systemd) = pair("status", None);
+        n.ready("Rotation normal".to_owned());
+        n.status("Rotation normal".to_owned());
+        n.status("Rotation left".to_owned());
+        n.alive(Instant::now());
+        n.stopping();
+        assert_eq!(received(&systemd),
+                   vec!["READY=1\nSTATUS=Rotation normal", "STATUS=Rotation left", "STOPPING=1"]);
+    }
+
+    #[test]
+    fn watchdog_every_half_period() {
+        let (mut n, systemd) = pair("watchdog", Some(Duration::from_secs(2)));
+        let start = Instant::now();
+        for ms in (0..3000).step_by(150) {
+            n.alive(start + Duration::from_millis(ms));
+        }
+        // At 0, 1050 and 2100ms
+        assert_eq!(received(&systemd), vec!["WATCHDOG=1"; 3]);
+    }
+}
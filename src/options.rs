@@ -0,0 +1,311 @@
+//! # options
+//! Option schemas for backends and frontends. Each one declares the options
+//! it takes, with their types, defaults and help; the schema checks the
+//! options it's given and generates the help text.
+
+use super::*;
+
+/// What an option's value has to look like
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum OptType {
+    /// Anything
+    Text,
+    /// A filename; uses filename formatting
+    Path,
+    /// `true` or `false`
+    Bool,
+    /// A whole number
+    Uint,
+    /// Any number (only the accelerometer backends take one)
+    #[cfg(feature = "fsaccel")]
+    Float,
+    /// An octal number, such as a file mode
+    Octal,
+    /// One of a fixed set of words
+    Choice(&'static [&'static str]),
+}
+
+impl OptType {
+    /// Whether `value` is of this type
+    fn accepts(&self, value: &str) -> bool {
+        match *self {
+            OptType::Text | OptType::Path   => true,
+            OptType::Bool   => value.parse::<bool>().is_ok(),
+            OptType::Uint   => value.parse::<u64>().is_ok(),
+            #[cfg(feature = "fsaccel")]
+            OptType::Float  => value.parse::<f64>().is_ok(),
+            OptType::Octal  => u32::from_str_radix(value, 8).is_ok(),
+            OptType::Choice(words)  => words.contains(&value),
+        }
+    }
+}
+
+impl Display for OptType {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            OptType::Text   => write!(f, "text"),
+            OptType::Path   => write!(f, "a filename"),
+            OptType::Bool   => write!(f, "'true' or 'false'"),
+            OptType::Uint   => write!(f, "a whole number"),
+            #[cfg(feature = "fsaccel")]
+            OptType::Float  => write!(f, "a number"),
+            OptType::Octal  => write!(f, "an octal number"),
+            OptType::Choice(words)  => {
+                let quoted: Vec<String> = words.iter().map(|w| format!("'{}'", w)).collect();
+                write!(f, "one of {}", quoted.join(", "))
+            },
+        }
+    }
+}
+
+/// An option a backend or frontend takes
+#[derive(Debug)]
+pub struct OptSpec {
+    pub name: &'static str,
+    pub kind: OptType,
+    /// Used when the option isn't given
+    pub default: Option<&'static str>,
+    pub help: &'static str,
+}
+
+/// Every option a backend or frontend takes
+pub type Schema = &'static [OptSpec];
+
+/// Errors checking options against a schema
+#[derive(Debug)]
+pub enum OptionError {
+    /// An option that isn't in the schema (owner, option)
+    Unknown(String, String),
+    /// An option with a bad value (owner, option, value, what it should be)
+    Invalid(String, String, String, String),
+}
+
+impl Display for OptionError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            OptionError::Unknown(ref owner, ref opt)    => {
+                write!(f, "{} has no option '{}'", owner, opt)
+            },
+            OptionError::Invalid(ref owner, ref opt, ref value, ref expected)    => {
+                write!(f, "invalid value '{}' for {} option '{}' (expected {})",
+                       value, owner, opt, expected)
+            },
+        }
+    }
+}
+
+impl std::error::Error for OptionError {
+    fn description(&self) -> &str {
+        "bad option"
+    }
+}
+
+/// Options that have been checked against a schema, with defaults filled in
+#[derive(Debug,Clone)]
+pub struct Options {
+    /// The backend or frontend they're for
+    owner: String,
+    values: HashMap<String, String>,
+}
+
+impl Options {
+    /// Check `raw` against `schemas`, filling in defaults.
+    pub fn parse(owner: &str, schemas: &[Schema], raw: &HashMap<String, String>)
+        -> Result<Options, OptionError> {
+        let specs = || schemas.iter().flat_map(|s| s.iter());
+        let mut values = HashMap::new();
+        for (name, value) in raw {
+            let spec = specs().find(|s| s.name == name)
+                .ok_or_else(|| OptionError::Unknown(owner.to_owned(), name.clone()))?;
+            if ! spec.kind.accepts(value) {
+                return Err(OptionError::Invalid(
+                        owner.to_owned(), name.clone(), value.clone(), spec.kind.to_string()));
+            }
+            values.insert(name.clone(), value.clone());
+        }
+        for spec in specs() {
+            if let Some(d) = spec.default {
+                values.entry(spec.name.to_owned()).or_insert_with(|| d.to_owned());
+            }
+        }
+        Ok(Options {
+            owner: owner.to_owned(),
+            values,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    /// A `Bool` option; false if it's unset
+    pub fn flag(&self, name: &str) -> bool {
+        self.get(name).and_then(|v| v.parse().ok()).unwrap_or(false)
+    }
+
+    pub fn uint(&self, name: &str) -> Option<u64> {
+        self.get(name).and_then(|v| v.parse().ok())
+    }
+
+    #[cfg(feature = "fsaccel")]
+    pub fn float(&self, name: &str) -> Option<f64> {
+        self.get(name).and_then(|v| v.parse().ok())
+    }
+
+    pub fn octal(&self, name: &str) -> Option<u32> {
+        self.get(name).and_then(|v| u32::from_str_radix(v, 8).ok())
+    }
+
+    /// A `Path` option, with its filename formatting expanded
+    pub fn path(&self, name: &str) -> Option<PathBuf> {
+        self.get(name).map(|v| PathBuf::from(parse_path(v, false)))
+    }
+
+    /// An error for option `name`'s value, for checks beyond its type
+    pub fn invalid(&self, name: &str, expected: &str) -> OptionError {
+        OptionError::Invalid(self.owner.clone(), name.to_owned(),
+                             self.get(name).unwrap_or("").to_owned(), expected.to_owned())
+    }
+}
+
+/// Help for a schema, for `--help`
+pub fn schema_help(owner: &str, schema: Schema) -> String {
+    let mut help = format!("\n    For {}:\n", owner);
+    help.push_str(&options_help(schema));
+    help
+}
+
+/// Help for registered backends or frontends, given each one's name, help
+/// and schema. Those with the same options as one before just say so.
+pub fn schemas_help(entries: &[(&str, &str, Schema)]) -> String {
+    let names = |schema: Schema| schema.iter().map(|s| s.name).collect::<Vec<_>>();
+    let mut help = String::new();
+    for (i, &(owner, about, schema)) in entries.iter().enumerate() {
+        help.push('\n');
+        help.push_str(&wrap(&format!("For {}: {}", owner, about), 4, 8, 76));
+        match entries[..i].iter().find(|e| ! schema.is_empty() && names(e.2) == names(schema)) {
+            Some(e) => help.push_str(&wrap(&format!("The same options as {}.", e.0), 8, 12, 76)),
+            None    => help.push_str(&options_help(schema)),
+        }
+    }
+    help
+}
+
+/// Help for each option in a schema
+fn options_help(schema: Schema) -> String {
+    let mut help = String::new();
+    for spec in schema {
+        let mut text = format!("{}: {}", spec.name, spec.help);
+        if let Some(d) = spec.default {
+            text.push_str(&format!(" Defaults to {}.", d));
+        }
+        help.push_str(&wrap(&text, 8, 12, 76));
+    }
+    help
+}
+
+/// Word-wrap `text` to `width` columns, indenting the first line by
+/// `first` spaces and the rest by `rest`.
+fn wrap(text: &str, first: usize, rest: usize, width: usize) -> String {
+    let mut out = String::new();
+    let mut line = " ".repeat(first);
+    let mut empty = true;
+    for word in text.split_whitespace() {
+        if ! empty && line.len() + 1 + word.len() > width {
+            out.push_str(&line);
+            out.push('\n');
+            line = " ".repeat(rest);
+            empty = true;
+        }
+        if ! empty { line.push(' '); }
+        line.push_str(word);
+        empty = false;
+    }
+    out.push_str(&line);
+    out.push('\n');
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMA: Schema = &[
+        OptSpec { name: "path", kind: OptType::Path, default: Some("/tmp/spin"), help: "Where." },
+        OptSpec { name: "count", kind: OptType::Uint, default: Some("3"), help: "How many." },
+        OptSpec { name: "loud", kind: OptType::Bool, default: None, help: "Whether to shout." },
+        OptSpec { name: "mode", kind: OptType::Octal, default: None, help: "Its mode." },
+        OptSpec { name: "when", kind: OptType::Choice(&["now", "later"]), default: Some("now"), help: "When." },
+    ];
+
+    const OTHER: Schema = &[
+        OptSpec { name: "note", kind: OptType::Text, default: None, help: "Anything at all." },
+    ];
+
+    fn parse(raw: &[(&str, &str)]) -> Result<Options, OptionError> {
+        let raw = raw.iter().map(|&(k, v)| (k.to_owned(), v.to_owned())).collect();
+        Options::parse("thing", &[SCHEMA, OTHER], &raw)
+    }
+
+    #[test]
+    fn defaults() {
+        let o = parse(&[]).unwrap();
+        assert_eq!(o.path("path"), Some(PathBuf::from("/tmp/spin")));
+        assert_eq!(o.uint("count"), Some(3));
+        assert_eq!(o.get("when"), Some("now"));
+        assert!(! o.flag("loud"));
+        assert_eq!(o.octal("mode"), None);
+        assert_eq!(o.get("note"), None);
+
+        let o = parse(&[("count", "10"), ("loud", "true"), ("mode", "640"), ("note", "hi"), ("when", "later")]).unwrap();
+        assert_eq!(o.uint("count"), Some(10));
+        assert!(o.flag("loud"));
+        assert_eq!(o.octal("mode"), Some(0o640));
+        assert_eq!(o.get("note"), Some("hi"));
+        assert_eq!(o.get("when"), Some("later"));
+    }
+
+    #[test]
+    fn bad_options() {
+        match parse(&[("colour", "red")]) {
+            Err(e @ OptionError::Unknown(..))   => assert_eq!(e.to_string(), "thing has no option 'colour'"),
+            r   => panic!("{:?}", r),
+        }
+        for &(name, value) in &[("count", "-1"), ("count", "many"), ("loud", "yes"),
+                                ("mode", "8"), ("when", "never")] {
+            match parse(&[(name, value)]) {
+                Err(OptionError::Invalid(ref o, ref n, ref v, _)) if o == "thing" && n == name && v == value => {},
+                r   => panic!("{}={}: {:?}", name, value, r),
+            }
+        }
+        assert_eq!(parse(&[("when", "never")]).unwrap_err().to_string(),
+                   "invalid value 'never' for thing option 'when' (expected one of 'now', 'later')");
+    }
+
+    #[cfg(feature = "fsaccel")]
+    #[test]
+    fn floats() {
+        const SCALE: Schema = &[
+            OptSpec { name: "scale", kind: OptType::Float, default: Some("1"), help: "The scale." },
+        ];
+        let parse = |v: &str| Options::parse("thing", &[SCALE],
+                                              &[("scale".to_owned(), v.to_owned())].iter().cloned().collect());
+        assert_eq!(parse("0.25").unwrap().float("scale"), Some(0.25));
+        assert!(parse("a quarter").is_err());
+        assert_eq!(Options::parse("thing", &[SCALE], &HashMap::new()).unwrap().float("scale"), Some(1.0));
+    }
+
+    #[test]
+    fn help() {
+        let help = schemas_help(&[("one", "The first.", SCHEMA), ("two", "The second.", SCHEMA),
+                                  ("three", "The third.", OTHER), ("four", "Takes nothing.", &[])]);
+        assert!(help.contains("    For one: The first.\n        path: Where. Defaults to /tmp/spin.\n"));
+        assert!(help.contains("        loud: Whether to shout.\n"));
+        assert!(help.contains("    For two: The second.\n        The same options as one.\n"));
+        assert!(help.contains("    For three: The third.\n        note: Anything at all.\n"));
+        assert!(help.ends_with("    For four: Takes nothing.\n"));
+        assert!(help.lines().all(|l| l.len() <= 76));
+        assert_eq!(wrap("aaa bbb ccc", 2, 4, 9), "  aaa bbb\n    ccc\n");
+    }
+}
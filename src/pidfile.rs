@@ -0,0 +1,209 @@
+//! # pidfile
+//! The pid file, which also keeps to one spinnrd per pid file: whoever holds
+//! an exclusive `flock` on it is the running instance. The lock goes with
+//! the process however it dies, so a pid file that's there but not locked
+//! was left behind by a crash, and is just taken over.
+
+use super::*;
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// How long `--replace` waits for the old instance to let go
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum PidFileError {
+    /// Couldn't open, lock or write the pid file
+    Io(PathBuf, IoError),
+    /// Another instance holds the pid file (its pid, if it wrote one)
+    Running(PathBuf, Option<libc::pid_t>),
+    /// The other instance didn't stop when asked to (its pid)
+    NotReplaced(PathBuf, libc::pid_t),
+}
+
+impl Display for PidFileError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            PidFileError::Io(ref p, ref e)  => {
+                write!(f, "couldn't take pid file {}: {}", p.to_string_lossy(), e)
+            },
+            PidFileError::Running(ref p, Some(pid)) => {
+                write!(f, "spinnrd is already running (pid {}, pid file {}); --replace takes over from it",
+                       pid, p.to_string_lossy())
+            },
+            PidFileError::Running(ref p, None)  => {
+                write!(f, "another spinnrd holds pid file {}", p.to_string_lossy())
+            },
+            PidFileError::NotReplaced(ref p, pid)   => {
+                write!(f, "spinnrd (pid {}) didn't stop within {}s, so pid file {} is still its",
+                       pid, REPLACE_TIMEOUT.as_secs(), p.to_string_lossy())
+            },
+        }
+    }
+}
+
+impl std::error::Error for PidFileError {
+    fn description(&self) -> &str {
+        "couldn't take pid file"
+    }
+}
+
+/// A locked pid file, removed when dropped
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl PidFile {
+    /// Take the pid file at `path`. If another instance has it, ask that one
+    /// to stop if `replace`, or give up otherwise.
+    pub fn take(path: PathBuf, replace: bool) -> Result<PidFile, PidFileError> {
+        let io = |e| PidFileError::Io(path.clone(), e);
+        let (mut file, locked) = open_locked(&path).map_err(io)?;
+        let mut file = if locked {
+            file
+        } else {
+            let pid = match read_pid(&mut file) {
+                Some(pid) if replace && pid != std::process::id() as libc::pid_t   => pid,
+                pid => return Err(PidFileError::Running(path, pid)),
+            };
+            info!("Asking spinnrd (pid {}) to stop", pid);
+            if 0 != unsafe { libc::kill(pid, libc::SIGTERM) } {
+                let e = IoError::last_os_error();
+                if Some(libc::ESRCH) != e.raw_os_error() {
+                    return Err(io(e));
+                }
+            }
+            let start = Instant::now();
+            loop {
+                thread::sleep(Duration::from_millis(100));
+                match open_locked(&path).map_err(io)? {
+                    (f, true)   => break f,
+                    _ if start.elapsed() >= REPLACE_TIMEOUT => {
+                        return Err(PidFileError::NotReplaced(path, pid));
+                    },
+                    _   => {},
+                }
+            }
+        };
+        if let Some(pid) = read_pid(&mut file) {
+            info!("Taking over pid file {} from pid {}, which is gone", path.to_string_lossy(), pid);
+        }
+        let mut pidfile = PidFile { path, file };
+        pidfile.update()?;
+        Ok(pidfile)
+    }
+
+    /// Write our pid, which changes when daemonizing.
+    pub fn update(&mut self) -> Result<(), PidFileError> {
+        let pid = std::process::id();
+        self.file.set_len(0)
+            .and_then(|_| self.file.seek(SeekFrom::Start(0)))
+            .and_then(|_| writeln!(self.file, "{}", pid))
+            .map_err(|e| PidFileError::Io(self.path.clone(), e))?;
+        debug!("Wrote pid {} to {}", pid, self.path.to_string_lossy());
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for PidFile {
+    /// Remove the pid file if we can. It's the lock that counts, so one
+    /// left behind (say, in a directory we can't write to any more) is just
+    /// stale.
+    fn drop(&mut self) {
+        let ours = self.file.metadata()
+            .and_then(|f| self.path.metadata().map(|p| f.dev() == p.dev() && f.ino() == p.ino()));
+        match ours {
+            Ok(true)    => if let Err(e) = remove_file(&self.path) {
+                debug!("Left pid file {} behind ({})", self.path.to_string_lossy(), e);
+            },
+            Ok(false)   => debug!("Pid file {} isn't ours any more", self.path.to_string_lossy()),
+            Err(e)  => debug!("Left pid file {} alone ({})", self.path.to_string_lossy(), e),
+        }
+    }
+}
+
+/// Open the pid file at `path` and try to lock it; whether it's locked
+/// comes with it.
+fn open_locked(path: &Path) -> Result<(File, bool), IoError> {
+    loop {
+        let file = OpenOptions::new().read(true).write(true)
+            .create(true).truncate(false).mode(0o644)
+            .open(path)?;
+        if 0 != unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } {
+            let e = IoError::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::EWOULDBLOCK) => Ok((file, false)),
+                _   => Err(e),
+            };
+        }
+        // The last holder might have removed it between our opening and
+        // locking it, in which case the lock's on a file nobody else can see.
+        let ours = file.metadata()?;
+        match path.metadata() {
+            Ok(m) if m.dev() == ours.dev() && m.ino() == ours.ino() => return Ok((file, true)),
+            Ok(_)   => {},
+            Err(ref e) if std::io::ErrorKind::NotFound == e.kind()  => {},
+            Err(e)  => return Err(e),
+        }
+    }
+}
+
+/// The pid in a pid file, if there is one
+fn read_pid(file: &mut File) -> Option<libc::pid_t> {
+    let mut s = String::new();
+    file.seek(SeekFrom::Start(0)).and_then(|_| file.read_to_string(&mut s)).ok()?;
+    s.trim().parse().ok().filter(|&pid| 0 < pid)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("spinnrd-test-{}-{}.pid", name, std::process::id()))
+    }
+
+    #[test]
+    fn one_at_a_time() {
+        let p = path("single");
+        let first = PidFile::take(p.clone(), false).unwrap();
+        assert_eq!(std::fs::read_to_string(&p).unwrap(), format!("{}\n", std::process::id()));
+        match PidFile::take(p.clone(), true) {
+            Err(PidFileError::Running(_, Some(pid))) => assert_eq!(pid as u32, std::process::id()),
+            r   => panic!("took a held pid file: {:?}", r),
+        }
+        drop(first);
+        assert!(! p.exists());
+        drop(PidFile::take(p, false).unwrap());
+    }
+
+    #[test]
+    fn leaves_others_alone() {
+        let p = path("replaced");
+        let pidfile = PidFile::take(p.clone(), false).unwrap();
+        remove_file(&p).unwrap();
+        std::fs::write(&p, "1\n").unwrap();
+        drop(pidfile);
+        assert_eq!(std::fs::read_to_string(&p).unwrap(), "1\n");
+        remove_file(&p).unwrap();
+    }
+
+    #[test]
+    fn takes_over_stale() {
+        let p = path("stale");
+        std::fs::write(&p, "2147483646\n").unwrap();
+        let pidfile = PidFile::take(p.clone(), false).unwrap();
+        assert_eq!(std::fs::read_to_string(&p).unwrap(), format!("{}\n", std::process::id()));
+        drop(pidfile);
+    }
+}
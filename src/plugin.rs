@@ -0,0 +1,494 @@
+//! # plugin
+//! Backends and frontends loaded from shared objects, for sensors and
+//! display stacks that can't be built in.
+//!
+//! A plugin exports `spinnr_plugins`, which returns an array of
+//! `SpinnrPlugin` descriptors and its length. Each descriptor gives the ABI
+//! version it was built against (`SPINNR_PLUGIN_ABI`), whether it's a
+//! backend or a frontend, the name it's asked for by, and the functions to
+//! call. Instances are made from their options (the same name/value pairs
+//! a built-in gets) and are only ever used from one thread at a time, but
+//! not necessarily the thread that made them.
+//!
+//! The ABI version only changes when the descriptor or a callback's
+//! signature does; descriptors for any other version are refused.
+
+use super::Rotation;
+use super::{Orientator, Frontend, Announcement, SendError, SendResult};
+use accel::AccelerationVector;
+use capi::{SpinnrRotation, SpinnrVector};
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fmt::{Display, Formatter};
+use std::fmt::Result as FmtResult;
+use std::io::Error as IoError;
+use std::os::raw::{c_char, c_int, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::Arc;
+
+/// The plugin ABI version
+pub const SPINNR_PLUGIN_ABI: u32 = 1;
+
+/// The symbol a plugin exports
+const ENTRY_POINT: &[u8] = b"spinnr_plugins\0";
+
+/// What a plugin provides
+#[repr(C)]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SpinnrPluginKind {
+    Backend = 1,
+    Frontend = 2,
+}
+
+/// What a frontend is told besides rotations (see `Announcement`)
+#[repr(C)]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SpinnrAnnouncement {
+    Pending = 0,
+    Suggested = 1,
+    Cancelled = 2,
+}
+
+/// One of an instance's options
+#[repr(C)]
+pub struct SpinnrOption {
+    pub name: *const c_char,
+    pub value: *const c_char,
+}
+
+/// A backend or frontend a plugin provides. Optional functions may be NULL.
+#[repr(C)]
+pub struct SpinnrPlugin {
+    /// `SPINNR_PLUGIN_ABI`, as the plugin was built against it
+    pub abi: u32,
+    pub kind: SpinnrPluginKind,
+    /// The name it's asked for by
+    pub name: *const c_char,
+    /// A line of help (optional)
+    pub help: *const c_char,
+    /// Make an instance from `count` options. On failure, return NULL and
+    /// optionally point `error` at a static message.
+    pub open: Option<extern "C" fn(options: *const SpinnrOption, count: usize,
+                                   error: *mut *const c_char) -> *mut c_void>,
+    /// Free an instance (optional)
+    pub close: Option<extern "C" fn(instance: *mut c_void)>,
+    /// Backends: the device's orientation (a `SpinnrRotation`)
+    pub orientation: Option<extern "C" fn(instance: *mut c_void) -> c_int>,
+    /// Backends (optional): the latest raw and filtered acceleration; false
+    /// if there's none
+    pub vector: Option<extern "C" fn(instance: *mut c_void, raw: *mut SpinnrVector,
+                                     filtered: *mut SpinnrVector) -> bool>,
+    /// Backends (optional): set the low-pass filter multiplier; false if
+    /// the backend isn't filtered
+    pub set_filter: Option<extern "C" fn(instance: *mut c_void, mult: f64) -> bool>,
+    /// Backends (optional): set the sensitivity; false if it can't be
+    pub set_sensitivity: Option<extern "C" fn(instance: *mut c_void, sensitivity: f64) -> bool>,
+    /// Frontends: show a rotation (a `SpinnrRotation`); 0, or an errno
+    pub send: Option<extern "C" fn(instance: *mut c_void, rotation: c_int) -> c_int>,
+    /// Frontends (optional): pass on an announcement; 0, or an errno
+    pub announce: Option<extern "C" fn(instance: *mut c_void, announcement: SpinnrAnnouncement,
+                                       rotation: c_int) -> c_int>,
+}
+
+/// The type of `spinnr_plugins`
+pub type SpinnrPluginsFn = extern "C" fn(count: *mut usize) -> *const SpinnrPlugin;
+
+#[derive(Debug)]
+pub enum PluginError {
+    /// The shared object couldn't be loaded (path, dlerror)
+    Load(PathBuf, String),
+    /// It doesn't export `spinnr_plugins`
+    NoEntryPoint(PathBuf),
+    /// A descriptor is for another ABI version (path, version)
+    Abi(PathBuf, u32),
+    /// A descriptor is missing something (path, what)
+    Invalid(PathBuf, &'static str),
+    /// An instance couldn't be made (plugin, why)
+    Open(String, String),
+}
+
+impl Display for PluginError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            PluginError::Load(ref p, ref e) => write!(f, "couldn't load {}: {}", p.display(), e),
+            PluginError::NoEntryPoint(ref p)    => {
+                write!(f, "{} isn't a spinnr plugin (no spinnr_plugins)", p.display())
+            },
+            PluginError::Abi(ref p, v)  => {
+                write!(f, "{} is for plugin ABI {}, not {}", p.display(), v, SPINNR_PLUGIN_ABI)
+            },
+            PluginError::Invalid(ref p, what)   => write!(f, "{} has a plugin {}", p.display(), what),
+            PluginError::Open(ref name, ref e)  => write!(f, "{} init error: {}", name, e),
+        }
+    }
+}
+
+impl std::error::Error for PluginError {
+    fn description(&self) -> &str {
+        "plugin error"
+    }
+}
+
+/// The last `dlerror`
+fn dlerror() -> String {
+    let e = unsafe { libc::dlerror() };
+    if e.is_null() {
+        "unknown error".to_owned()
+    } else {
+        unsafe { CStr::from_ptr(e) }.to_string_lossy().into_owned()
+    }
+}
+
+/// A loaded shared object, closed once nothing from it is left
+struct Library(*mut c_void);
+
+// dlopen handles can be used and closed from any thread.
+unsafe impl Send for Library {}
+unsafe impl Sync for Library {}
+
+impl Drop for Library {
+    fn drop(&mut self) {
+        unsafe { libc::dlclose(self.0) };
+    }
+}
+
+/// A backend or frontend from a plugin
+#[derive(Clone)]
+pub struct Plugin {
+    /// `None` for descriptors that weren't loaded from a file
+    _lib: Option<Arc<Library>>,
+    desc: &'static SpinnrPlugin,
+    name: String,
+    help: String,
+    path: PathBuf,
+}
+
+// Descriptors are read-only, and plugins must let instances move between
+// threads (see the module docs).
+unsafe impl Send for Plugin {}
+unsafe impl Sync for Plugin {}
+
+impl Plugin {
+    /// Load every backend and frontend the shared object at `path` provides.
+    pub fn load(path: &Path) -> Result<Vec<Plugin>, PluginError> {
+        let cpath = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| PluginError::Load(path.to_owned(), "NUL in path".to_owned()))?;
+        let handle = unsafe { libc::dlopen(cpath.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if handle.is_null() {
+            return Err(PluginError::Load(path.to_owned(), dlerror()));
+        }
+        let lib = Arc::new(Library(handle));
+        let entry = unsafe { libc::dlsym(handle, ENTRY_POINT.as_ptr() as *const c_char) };
+        if entry.is_null() {
+            return Err(PluginError::NoEntryPoint(path.to_owned()));
+        }
+        let entry: SpinnrPluginsFn = unsafe { std::mem::transmute(entry) };
+        let mut count = 0;
+        let descs = entry(&mut count);
+        if descs.is_null() {
+            return Ok(Vec::new());
+        }
+        // The descriptors live as long as the library, which every
+        // `Plugin` keeps open.
+        let descs: &'static [SpinnrPlugin] = unsafe { std::slice::from_raw_parts(descs, count) };
+        descs.iter().map(|d| Plugin::from_descriptor(Some(lib.clone()), d, path)).collect()
+    }
+
+    /// Check a descriptor
+    fn from_descriptor(lib: Option<Arc<Library>>, desc: &'static SpinnrPlugin, path: &Path)
+        -> Result<Plugin, PluginError> {
+        let invalid = |what| PluginError::Invalid(path.to_owned(), what);
+        if desc.abi != SPINNR_PLUGIN_ABI {
+            return Err(PluginError::Abi(path.to_owned(), desc.abi));
+        }
+        if desc.name.is_null() {
+            return Err(invalid("without a name"));
+        }
+        if desc.open.is_none() {
+            return Err(invalid("without an open function"));
+        }
+        match desc.kind {
+            SpinnrPluginKind::Backend if desc.orientation.is_none()  => {
+                return Err(invalid("backend without an orientation function"));
+            },
+            SpinnrPluginKind::Frontend if desc.send.is_none()   => {
+                return Err(invalid("frontend without a send function"));
+            },
+            _   => (),
+        }
+        let text = |s: *const c_char| if s.is_null() {
+            String::new()
+        } else {
+            unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned()
+        };
+        Ok(Plugin {
+            _lib: lib,
+            desc,
+            name: text(desc.name),
+            help: text(desc.help),
+            path: path.to_owned(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn help(&self) -> &str {
+        &self.help
+    }
+
+    pub fn kind(&self) -> SpinnrPluginKind {
+        self.desc.kind
+    }
+
+    /// The shared object it came from
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Make an instance from `options`
+    fn open(&self, options: &HashMap<String, String>) -> Result<Instance, PluginError> {
+        let err = |e: String| PluginError::Open(self.name.clone(), e);
+        let mut strings = Vec::new();
+        for (name, value) in options {
+            strings.push((CString::new(name.as_str()).map_err(|_| err(format!("NUL in {}", name)))?,
+                          CString::new(value.as_str()).map_err(|_| err(format!("NUL in {}", name)))?));
+        }
+        let opts: Vec<SpinnrOption> = strings.iter()
+            .map(|(n, v)| SpinnrOption { name: n.as_ptr(), value: v.as_ptr() })
+            .collect();
+        let mut error: *const c_char = ptr::null();
+        let open = self.desc.open.expect("checked on load");
+        let instance = open(opts.as_ptr(), opts.len(), &mut error);
+        if instance.is_null() {
+            return Err(err(if error.is_null() {
+                "failed".to_owned()
+            } else {
+                unsafe { CStr::from_ptr(error) }.to_string_lossy().into_owned()
+            }));
+        }
+        Ok(Instance { plugin: self.clone(), ptr: instance })
+    }
+
+    /// Make a backend from `options`
+    pub fn open_backend(&self, options: &HashMap<String, String>) -> Result<PluginBackend, PluginError> {
+        if self.kind() != SpinnrPluginKind::Backend {
+            return Err(PluginError::Open(self.name.clone(), "not a backend".to_owned()));
+        }
+        self.open(options).map(PluginBackend)
+    }
+
+    /// Make a frontend from `options`
+    pub fn open_frontend(&self, options: &HashMap<String, String>) -> Result<PluginFrontend, PluginError> {
+        if self.kind() != SpinnrPluginKind::Frontend {
+            return Err(PluginError::Open(self.name.clone(), "not a frontend".to_owned()));
+        }
+        self.open(options).map(PluginFrontend)
+    }
+}
+
+/// Something a plugin made
+struct Instance {
+    plugin: Plugin,
+    ptr: *mut c_void,
+}
+
+// See the module docs.
+unsafe impl Send for Instance {}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        if let Some(close) = self.plugin.desc.close {
+            close(self.ptr);
+        }
+    }
+}
+
+/// A `SpinnrRotation` from a plugin
+fn rotation(r: c_int) -> Option<Rotation> {
+    match r {
+        0   => Some(Rotation::Normal),
+        1   => Some(Rotation::Left),
+        2   => Some(Rotation::Inverted),
+        3   => Some(Rotation::Right),
+        _   => None,
+    }
+}
+
+/// A backend from a plugin
+pub struct PluginBackend(Instance);
+
+impl PluginBackend {
+    fn vectors(&self) -> Option<(AccelerationVector<f64>, AccelerationVector<f64>)> {
+        let vector = self.0.plugin.desc.vector?;
+        let (mut raw, mut filtered) = (SpinnrVector::default(), SpinnrVector::default());
+        if vector(self.0.ptr, &mut raw, &mut filtered) {
+            Some((raw.into(), filtered.into()))
+        } else {
+            None
+        }
+    }
+}
+
+impl Orientator for PluginBackend {
+    fn orientation(&mut self) -> Option<Rotation> {
+        let orientation = self.0.plugin.desc.orientation.expect("checked on load");
+        rotation(orientation(self.0.ptr))
+    }
+
+    fn raw_vector(&self) -> Option<AccelerationVector<f64>> {
+        self.vectors().map(|v| v.0)
+    }
+
+    fn filtered_vector(&self) -> Option<AccelerationVector<f64>> {
+        self.vectors().map(|v| v.1)
+    }
+
+    fn set_filter_mult(&mut self, mult: f64) -> bool {
+        self.0.plugin.desc.set_filter.is_some_and(|f| f(self.0.ptr, mult))
+    }
+
+    fn set_sensitivity(&mut self, sensitivity: f64) -> bool {
+        self.0.plugin.desc.set_sensitivity.is_some_and(|f| f(self.0.ptr, sensitivity))
+    }
+}
+
+impl Display for PluginBackend {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}", self.0.plugin.name)
+    }
+}
+
+/// A frontend from a plugin
+pub struct PluginFrontend(Instance);
+
+/// What a plugin's errno means
+fn sent(rc: c_int) -> SendResult {
+    if 0 == rc { Ok(()) } else { Err(SendError::IoError(IoError::from_raw_os_error(rc))) }
+}
+
+impl Frontend for PluginFrontend {
+    fn send(&mut self, r: Rotation) -> SendResult {
+        let send = self.0.plugin.desc.send.expect("checked on load");
+        sent(send(self.0.ptr, SpinnrRotation::from(Some(r)) as c_int))
+    }
+
+    fn announce(&mut self, a: Announcement) -> SendResult {
+        let announce = match self.0.plugin.desc.announce {
+            Some(f) => f,
+            None    => return Ok(()),
+        };
+        let (kind, r) = match a {
+            Announcement::Pending(r)    => (SpinnrAnnouncement::Pending, r),
+            Announcement::Suggested(r)  => (SpinnrAnnouncement::Suggested, r),
+            Announcement::Cancelled(r)  => (SpinnrAnnouncement::Cancelled, r),
+        };
+        sent(announce(self.0.ptr, kind, SpinnrRotation::from(Some(r)) as c_int))
+    }
+}
+
+impl Display for PluginFrontend {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{} (plugin)", self.0.plugin.name)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static SENT: AtomicUsize = AtomicUsize::new(0);
+    static CLOSED: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn open(options: *const SpinnrOption, count: usize, error: *mut *const c_char) -> *mut c_void {
+        let opts = unsafe { std::slice::from_raw_parts(options, count) };
+        let fail = opts.iter().any(|o| unsafe { CStr::from_ptr(o.name) }.to_bytes() == b"fail");
+        if fail {
+            unsafe { *error = b"told to\0".as_ptr() as *const c_char };
+            ptr::null_mut()
+        } else {
+            Box::into_raw(Box::new(0u32)) as *mut c_void
+        }
+    }
+
+    extern "C" fn close(instance: *mut c_void) {
+        drop(unsafe { Box::from_raw(instance as *mut u32) });
+        CLOSED.fetch_add(1, Ordering::SeqCst);
+    }
+
+    extern "C" fn orientation(_: *mut c_void) -> c_int {
+        3
+    }
+
+    extern "C" fn send(_: *mut c_void, rotation: c_int) -> c_int {
+        SENT.store(rotation as usize, Ordering::SeqCst);
+        if 2 == rotation { libc::EIO } else { 0 }
+    }
+
+    fn descriptor(kind: SpinnrPluginKind) -> SpinnrPlugin {
+        SpinnrPlugin {
+            abi: SPINNR_PLUGIN_ABI,
+            kind,
+            name: b"test\0".as_ptr() as *const c_char,
+            help: ptr::null(),
+            open: Some(open),
+            close: Some(close),
+            orientation: Some(orientation),
+            vector: None,
+            set_filter: None,
+            set_sensitivity: None,
+            send: Some(send),
+            announce: None,
+        }
+    }
+
+    fn plugin(desc: SpinnrPlugin) -> Result<Plugin, PluginError> {
+        Plugin::from_descriptor(None, Box::leak(Box::new(desc)), Path::new("test.so"))
+    }
+
+    #[test]
+    fn backend() {
+        let p = plugin(descriptor(SpinnrPluginKind::Backend)).unwrap();
+        assert_eq!(p.name(), "test");
+        let mut b = p.open_backend(&HashMap::new()).unwrap();
+        assert_eq!(b.orientation(), Some(Rotation::Right));
+        assert!(b.raw_vector().is_none());
+        assert!(!b.set_filter_mult(0.5));
+        assert!(p.open_frontend(&HashMap::new()).is_err());
+        let closed = CLOSED.load(Ordering::SeqCst);
+        drop(b);
+        assert_eq!(CLOSED.load(Ordering::SeqCst), closed + 1);
+    }
+
+    #[test]
+    fn frontend() {
+        let p = plugin(descriptor(SpinnrPluginKind::Frontend)).unwrap();
+        let mut f = p.open_frontend(&HashMap::new()).unwrap();
+        assert!(f.send(Rotation::Left).is_ok());
+        assert_eq!(SENT.load(Ordering::SeqCst), 1);
+        assert!(f.send(Rotation::Inverted).is_err());
+        assert!(f.announce(Announcement::Pending(Rotation::Left)).is_ok());
+        let mut opts = HashMap::new();
+        opts.insert("fail".to_owned(), "true".to_owned());
+        match p.open_frontend(&opts) {
+            Err(PluginError::Open(_, ref e))    => assert_eq!(e, "told to"),
+            _   => panic!("opened anyway"),
+        }
+    }
+
+    #[test]
+    fn checked() {
+        let mut d = descriptor(SpinnrPluginKind::Backend);
+        d.abi = SPINNR_PLUGIN_ABI + 1;
+        assert!(matches!(plugin(d), Err(PluginError::Abi(..))));
+        let mut d = descriptor(SpinnrPluginKind::Frontend);
+        d.send = None;
+        assert!(matches!(plugin(d), Err(PluginError::Invalid(..))));
+    }
+}
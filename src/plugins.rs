@@ -0,0 +1,77 @@
+//! # plugins
+//! Backends and frontends from the plugin directory. Every `*.so` in it is
+//! loaded at startup, and its backends and frontends can then be asked for
+//! by name like the built-in ones (which win if the names clash). See
+//! `spinnr::plugin` for the ABI.
+
+use super::*;
+
+use spinnr::plugin::{Plugin, SpinnrPluginKind};
+
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// The default plugin directory
+pub const DEFAULT_PLUGIN_DIR: &str = "/usr/lib/spinnrd/plugins";
+
+lazy_static! {
+    static ref PLUGINS: Mutex<Vec<Plugin>> = Mutex::new(Vec::new());
+}
+
+/// Whether `path` is safe to load code from: owned by root or us, and not
+/// writable by anyone else
+fn trusted(path: &Path) -> bool {
+    match path.metadata() {
+        Ok(m)   => (0 == m.uid() || unsafe { geteuid() } == m.uid()) && 0 == m.mode() & 0o022,
+        Err(_)  => false,
+    }
+}
+
+/// Load the plugins in `dir`. A missing directory just means no plugins.
+pub fn load_plugins(dir: &Path) {
+    let entries = match dir.read_dir() {
+        Ok(e)   => e,
+        Err(e)  => {
+            debug!("No plugins loaded from {} ({})", dir.to_string_lossy(), e);
+            return;
+        },
+    };
+    if ! trusted(dir) {
+        error!("Not loading plugins from {}: it's writable by other users", dir.to_string_lossy());
+        return;
+    }
+    let mut paths: Vec<PathBuf> = entries.filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|x| "so" == x))
+        .collect();
+    paths.sort();
+    let mut plugins = PLUGINS.lock().unwrap();
+    for path in paths {
+        if ! trusted(&path) {
+            error!("Not loading plugin {}: it's writable by other users", path.to_string_lossy());
+            continue;
+        }
+        match Plugin::load(&path) {
+            Ok(loaded)  => for p in loaded {
+                info!("Loaded {} {} from {}", p.name(), kind_name(p.kind()), path.to_string_lossy());
+                plugins.push(p);
+            },
+            Err(e)  => error!("{}", e),
+        }
+    }
+}
+
+fn kind_name(kind: SpinnrPluginKind) -> &'static str {
+    match kind {
+        SpinnrPluginKind::Backend   => "backend",
+        SpinnrPluginKind::Frontend  => "frontend",
+    }
+}
+
+/// The loaded backend or frontend called `name`
+pub fn find_plugin(name: &str, kind: SpinnrPluginKind) -> Option<Plugin> {
+    PLUGINS.lock().unwrap().iter()
+        .find(|p| p.kind() == kind && p.name() == name)
+        .cloned()
+}
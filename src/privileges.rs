@@ -0,0 +1,281 @@
+//! # privileges
+//! Running as someone else. spinnrd starts as whoever launched it (usually
+//! root, to get at the sensors and input devices), opens what it needs,
+//! then switches to `--user`/`--group` with no supplementary groups and
+//! only the capabilities in `--keep-caps`.
+
+use super::*;
+
+use std::ffi::{CStr, CString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// `_LINUX_CAPABILITY_VERSION_3`, for 64-bit capability sets
+const CAP_VERSION: u32 = 0x2008_0522;
+
+/// Capability names, by number
+const CAPS: &[&str] = &[
+    "chown", "dac_override", "dac_read_search", "fowner", "fsetid", "kill",
+    "setgid", "setuid", "setpcap", "linux_immutable", "net_bind_service",
+    "net_broadcast", "net_admin", "net_raw", "ipc_lock", "ipc_owner",
+    "sys_module", "sys_rawio", "sys_chroot", "sys_ptrace", "sys_pacct",
+    "sys_admin", "sys_boot", "sys_nice", "sys_resource", "sys_time",
+    "sys_tty_config", "mknod", "lease", "audit_write", "audit_control",
+    "setfcap", "mac_override", "mac_admin", "syslog", "wake_alarm",
+    "block_suspend", "audit_read", "perfmon", "bpf", "checkpoint_restore",
+];
+
+#[derive(Debug)]
+pub enum PrivilegeError {
+    /// There's no such user
+    NoUser(String),
+    /// There's no such group
+    NoGroup(String),
+    /// There's no such capability
+    BadCap(String),
+    /// A call failed (what, why)
+    Call(&'static str, IoError),
+    /// Root could be got back after dropping it
+    Regained,
+}
+
+impl Display for PrivilegeError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            PrivilegeError::NoUser(ref u)   => write!(f, "there's no user '{}'", u),
+            PrivilegeError::NoGroup(ref g)  => write!(f, "there's no group '{}'", g),
+            PrivilegeError::BadCap(ref c)   => write!(f, "there's no capability '{}'", c),
+            PrivilegeError::Call(what, ref e)   => write!(f, "couldn't {} ({})", what, e),
+            PrivilegeError::Regained    => write!(f, "root privileges could be regained after dropping them"),
+        }
+    }
+}
+
+impl std::error::Error for PrivilegeError {
+    fn description(&self) -> &str {
+        "couldn't drop privileges"
+    }
+}
+
+/// A user and group
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub uid: libc::uid_t,
+    pub user: String,
+    pub gid: libc::gid_t,
+    pub group: String,
+}
+
+impl Identity {
+    /// The effective user and group
+    fn effective() -> Identity {
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        Identity { uid, user: user_name(uid), gid, group: group_name(gid) }
+    }
+
+    /// The real user and group
+    fn real() -> Identity {
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        Identity { uid, user: user_name(uid), gid, group: group_name(gid) }
+    }
+
+    /// Who the settings say to run as. The group defaults to the user's.
+    fn configured() -> Result<Identity, PrivilegeError> {
+        let mut id = Identity::effective();
+        if let Some(u) = config_value("user") {
+            let pw = passwd(&u).ok_or(PrivilegeError::NoUser(u))?;
+            id = Identity { uid: pw.0, user: pw.1, gid: pw.2, group: group_name(pw.2) };
+        }
+        if let Some(g) = config_value("group") {
+            let gr = group(&g).ok_or(PrivilegeError::NoGroup(g))?;
+            id.gid = gr.0;
+            id.group = gr.1;
+        }
+        Ok(id)
+    }
+}
+
+lazy_static! {
+    static ref RUNNING: Identity = Identity::configured().unwrap_or_else(|_| Identity::effective());
+    static ref CALLING: Identity = Identity::real();
+}
+
+/// Who spinnrd runs as (once it's dropped privileges)
+pub fn running() -> &'static Identity {
+    &RUNNING
+}
+
+/// Who started spinnrd
+pub fn calling() -> &'static Identity {
+    &CALLING
+}
+
+/// Switch to the configured user and group, keeping only the configured
+/// capabilities. `hand_over` are files and directories spinnrd made for
+/// itself, which the new user will need to write.
+pub fn drop_privileges(hand_over: &[&Path]) -> Result<(), PrivilegeError> {
+    // Who we were has to be noted before it changes.
+    let _ = calling();
+    let keep = match config_values("keepcaps") {
+        Some(names) => Some(parse_caps(&names)?),
+        None    => None,
+    };
+    let id = Identity::configured()?;
+    let me = Identity::effective();
+    let switch = id.uid != me.uid || id.gid != me.gid;
+    if switch {
+        for path in hand_over.iter().filter(|p| p.exists()) {
+            let c = CString::new(path.as_os_str().as_bytes()).unwrap();
+            if 0 != unsafe { libc::chown(c.as_ptr(), id.uid, id.gid) } {
+                warn!("Couldn't give {} to {} ({})", path.to_string_lossy(), id.user,
+                      IoError::last_os_error());
+            }
+        }
+        if keep.is_some() {
+            check("keep capabilities", unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) })?;
+        }
+        if 0 == me.uid {
+            check("clear supplementary groups", unsafe { libc::setgroups(0, std::ptr::null()) })?;
+        }
+        check("change group", unsafe { libc::setgid(id.gid) })?;
+        check("change user", unsafe { libc::setuid(id.uid) })?;
+        info!("Running as {}:{}", id.user, id.group);
+    }
+    if let Some(caps) = keep {
+        set_caps(caps)?;
+        check("stop keeping capabilities", unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 0, 0, 0, 0) })?;
+        info!("Keeping capabilities: {}", cap_names(caps));
+    }
+    let can_setuid = keep.is_some_and(|caps| 0 != caps & 1 << cap("setuid"));
+    if switch && 0 != id.uid && ! can_setuid && 0 == unsafe { libc::setuid(0) } {
+        return Err(PrivilegeError::Regained);
+    }
+    Ok(())
+}
+
+/// Turn a libc return value into a `Result`
+fn check(what: &'static str, ret: libc::c_int) -> Result<(), PrivilegeError> {
+    if 0 == ret {
+        Ok(())
+    } else {
+        Err(PrivilegeError::Call(what, IoError::last_os_error()))
+    }
+}
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Make `caps` the only capabilities we have (or can get back).
+fn set_caps(caps: u64) -> Result<(), PrivilegeError> {
+    let header = CapHeader { version: CAP_VERSION, pid: 0 };
+    let data = [
+        CapData { effective: caps as u32, permitted: caps as u32, inheritable: 0 },
+        CapData { effective: (caps >> 32) as u32, permitted: (caps >> 32) as u32, inheritable: 0 },
+    ];
+    let ret = unsafe { libc::syscall(libc::SYS_capset, &header, data.as_ptr()) };
+    check("set capabilities", ret as libc::c_int)
+}
+
+/// Capability names (`cap_` is optional) as a set
+fn parse_caps(names: &[String]) -> Result<u64, PrivilegeError> {
+    names.iter().flat_map(|n| n.split(','))
+        .map(|n| n.trim().to_lowercase())
+        .filter(|n| ! n.is_empty())
+        .try_fold(0u64, |set, n| {
+            CAPS.iter().position(|&c| c == n.trim_start_matches("cap_"))
+                .map(|i| set | 1 << i)
+                .ok_or(PrivilegeError::BadCap(n))
+        })
+}
+
+/// The number of the capability called `name`
+fn cap(name: &str) -> usize {
+    CAPS.iter().position(|&c| c == name).unwrap()
+}
+
+/// The names in a capability set
+fn cap_names(caps: u64) -> String {
+    let names: Vec<&str> = CAPS.iter().enumerate()
+        .filter(|&(i, _)| 0 != caps & 1 << i)
+        .map(|(_, c)| *c)
+        .collect();
+    if names.is_empty() { "none".to_owned() } else { names.join(", ") }
+}
+
+/// The uid, name and primary gid of `user` (a name or a uid)
+fn passwd(user: &str) -> Option<(libc::uid_t, String, libc::gid_t)> {
+    let mut pw: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let c = CString::new(user).ok()?;
+    let ret = unsafe { match user.parse::<libc::uid_t>() {
+        Ok(uid) => libc::getpwuid_r(uid, &mut pw, buf.as_mut_ptr(), buf.len(), &mut found),
+        Err(_)  => libc::getpwnam_r(c.as_ptr(), &mut pw, buf.as_mut_ptr(), buf.len(), &mut found),
+    } };
+    if 0 != ret || found.is_null() {
+        // A uid with no name is still a uid.
+        return user.parse::<libc::uid_t>().ok().map(|uid| (uid, user.to_owned(), uid));
+    }
+    let name = unsafe { CStr::from_ptr(pw.pw_name) }.to_string_lossy().into_owned();
+    Some((pw.pw_uid, name, pw.pw_gid))
+}
+
+/// The gid and name of `group` (a name or a gid)
+fn group(group: &str) -> Option<(libc::gid_t, String)> {
+    let mut gr: libc::group = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let c = CString::new(group).ok()?;
+    let ret = unsafe { match group.parse::<libc::gid_t>() {
+        Ok(gid) => libc::getgrgid_r(gid, &mut gr, buf.as_mut_ptr(), buf.len(), &mut found),
+        Err(_)  => libc::getgrnam_r(c.as_ptr(), &mut gr, buf.as_mut_ptr(), buf.len(), &mut found),
+    } };
+    if 0 != ret || found.is_null() {
+        return group.parse::<libc::gid_t>().ok().map(|gid| (gid, group.to_owned()));
+    }
+    let name = unsafe { CStr::from_ptr(gr.gr_name) }.to_string_lossy().into_owned();
+    Some((gr.gr_gid, name))
+}
+
+fn user_name(uid: libc::uid_t) -> String {
+    passwd(&uid.to_string()).map_or_else(|| uid.to_string(), |pw| pw.1)
+}
+
+fn group_name(gid: libc::gid_t) -> String {
+    group(&gid.to_string()).map_or_else(|| gid.to_string(), |gr| gr.1)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caps() {
+        let names = vec!["CAP_DAC_OVERRIDE,sys_nice".to_owned(), "checkpoint_restore".to_owned()];
+        let caps = parse_caps(&names).unwrap();
+        assert_eq!(caps, 1 << 1 | 1 << 23 | 1 << 40);
+        assert_eq!(cap_names(caps), "dac_override, sys_nice, checkpoint_restore");
+        assert!(matches!(parse_caps(&["cap_fly".to_owned()]), Err(PrivilegeError::BadCap(_))));
+    }
+
+    #[test]
+    fn users_and_groups() {
+        assert_eq!(passwd("root"), Some((0, "root".to_owned(), 0)));
+        assert_eq!(passwd("0"), Some((0, "root".to_owned(), 0)));
+        assert_eq!(passwd("4000000"), Some((4000000, "4000000".to_owned(), 4000000)));
+        assert_eq!(passwd("no-such-user-here"), None);
+        assert_eq!(group("0").map(|g| g.0), Some(0));
+        assert_eq!(group("no-such-group-here"), None);
+    }
+}
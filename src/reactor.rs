@@ -0,0 +1,275 @@
+//! # reactor
+//! What the main loop waits on. Everything that can wake it up (the
+//! sampling timer, signals, the control socket, lock keys) is a file
+//! descriptor registered with an epoll instance under a token, so new
+//! event sources plug in without a thread of their own.
+//!
+//! Buffered IIO sensors aren't registered yet: the backend is still read
+//! when the sampling timer goes off.
+//!
+//! Frontends aren't in the main loop's reactor: they send from worker
+//! threads, so a frontend with connections of its own (the socket frontend)
+//! waits on a reactor of its own on its thread, with a `Waker` to stop it.
+
+use std::io::Error as IoError;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+use libc::c_int;
+
+/// Says which source an event came from
+pub type Token = u64;
+
+/// How many events to pick up per wait
+const MAX_EVENTS: usize = 32;
+
+/// Turn a libc return value into a `Result`
+fn check(rc: c_int) -> Result<c_int, IoError> {
+    if 0 > rc { Err(IoError::last_os_error()) } else { Ok(rc) }
+}
+
+/// An epoll instance
+pub struct Reactor {
+    epfd: RawFd,
+}
+
+impl Reactor {
+    pub fn new() -> Result<Reactor, IoError> {
+        let epfd = check(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+        Ok(Reactor { epfd })
+    }
+
+    /// Wake up with `token` whenever `fd` is readable (or hung up). Closing
+    /// `fd` unregisters it.
+    pub fn add(&self, fd: RawFd, token: Token) -> Result<(), IoError> {
+        let mut ev = libc::epoll_event {
+            events: (libc::EPOLLIN | libc::EPOLLRDHUP) as u32,
+            u64: token,
+        };
+        check(unsafe { libc::epoll_ctl(self.epfd, libc::EPOLL_CTL_ADD, fd, &mut ev) })
+            .map(|_| ())
+    }
+
+    /// Wait until something's ready (or `timeout` passes), and return the
+    /// tokens of whatever is. A signal interrupting the wait just returns
+    /// nothing.
+    pub fn wait(&self, timeout: Option<Duration>) -> Result<Vec<Token>, IoError> {
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        let ms = timeout.map_or(-1, |t| t.as_millis().min(c_int::MAX as u128) as c_int);
+        let n = match check(unsafe {
+            libc::epoll_wait(self.epfd, events.as_mut_ptr(), MAX_EVENTS as c_int, ms)
+        }) {
+            Ok(n)   => n as usize,
+            Err(ref e) if e.raw_os_error() == Some(libc::EINTR)  => 0,
+            Err(e)  => return Err(e),
+        };
+        Ok(events[..n].iter().map(|e| e.u64).collect())
+    }
+}
+
+impl Drop for Reactor {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.epfd) };
+    }
+}
+
+/// A periodic timer (a `timerfd`)
+pub struct Timer {
+    fd: RawFd,
+}
+
+impl Timer {
+    /// Start a timer that goes off every `period`.
+    pub fn new(period: Duration) -> Result<Timer, IoError> {
+        let fd = check(unsafe {
+            libc::timerfd_create(libc::CLOCK_MONOTONIC, libc::TFD_NONBLOCK | libc::TFD_CLOEXEC)
+        })?;
+        let timer = Timer { fd };
+        timer.set_period(period)?;
+        Ok(timer)
+    }
+
+    /// Go off every `period` from now on.
+    pub fn set_period(&self, period: Duration) -> Result<(), IoError> {
+        // A zero interval would disarm the timer.
+        let period = period.max(Duration::from_millis(1));
+        let ts = libc::timespec {
+            tv_sec: period.as_secs() as libc::time_t,
+            tv_nsec: period.subsec_nanos() as libc::c_long,
+        };
+        let spec = libc::itimerspec { it_interval: ts, it_value: ts };
+        check(unsafe { libc::timerfd_settime(self.fd, 0, &spec, std::ptr::null_mut()) })
+            .map(|_| ())
+    }
+
+    /// How many times the timer's gone off since this was last called
+    pub fn expirations(&self) -> u64 {
+        let mut count = 0u64;
+        let n = unsafe {
+            libc::read(self.fd, &mut count as *mut u64 as *mut libc::c_void, 8)
+        };
+        if 8 == n { count } else { 0 }
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Wakes up a reactor from another thread (an `eventfd`)
+pub struct Waker {
+    fd: RawFd,
+}
+
+impl Waker {
+    pub fn new() -> Result<Waker, IoError> {
+        let fd = check(unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) })?;
+        Ok(Waker { fd })
+    }
+
+    /// Make the waker's fd readable (for good; it's for shutting down).
+    pub fn wake(&self) {
+        let one = 1u64;
+        unsafe { libc::write(self.fd, &one as *const u64 as *const libc::c_void, 8) };
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for Waker {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Signals, delivered through a file instead of a handler (a `signalfd`)
+pub struct Signals {
+    fd: RawFd,
+}
+
+impl Signals {
+    /// Block `signals` in this thread and pick them up through a file
+    /// instead. Threads started afterwards inherit the block.
+    pub fn new(signals: &[c_int]) -> Result<Signals, IoError> {
+        let fd = unsafe {
+            let mut set: libc::sigset_t = std::mem::zeroed();
+            libc::sigemptyset(&mut set);
+            for &s in signals {
+                libc::sigaddset(&mut set, s);
+            }
+            libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+            check(libc::signalfd(-1, &set, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC))?
+        };
+        Ok(Signals { fd })
+    }
+
+    /// The next signal that came in, if any
+    pub fn next(&self) -> Option<c_int> {
+        let mut info: libc::signalfd_siginfo = unsafe { std::mem::zeroed() };
+        let size = std::mem::size_of::<libc::signalfd_siginfo>();
+        let n = unsafe {
+            libc::read(self.fd, &mut info as *mut _ as *mut libc::c_void, size)
+        };
+        if n as usize == size { Some(info.ssi_signo as c_int) } else { None }
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for Signals {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// A signal's name, for the log
+pub fn signal_name(signal: c_int) -> String {
+    match signal {
+        libc::SIGHUP    => "SIGHUP".to_owned(),
+        libc::SIGINT    => "SIGINT".to_owned(),
+        libc::SIGTERM   => "SIGTERM".to_owned(),
+        libc::SIGUSR1   => "SIGUSR1".to_owned(),
+        libc::SIGUSR2   => "SIGUSR2".to_owned(),
+        s   => format!("signal {}", s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn times_out() {
+        let reactor = Reactor::new().unwrap();
+        let waker = Waker::new().unwrap();
+        reactor.add(waker.fd(), 1).unwrap();
+        let start = Instant::now();
+        assert_eq!(reactor.wait(Some(Duration::from_millis(20))).unwrap(), vec![]);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn dispatches() {
+        let reactor = Reactor::new().unwrap();
+        let timer = Timer::new(Duration::from_millis(10)).unwrap();
+        let waker = Waker::new().unwrap();
+        reactor.add(timer.fd(), 1).unwrap();
+        reactor.add(waker.fd(), 2).unwrap();
+
+        assert_eq!(reactor.wait(Some(Duration::from_secs(5))).unwrap(), vec![1]);
+        assert!(timer.expirations() >= 1);
+
+        // Stretch the timer out of the way so only the waker is ready
+        timer.set_period(Duration::from_secs(60)).unwrap();
+        waker.wake();
+        assert_eq!(reactor.wait(Some(Duration::from_secs(5))).unwrap(), vec![2]);
+        // It stays woken, so everything waiting on it sees the stop
+        assert_eq!(reactor.wait(Some(Duration::ZERO)).unwrap(), vec![2]);
+
+        timer.set_period(Duration::from_millis(10)).unwrap();
+        thread::sleep(Duration::from_millis(30));
+        let mut tokens = reactor.wait(Some(Duration::ZERO)).unwrap();
+        tokens.sort();
+        assert_eq!(tokens, vec![1, 2]);
+    }
+
+    #[test]
+    fn stops_from_another_thread() {
+        let waker = Arc::new(Waker::new().unwrap());
+        let stop = waker.clone();
+        let waiter = thread::spawn(move || {
+            let reactor = Reactor::new().unwrap();
+            let timer = Timer::new(Duration::from_millis(5)).unwrap();
+            reactor.add(timer.fd(), 1).unwrap();
+            reactor.add(stop.fd(), 2).unwrap();
+            let mut ticks = 0;
+            loop {
+                let tokens = reactor.wait(None).unwrap();
+                if tokens.contains(&2) { return ticks }
+                if tokens.contains(&1) { ticks += timer.expirations() }
+            }
+        });
+        thread::sleep(Duration::from_millis(50));
+        waker.wake();
+        assert!(waiter.join().unwrap() >= 1);
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(signal_name(libc::SIGHUP), "SIGHUP");
+        assert_eq!(signal_name(libc::SIGTERM), "SIGTERM");
+    }
+}
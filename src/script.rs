@@ -0,0 +1,244 @@
+//! # script
+//! Rotation policy scripts, for site rules that don't belong in spinnrd
+//! ("never rotate while the keyboard's attached", "portrait only during
+//! opening hours", "never inverted on the wall-mounted units").
+//!
+//! A script (`--policy-script`, in Rhai) defines
+//! `policy(vector, candidate, locked, posture)`, which is called with every
+//! sample:
+//!
+//! - `vector`: the filtered acceleration, `#{x, y, z}`, or `()`
+//! - `candidate`: `"normal"`, `"left"`, `"inverted"`, `"right"` or `()`
+//! - `locked`: whether the rotation lock is on
+//! - `posture`: `"laptop"`, `"tablet"` or `"unknown"`
+//!
+//! and returns the rotation to settle on, or `()` to keep the display where
+//! it is. The delays still apply to whatever it returns. `hour()`,
+//! `minute()` and `weekday()` (0 is Sunday) give the local time, and
+//! `print` goes to the log. A script that fails is ignored for that sample.
+//!
+//! Scripting needs the `scripting` cargo feature.
+
+use super::*;
+
+#[cfg(feature = "scripting")]
+use chrono::{Datelike, Timelike};
+#[cfg(feature = "scripting")]
+use rhai::{Engine, AST, Scope, Dynamic, Map, CallFnOptions};
+
+/// The function a script has to define, and how many arguments it takes
+#[cfg(feature = "scripting")]
+const POLICY_FN: (&str, usize) = ("policy", 4);
+
+/// How much work a script may do per sample, so a runaway one can't
+/// stall the main loop
+#[cfg(feature = "scripting")]
+const MAX_OPERATIONS: u64 = 100_000;
+
+#[derive(Debug)]
+pub enum ScriptError {
+    /// The script couldn't be read or compiled (path, why)
+    #[allow(dead_code)] // not always compiled
+    Load(PathBuf, String),
+    /// The script doesn't define `policy` (path)
+    #[allow(dead_code)]
+    NoPolicy(PathBuf),
+    /// Scripting isn't compiled in
+    #[allow(dead_code)]
+    NotCompiled,
+}
+
+impl Display for ScriptError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            ScriptError::Load(ref p, ref e) => {
+                write!(f, "couldn't load policy script {}: {}", p.to_string_lossy(), e)
+            },
+            ScriptError::NoPolicy(ref p)    => {
+                write!(f, "policy script {} doesn't define policy(vector, candidate, locked, posture)",
+                       p.to_string_lossy())
+            },
+            ScriptError::NotCompiled    => {
+                write!(f, "policy scripts aren't compiled in (the 'scripting' feature)")
+            },
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {
+    fn description(&self) -> &str {
+        "couldn't load policy script"
+    }
+}
+
+/// A policy script
+#[cfg(feature = "scripting")]
+pub struct Script {
+    path: PathBuf,
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    /// The last error, so a script that keeps failing doesn't flood the log
+    last_error: Option<String>,
+}
+
+#[cfg(feature = "scripting")]
+impl Script {
+    pub fn load(path: PathBuf) -> Result<Script, ScriptError> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.on_print(|s| info!("policy script: {}", s));
+        engine.on_debug(|s, _, pos| debug!("policy script ({}): {}", pos, s));
+        engine.register_fn("hour", || i64::from(Local::now().hour()));
+        engine.register_fn("minute", || i64::from(Local::now().minute()));
+        engine.register_fn("weekday", || i64::from(Local::now().weekday().num_days_from_sunday()));
+        let ast = engine.compile_file(path.clone())
+            .map_err(|e| ScriptError::Load(path.clone(), e.to_string()))?;
+        if ! ast.iter_functions().any(|f| f.name == POLICY_FN.0 && f.params.len() == POLICY_FN.1) {
+            return Err(ScriptError::NoPolicy(path));
+        }
+        // Top-level statements run once, so they can set things up.
+        let mut scope = Scope::new();
+        engine.run_ast_with_scope(&mut scope, &ast)
+            .map_err(|e| ScriptError::Load(path.clone(), e.to_string()))?;
+        Ok(Script { path, engine, ast, scope, last_error: None })
+    }
+
+    /// Log `e`, unless it's the same as last time.
+    fn failed(&mut self, e: String) {
+        if self.last_error.as_ref() != Some(&e) {
+            warn!("Policy script {} failed ({}); ignoring it until it works",
+                  self.path.to_string_lossy(), e);
+            self.last_error = Some(e);
+        }
+    }
+}
+
+#[cfg(feature = "scripting")]
+impl Policy for Script {
+    fn decide(&mut self, sample: &Sample) -> Option<Rotation> {
+        let vector = match sample.vector {
+            Some(v) => {
+                let mut m = Map::new();
+                m.insert("x".into(), Dynamic::from(v.x));
+                m.insert("y".into(), Dynamic::from(v.y));
+                m.insert("z".into(), Dynamic::from(v.z));
+                Dynamic::from(m)
+            },
+            None    => Dynamic::UNIT,
+        };
+        let candidate = sample.candidate.map_or(Dynamic::UNIT, |r| Dynamic::from(r.to_string()));
+        let args = (vector, candidate, sample.locked, sample.posture.to_string());
+        let options = CallFnOptions::new().eval_ast(false);
+        let result = self.engine.call_fn_with_options::<Dynamic>(
+            options, &mut self.scope, &self.ast, POLICY_FN.0, args);
+        let decided = match result {
+            Ok(ref d) if d.is_unit()    => Ok(None),
+            Ok(d)   => d.clone().into_string().ok()
+                .and_then(|s| s.parse::<Rotation>().ok())
+                .map(Some)
+                .ok_or_else(|| format!("it returned {} instead of a rotation or ()", d)),
+            Err(e)  => Err(e.to_string()),
+        };
+        match decided {
+            Ok(r)   => {
+                if self.last_error.take().is_some() {
+                    info!("Policy script {} is working again", self.path.to_string_lossy());
+                }
+                if r != sample.candidate {
+                    trace!("Policy script turned {} into {}", opt(sample.candidate), opt(r));
+                }
+                r
+            },
+            Err(e)  => {
+                self.failed(e);
+                sample.candidate
+            },
+        }
+    }
+}
+
+/// Load the policy script at `path`
+#[cfg(feature = "scripting")]
+fn load(path: PathBuf) -> Result<Box<dyn Policy>, ScriptError> {
+    Script::load(path).map(|s| Box::new(s) as Box<dyn Policy>)
+}
+
+#[cfg(not(feature = "scripting"))]
+fn load(_: PathBuf) -> Result<Box<dyn Policy>, ScriptError> {
+    Err(ScriptError::NotCompiled)
+}
+
+/// Load the policy script, if one's configured.
+pub fn init_policy() -> Option<Box<dyn Policy>> {
+    let path = PathBuf::from(parse_path(&config_value("policyscript")?, false));
+    match load(path.clone()) {
+        Ok(p)   => {
+            info!("Using policy script {}", path.to_string_lossy());
+            Some(p)
+        },
+        Err(e)  => {
+            error!("{}", e);
+            None
+        },
+    }
+}
+
+
+#[cfg(all(test, feature = "scripting"))]
+mod tests {
+    use super::*;
+    use std::fs::write;
+
+    fn script(name: &str, source: &str) -> Result<Script, ScriptError> {
+        let path = std::env::temp_dir().join(format!("spinnrd-test-{}-{}.rhai", name, std::process::id()));
+        write(&path, source).unwrap();
+        let s = Script::load(path.clone());
+        let _ = remove_file(&path);
+        s
+    }
+
+    fn sample(candidate: Option<Rotation>, posture: Posture) -> Sample {
+        Sample {
+            vector: Some(AccelerationVector { x: 0.0, y: -9.8, z: 0.0 }),
+            candidate,
+            locked: false,
+            posture,
+        }
+    }
+
+    #[test]
+    fn decides() {
+        let mut s = script("decides", r#"
+            fn policy(vector, candidate, locked, posture) {
+                if posture == "laptop" { return (); }
+                if candidate == "inverted" { return "normal"; }
+                if vector.y > 0.0 { return "left"; }
+                candidate
+            }
+        "#).unwrap();
+        assert_eq!(s.decide(&sample(Some(Rotation::Right), Posture::Tablet)), Some(Rotation::Right));
+        assert_eq!(s.decide(&sample(Some(Rotation::Inverted), Posture::Unknown)), Some(Rotation::Normal));
+        assert_eq!(s.decide(&sample(Some(Rotation::Right), Posture::Laptop)), None);
+        assert_eq!(s.decide(&sample(None, Posture::Tablet)), None);
+    }
+
+    #[test]
+    fn failures_pass_the_candidate_through() {
+        let mut s = script("fails", r#"
+            fn policy(vector, candidate, locked, posture) {
+                if candidate == "left" { return 42; }
+                loop {}
+            }
+        "#).unwrap();
+        assert_eq!(s.decide(&sample(Some(Rotation::Left), Posture::Tablet)), Some(Rotation::Left));
+        assert_eq!(s.decide(&sample(Some(Rotation::Right), Posture::Tablet)), Some(Rotation::Right));
+        assert!(s.last_error.is_some());
+    }
+
+    #[test]
+    fn needs_a_policy() {
+        assert!(matches!(script("nopolicy", "fn rotate(x) { x }"), Err(ScriptError::NoPolicy(_))));
+        assert!(matches!(script("broken", "fn policy("), Err(ScriptError::Load(..))));
+    }
+}
@@ -0,0 +1,100 @@
+//! # send
+//! What frontends implement: the `Frontend` trait, and what can go wrong
+//! sending a rotation.
+
+use super::Rotation;
+
+use std::fmt::{Display, Formatter};
+use std::fmt::Result as FmtResult;
+use std::io::Error as IoError;
+use std::time::Duration;
+
+pub type SendResult = Result<(), SendError>;
+
+#[derive(Debug)]
+pub enum SendError {
+    IoError(IoError),
+    /// One or more members of a `Frontends` set failed (member, error)
+    Members(Vec<(String, SendError)>),
+    /// A frontend whose policy is to quit failed
+    Fatal(Box<SendError>),
+    /// A send took longer than the frontend's timeout
+    Timeout(Duration),
+    /// The frontend's worker thread exited
+    WorkerDied,
+}
+
+impl Display for SendError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            &SendError::IoError(ref e)  => {
+                write!(fmt, "io error sending rotation: {}", e)
+            },
+            &SendError::Members(ref errs)   => {
+                for (i, &(ref member, ref e)) in errs.iter().enumerate() {
+                    if 0 < i { write!(fmt, "; ")?; }
+                    write!(fmt, "{}: {}", member, e)?;
+                }
+                Ok(())
+            },
+            &SendError::Fatal(ref e)    => write!(fmt, "{}", e),
+            &SendError::Timeout(d)  => {
+                write!(fmt, "send timed out after {}ms",
+                       d.as_secs() * 1000 + u64::from(d.subsec_millis()))
+            },
+            &SendError::WorkerDied  => write!(fmt, "frontend worker died"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {
+    fn description(&self) -> &str {
+        "error sending rotation"
+    }
+
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            &SendError::IoError(ref e)  => Some(e),
+            &SendError::Members(ref errs)   => {
+                errs.first().map(|&(_, ref e)| e as &(dyn std::error::Error + 'static))
+            },
+            &SendError::Fatal(ref e)    => Some(&**e),
+            &SendError::Timeout(_)  => None,
+            &SendError::WorkerDied  => None,
+        }
+    }
+}
+
+
+/// Something frontends can be told about besides a committed rotation.
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub enum Announcement {
+    /// This rotation will be committed unless it's vetoed
+    Pending(Rotation),
+    /// This rotation would be committed if rotation weren't locked or
+    /// suggest-only; it can be accepted through the control socket
+    Suggested(Rotation),
+    /// The pending or suggested rotation was vetoed or abandoned
+    Cancelled(Rotation),
+}
+
+impl Display for Announcement {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            Announcement::Pending(r)    => write!(f, "pending {}", r),
+            Announcement::Suggested(r)  => write!(f, "suggest {}", r),
+            Announcement::Cancelled(r)  => write!(f, "cancel {}", r),
+        }
+    }
+}
+
+pub trait Frontend {
+    fn send(&mut self, Rotation) -> SendResult;
+
+    /// Pass on an announcement. Frontends that don't care about them
+    /// needn't do anything.
+    fn announce(&mut self, _: Announcement) -> SendResult {
+        Ok(())
+    }
+}
+
@@ -0,0 +1,86 @@
+//! # settings
+//! The configured settings, gathered in one place so they can be read
+//! again on `SIGHUP` and compared with what's running.
+
+use super::*;
+
+/// The backends to try, in order, and every backend's options
+pub type BackendOptions = (Vec<String>, HashMap<String, HashMap<String, String>>);
+
+/// Everything the main loop is configured with
+#[derive(Debug, PartialEq, Clone)]
+pub struct Settings {
+    /// Polling interval (in ms)
+    pub period: u32,
+    /// How long a quarter turn has to hold (in ms)
+    pub delay: u32,
+    /// How long a half turn has to hold (in ms); defaults to `delay`
+    pub flip_delay: Option<u32>,
+    /// How long to average acceleration over (in ms)
+    pub hysteresis: u32,
+    pub sensitivity: f64,
+    /// In degrees per second
+    pub motion_threshold: f64,
+    /// In ms
+    pub veto_window: u32,
+    pub allowed: Vec<Rotation>,
+    pub offset: Rotation,
+    pub suggest_only: bool,
+    pub backends: BackendOptions,
+    pub frontends: Vec<Vec<FrontendSpec>>,
+}
+
+impl Settings {
+    /// Read the settings from the configuration.
+    pub fn load() -> Settings {
+        Settings {
+            period: get_u32_arg_val("period").unwrap_or(DEFAULT_PERIOD),
+            delay: get_u32_arg_val("delay").unwrap_or(DEFAULT_DELAY),
+            flip_delay: get_u32_arg_val("flipdelay"),
+            hysteresis: get_u32_arg_val("hysteresis").unwrap_or(DEFAULT_HYSTERESIS),
+            sensitivity: get_f64_arg_val("sensitivity").unwrap_or(DEFAULT_SENSITIVITY),
+            motion_threshold: get_f64_arg_val("motionthreshold").unwrap_or(0.0),
+            veto_window: get_u32_arg_val("vetowindow").unwrap_or(0),
+            allowed: config_value("allowed")
+                .and_then(|a| parse_set(&a).ok())
+                .unwrap_or_else(|| Rotation::ALL.to_vec()),
+            offset: config_value("offset")
+                .and_then(|o| o.parse::<Rotation>().ok())
+                .unwrap_or_default(),
+            suggest_only: config_flag("suggestonly"),
+            backends: get_backend_options(),
+            frontends: get_frontend_options(),
+        }
+    }
+
+    pub fn period(&self) -> Duration {
+        // period is in ms, so multiply by 10^6 to get ns
+        Duration::new(
+            (self.period / PERIOD_SEC_DIV) as u64,
+            (self.period % PERIOD_SEC_DIV) * PERIOD_NS_MULT)
+    }
+
+    pub fn delay(&self) -> Duration {
+        Duration::new(
+            (self.delay / DELAY_SEC_DIV) as u64,
+            (self.delay % DELAY_SEC_DIV) * DELAY_NS_MULT)
+    }
+
+    pub fn flip_delay(&self) -> Duration {
+        Duration::from_millis(u64::from(self.flip_delay.unwrap_or(self.delay)))
+    }
+
+    pub fn veto_window(&self) -> Duration {
+        Duration::from_millis(u64::from(self.veto_window))
+    }
+
+    /// The low-pass filter multiplier
+    ///
+    /// a_now = m * (measurement - a_last)
+    /// where m is the amount of time we're low-pass filtering over
+    /// times the frequency with which we're polling
+    /// (AKA the time we're filtering over divided by the period)
+    pub fn filter_mult(&self) -> f64 {
+        f64::from(self.period) / f64::from(self.hysteresis)
+    }
+}
@@ -0,0 +1,529 @@
+//! # spinner
+//! Decides what gets written to the frontends and what gets announced: the
+//! part of the main loop that isn't waiting on anything. `runloop` hands it
+//! a sample every period and passes on control requests; the time comes
+//! from a `Clock`, so it can be run against a virtual one in tests.
+
+use super::*;
+
+/// What a `Policy` is shown each sample
+#[derive(Debug,Clone,Copy)]
+#[allow(dead_code)] // policy scripts aren't always compiled
+pub struct Sample {
+    /// The filtered acceleration, if the backend has one
+    pub vector: Option<AccelerationVector<f64>>,
+    /// The orientation, with the offset and leases applied
+    pub candidate: Option<Rotation>,
+    pub locked: bool,
+    pub posture: Posture,
+}
+
+/// Site rules about which rotations to go with (see `script.rs`)
+pub trait Policy {
+    /// The orientation to settle on for this sample, instead of the
+    /// candidate; `None` keeps the display where it is.
+    fn decide(&mut self, sample: &Sample) -> Option<Rotation>;
+}
+
+/// The main loop's view of the device, and what it's done about it
+pub struct Spinner<C: Clock> {
+    clock: C,
+    pub state: OrientationState,
+    pub motion: Motion,
+    pub leases: Leases,
+    /// How long a rotation is pending before it's written
+    pub veto_window: Duration,
+    pub offset: Rotation,
+    pub suggest_only: bool,
+    /// Gets the last word on each sample's orientation
+    pub policy: Option<Box<dyn Policy>>,
+    pub posture: Posture,
+    /// What was sensed, with leases and the policy applied
+    orientation: Option<Rotation>,
+    sensed: Option<Rotation>,
+    last_written: Option<Rotation>,
+    last_change: Option<Rotation>,
+    /// The orientation that was overridden by a requested rotation; it
+    /// isn't written until the device has been turned some other way.
+    overridden: Option<Rotation>,
+    /// The rotation waiting out the veto window, and when it started
+    pending: Option<(Rotation, Instant)>,
+    /// The rotation suggested while locked or suggest-only
+    suggested: Option<Rotation>,
+}
+
+impl<C: Clock> Spinner<C> {
+    pub fn new(clock: C, settings: &Settings) -> Spinner<C> {
+        Spinner {
+            clock,
+            state: OrientationState::new(settings.delay(), settings.flip_delay()),
+            motion: Motion::new(settings.motion_threshold),
+            leases: Leases::new(settings.allowed.clone()),
+            veto_window: settings.veto_window(),
+            offset: settings.offset,
+            suggest_only: settings.suggest_only,
+            policy: None,
+            posture: Posture::Unknown,
+            orientation: None,
+            sensed: None,
+            last_written: None,
+            last_change: None,
+            overridden: None,
+            pending: None,
+            suggested: None,
+        }
+    }
+
+    /// Pick up the settings that changed from `old` to `new`.
+    pub fn reconfigure(&mut self, old: &Settings, new: &Settings) {
+        if new.delay != old.delay {
+            self.state.delay = new.delay();
+        }
+        if new.flip_delay() != old.flip_delay() {
+            self.state.flip_delay = new.flip_delay();
+        }
+        if new.motion_threshold != old.motion_threshold {
+            self.motion.threshold = new.motion_threshold;
+        }
+        if new.veto_window != old.veto_window {
+            self.veto_window = new.veto_window();
+        }
+        if new.allowed != old.allowed {
+            self.leases.set_allowed(new.allowed.clone());
+        }
+        self.offset = new.offset;
+        self.suggest_only = new.suggest_only;
+    }
+
+    /// The orientation as of the last sample, with leases and the policy
+    /// applied
+    pub fn orientation(&self) -> Option<Rotation> {
+        self.orientation
+    }
+
+    pub fn sensed(&self) -> Option<Rotation> {
+        self.sensed
+    }
+
+    /// The rotation as it stands, with leases taken since the last sample
+    pub fn current(&self) -> Option<Rotation> {
+        self.leases.apply(self.sensed, self.last_written)
+    }
+
+    pub fn last_written(&self) -> Option<Rotation> {
+        self.last_written
+    }
+
+    pub fn pending(&self) -> Option<Rotation> {
+        self.pending.map(|(r, _)| r)
+    }
+
+    pub fn suggested(&self) -> Option<Rotation> {
+        self.suggested
+    }
+
+    /// Take a sample from `orient`, and write or announce whatever comes
+    /// of it to `frontend`. `locked` is whether the rotation lock is on.
+    ///
+    /// Returns how sending went, if anything was sent.
+    pub fn tick<O, F>(&mut self, orient: &mut O, frontend: &mut F, locked: bool)
+        -> Option<SendResult>
+        where O: Orientator, F: Frontend + Display {
+        let mut sent = None;
+        self.sensed = orient.orientation().map(|r| r.relative_to(self.offset));
+        self.orientation = self.leases.apply(self.sensed, self.last_written);
+        if let Some(ref mut policy) = self.policy {
+            self.orientation = policy.decide(&Sample {
+                vector: orient.filtered_vector(),
+                candidate: self.orientation,
+                locked,
+                posture: self.posture,
+            });
+        }
+        trace!("Orientation is {}", opt(self.orientation));
+        let now = self.clock.now();
+        let moving = self.motion.update(orient.raw_vector(), now);
+        let settled = self.state.update(self.orientation, moving, self.last_written, now);
+        if self.last_change != self.state.candidate() {
+            self.last_change = self.state.candidate();
+            self.overridden = None;
+        }
+        if let Some(rot) = settled {
+            if Some(rot) != self.last_written && Some(rot) != self.overridden {
+                let commit = match self.pending {
+                    _ if locked || self.suggest_only    => {
+                        if self.suggested != Some(rot) {
+                            info!("Suggesting {}", rot);
+                            self.suggested = Some(rot);
+                            let _ = frontend.announce(Announcement::Suggested(rot));
+                        }
+                        false
+                    },
+                    _ if 0 == millis(self.veto_window)  => true,
+                    Some((r, since)) if r == rot    => now.duration_since(since) >= self.veto_window,
+                    _   => {
+                        info!("Rotation to {} pending", rot);
+                        self.pending = Some((rot, now));
+                        let _ = frontend.announce(Announcement::Pending(rot));
+                        false
+                    },
+                };
+                if commit {
+                    info!("Writing {} to {}", rot, frontend);
+                    self.pending = None;
+                    // Failed sends are retried by the frontends according
+                    // to their policies, so this is written either way.
+                    self.last_written = Some(rot);
+                    sent = Some(frontend.send(rot));
+                }
+            } // if Some(rot) != last_written ...
+        } // if let Some(rot) = settled
+        // Drop a pending rotation that's been locked out or turned away
+        // from.
+        if let Some((r, _)) = self.pending {
+            if self.last_change != Some(r) || self.overridden == Some(r) || locked {
+                debug!("Rotation to {} cancelled", r);
+                self.pending = None;
+                let _ = frontend.announce(Announcement::Cancelled(r));
+            }
+        }
+        // Likewise a suggestion that's been turned away from, or that
+        // doesn't apply any more.
+        if let Some(r) = self.suggested {
+            if self.last_change != Some(r) || self.overridden == Some(r)
+                || self.last_written == Some(r) || ! (locked || self.suggest_only) {
+                debug!("Suggestion of {} withdrawn", r);
+                self.suggested = None;
+                let _ = frontend.announce(Announcement::Cancelled(r));
+            }
+        }
+        sent
+    }
+
+    /// Cancel the pending rotation, returning it if there was one. It
+    /// isn't written until the device has been turned some other way.
+    pub fn veto<F: Frontend>(&mut self, frontend: &mut F) -> Option<Rotation> {
+        let (r, _) = self.pending.take()?;
+        info!("Rotation to {} vetoed", r);
+        self.overridden = Some(r);
+        let _ = frontend.announce(Announcement::Cancelled(r));
+        Some(r)
+    }
+
+    /// Write the suggested or pending rotation now, returning it and how
+    /// sending went, if there was one.
+    pub fn accept<F>(&mut self, frontend: &mut F) -> Option<(Rotation, SendResult)>
+        where F: Frontend + Display {
+        let r = self.suggested.or(self.pending())?;
+        info!("Writing {} to {} (accepted)", r, frontend);
+        self.last_written = Some(r);
+        self.pending = None;
+        self.suggested = None;
+        Some((r, frontend.send(r)))
+    }
+
+    /// Write `r` now, whatever the device says. What it says isn't written
+    /// until it's been turned some other way.
+    pub fn rotate<F>(&mut self, r: Rotation, frontend: &mut F) -> SendResult
+        where F: Frontend + Display {
+        info!("Writing {} to {} (requested)", r, frontend);
+        self.last_written = Some(r);
+        self.overridden = self.last_change;
+        frontend.send(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spinnr::{Accelerometer, FilteredAccelerometer};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    const PERIOD: u32 = 50;
+    const DELAY: u32 = 350;
+    const FLIP_DELAY: u32 = 600;
+
+    /// An accelerometer that reads whatever the test says
+    struct Scripted(Rc<Cell<AccelerationVector<f64>>>);
+
+    impl Accelerometer for Scripted {
+        fn read(&mut self) -> AccelerationVector<f64> {
+            self.0.get()
+        }
+
+        fn read_raw(&mut self) -> AccelerationVector<i32> {
+            let v = self.0.get();
+            AccelerationVector { x: v.x as i32, y: v.y as i32, z: v.z as i32 }
+        }
+
+        fn get_scale(&self) -> f64 {
+            1.0
+        }
+
+        fn latest_raw(&self) -> Option<AccelerationVector<f64>> {
+            Some(self.0.get())
+        }
+    }
+
+    /// Something a frontend was told
+    #[derive(Debug,PartialEq,Clone,Copy)]
+    enum Told {
+        Sent(Rotation),
+        Announced(Announcement),
+    }
+
+    /// A frontend that writes down what it's told, and when (in ms from
+    /// the start)
+    struct Recorder {
+        clock: VirtualClock,
+        start: Instant,
+        told: Vec<(u64, Told)>,
+    }
+
+    impl Recorder {
+        fn record(&mut self, what: Told) {
+            self.told.push((millis(self.clock.now().duration_since(self.start)), what));
+        }
+    }
+
+    impl Frontend for Recorder {
+        fn send(&mut self, r: Rotation) -> SendResult {
+            self.record(Told::Sent(r));
+            Ok(())
+        }
+
+        fn announce(&mut self, a: Announcement) -> SendResult {
+            self.record(Told::Announced(a));
+            Ok(())
+        }
+    }
+
+    impl Display for Recorder {
+        fn fmt(&self, f: &mut Formatter) -> FmtResult {
+            write!(f, "recorder")
+        }
+    }
+
+    /// A spinner hooked up to a scripted accelerometer and a recorder,
+    /// sampling every `PERIOD`ms of virtual time
+    struct Harness {
+        clock: VirtualClock,
+        accel: Rc<Cell<AccelerationVector<f64>>>,
+        /// Where the device is turned to, in degrees (0 is normal, 90 left)
+        angle: f64,
+        orient: FilteredAccelerometer<Scripted>,
+        frontend: Recorder,
+        spinner: Spinner<VirtualClock>,
+        locked: bool,
+    }
+
+    fn settings() -> Settings {
+        Settings {
+            period: PERIOD,
+            delay: DELAY,
+            flip_delay: Some(FLIP_DELAY),
+            hysteresis: PERIOD,
+            sensitivity: DEFAULT_SENSITIVITY,
+            motion_threshold: 0.0,
+            veto_window: 0,
+            allowed: Rotation::ALL.to_vec(),
+            offset: Rotation::Normal,
+            suggest_only: false,
+            backends: (Vec::new(), HashMap::new()),
+            frontends: Vec::new(),
+        }
+    }
+
+    /// The acceleration with the device turned `angle` degrees
+    fn gravity(angle: f64) -> AccelerationVector<f64> {
+        let rad = angle.to_radians();
+        AccelerationVector { x: 9.8 * rad.sin(), y: -9.8 * rad.cos(), z: 0.0 }
+    }
+
+    impl Harness {
+        /// Start with the device upright.
+        fn new(settings: Settings) -> Harness {
+            let clock = VirtualClock::new();
+            let accel = Rc::new(Cell::new(gravity(0.0)));
+            Harness {
+                orient: FilteredAccelerometer::new(Scripted(accel.clone()), settings.filter_mult()),
+                frontend: Recorder {
+                    clock: clock.clone(),
+                    start: clock.now(),
+                    told: Vec::new(),
+                },
+                spinner: Spinner::new(clock.clone(), &settings),
+                clock,
+                accel,
+                angle: 0.0,
+                locked: false,
+            }
+        }
+
+        fn tick(&mut self) {
+            self.clock.advance(Duration::from_millis(u64::from(PERIOD)));
+            self.spinner.tick(&mut self.orient, &mut self.frontend, self.locked);
+        }
+
+        /// Hold the device where it is for `ms`.
+        fn hold(&mut self, ms: u32) {
+            for _ in 0..ms / PERIOD {
+                self.tick();
+            }
+        }
+
+        /// Turn the device steadily to `angle` over `ms`.
+        fn turn(&mut self, angle: f64, ms: u32) {
+            let steps = ms / PERIOD;
+            let from = self.angle;
+            for i in 1..=steps {
+                let a = from + (angle - from) * f64::from(i) / f64::from(steps);
+                self.accel.set(gravity(a));
+                self.tick();
+            }
+            self.angle = angle;
+        }
+
+        /// Snap the device to `angle` and hold it there for `ms`.
+        fn hold_at(&mut self, angle: f64, ms: u32) {
+            self.accel.set(gravity(angle));
+            self.angle = angle;
+            self.hold(ms);
+        }
+
+        /// When each rotation was written
+        fn written(&self) -> Vec<(u64, Rotation)> {
+            self.frontend.told.iter().filter_map(|&(t, w)| match w {
+                Told::Sent(r)   => Some((t, r)),
+                _   => None,
+            }).collect()
+        }
+
+        fn rotations(&self) -> Vec<Rotation> {
+            self.written().into_iter().map(|(_, r)| r).collect()
+        }
+    }
+
+    #[test]
+    fn quarter_turn_written_after_delay() {
+        let mut h = Harness::new(settings());
+        h.hold(1000);
+        h.hold_at(90.0, 1000);
+        // Each holds from the first sample after it's seen
+        let settled = u64::from(PERIOD + DELAY);
+        assert_eq!(vec![(settled, Rotation::Normal), (1000 + settled, Rotation::Left)],
+                   h.written());
+    }
+
+    #[test]
+    fn half_turn_written_after_flip_delay() {
+        let mut h = Harness::new(settings());
+        h.hold(1000);
+        h.hold_at(180.0, 1000);
+        assert_eq!(Some(&(u64::from(1000 + PERIOD + FLIP_DELAY), Rotation::Inverted)),
+                   h.written().last());
+    }
+
+    #[test]
+    fn hysteresis_filters_out_jolts() {
+        let jolt = |hysteresis| {
+            let mut h = Harness::new(Settings { delay: 100, hysteresis, ..settings() });
+            h.hold(1000);
+            h.hold_at(90.0, 300);
+            h.hold_at(0.0, 1000);
+            h.rotations()
+        };
+        assert_eq!(vec![Rotation::Normal, Rotation::Left, Rotation::Normal], jolt(PERIOD));
+        assert_eq!(vec![Rotation::Normal], jolt(1000));
+    }
+
+    #[test]
+    fn hysteresis_slows_turns() {
+        let mut h = Harness::new(Settings { hysteresis: 1000, ..settings() });
+        h.hold(1000);
+        h.hold_at(90.0, 3000);
+        let (t, r) = h.written()[1];
+        assert_eq!(Rotation::Left, r);
+        assert!(t > u64::from(1000 + DELAY + 500), "written at {}ms", t);
+    }
+
+    #[test]
+    fn slow_flip_writes_the_side_without_motion_detection() {
+        let mut h = Harness::new(settings());
+        h.hold(1000);
+        h.turn(180.0, 1200);
+        h.hold(1000);
+        assert_eq!(vec![Rotation::Normal, Rotation::Left, Rotation::Inverted], h.rotations());
+    }
+
+    #[test]
+    fn motion_detection_skips_the_side_during_a_flip() {
+        // 180 degrees in 1200ms is 150 degrees per second
+        let mut h = Harness::new(Settings { motion_threshold: 120.0, ..settings() });
+        h.hold(1000);
+        h.turn(180.0, 1200);
+        h.hold(1000);
+        assert_eq!(vec![Rotation::Normal, Rotation::Inverted], h.rotations());
+        // The clock starts once the device stops
+        assert_eq!(u64::from(2200 + PERIOD + FLIP_DELAY), h.written()[1].0);
+    }
+
+    #[test]
+    fn veto_window_announces_before_writing() {
+        let mut h = Harness::new(Settings { veto_window: 500, ..settings() });
+        h.hold(1000);
+        let settled = u64::from(PERIOD + DELAY);
+        assert_eq!(vec![(settled, Told::Announced(Announcement::Pending(Rotation::Normal))),
+                        (settled + 500, Told::Sent(Rotation::Normal))],
+                   h.frontend.told);
+    }
+
+    #[test]
+    fn vetoed_rotation_waits_for_another_turn() {
+        let mut h = Harness::new(Settings { veto_window: 500, ..settings() });
+        h.hold(PERIOD + DELAY);
+        assert_eq!(Some(Rotation::Normal), h.spinner.veto(&mut h.frontend));
+        h.hold(1000);
+        assert!(h.written().is_empty());
+        h.hold_at(90.0, 1000);
+        assert_eq!(vec![Rotation::Left], h.rotations());
+    }
+
+    #[test]
+    fn locked_rotation_is_only_suggested() {
+        let mut h = Harness::new(settings());
+        h.locked = true;
+        h.hold(1000);
+        assert!(h.written().is_empty());
+        assert_eq!(Some(Rotation::Normal), h.spinner.suggested());
+        assert_eq!(Some(Rotation::Normal), h.spinner.accept(&mut h.frontend).map(|(r, _)| r));
+        assert_eq!(vec![Rotation::Normal], h.rotations());
+    }
+
+    /// Never inverted, and no rotating while the keyboard's in use
+    struct Site;
+
+    impl Policy for Site {
+        fn decide(&mut self, sample: &Sample) -> Option<Rotation> {
+            match sample.candidate {
+                _ if Posture::Laptop == sample.posture  => None,
+                Some(Rotation::Inverted)    => None,
+                c   => c,
+            }
+        }
+    }
+
+    #[test]
+    fn policy_has_the_last_word() {
+        let mut h = Harness::new(settings());
+        h.spinner.policy = Some(Box::new(Site));
+        h.hold(1000);
+        h.hold_at(180.0, 2000);
+        h.spinner.posture = Posture::Laptop;
+        h.hold_at(90.0, 1000);
+        h.spinner.posture = Posture::Tablet;
+        h.hold(1000);
+        assert_eq!(vec![Rotation::Normal, Rotation::Left], h.rotations());
+    }
+}
@@ -0,0 +1,241 @@
+//! # state
+//! Decides when the device has settled into a new orientation.
+//!
+//! A candidate orientation has to hold for a while before it's committed:
+//! `delay` for a quarter turn from what's on the display, and `flip_delay`
+//! for a half turn. While the device is moving fast, the clock doesn't
+//! run at all, so the sideways orientations it passes through while being
+//! flipped never get committed.
+
+use super::*;
+
+/// Tracks the candidate orientation and how long it's been settled.
+#[derive(Debug)]
+pub struct OrientationState {
+    /// How long a quarter turn has to hold
+    pub delay: Duration,
+    /// How long a half turn has to hold
+    pub flip_delay: Duration,
+    candidate: Option<Rotation>,
+    /// When the candidate was last seen while the device was still
+    still_since: Option<Instant>,
+}
+
+impl OrientationState {
+    pub fn new(delay: Duration, flip_delay: Duration) -> OrientationState {
+        OrientationState {
+            delay,
+            flip_delay,
+            candidate: None,
+            still_since: None,
+        }
+    }
+
+    /// The orientation most recently sensed
+    pub fn candidate(&self) -> Option<Rotation> {
+        self.candidate
+    }
+
+    /// Take a sample. `sensed` is what the orientator says (`None` when it
+    /// can't tell, which doesn't disturb the candidate), `moving` whether
+    /// the device is moving fast, and `current` what's on the display.
+    ///
+    /// Returns the candidate once it's held long enough to commit.
+    pub fn update(&mut self, sensed: Option<Rotation>, moving: bool,
+                  current: Option<Rotation>, now: Instant) -> Option<Rotation> {
+        if sensed.is_some() && sensed != self.candidate {
+            trace!("New candidate orientation {}", opt(sensed));
+            self.candidate = sensed;
+            self.still_since = None;
+        }
+        if moving {
+            self.still_since = None;
+        } else if self.still_since.is_none() {
+            self.still_since = Some(now);
+        }
+        let candidate = self.candidate?;
+        let wait = match current {
+            Some(c) if 2 == c.quarter_turns(candidate)  => self.flip_delay,
+            _   => self.delay,
+        };
+        match self.still_since {
+            Some(t) if now.duration_since(t) >= wait    => Some(candidate),
+            _   => None,
+        }
+    }
+}
+
+/// Decides whether the device is moving fast, from how far the raw
+/// acceleration turns between samples.
+#[derive(Debug)]
+pub struct Motion {
+    /// How fast the device has to turn to count as moving (in degrees per
+    /// second); 0 turns motion detection off
+    pub threshold: f64,
+    last: Option<(AccelerationVector<f64>, Instant)>,
+}
+
+impl Motion {
+    pub fn new(threshold: f64) -> Motion {
+        Motion {
+            threshold,
+            last: None,
+        }
+    }
+
+    /// Take a raw acceleration sample, if there is one, and say whether the
+    /// device is moving fast.
+    pub fn update(&mut self, raw: Option<AccelerationVector<f64>>, now: Instant) -> bool {
+        let raw = match raw {
+            Some(r) if 0.0 < self.threshold => r,
+            _   => return false,
+        };
+        let moving = match self.last {
+            Some((last, then)) if now > then    => {
+                let secs = now.duration_since(then).as_secs() as f64
+                    + f64::from(now.duration_since(then).subsec_nanos()) / 1e9;
+                angle_between(last, raw) / secs > self.threshold
+            },
+            _   => false,
+        };
+        self.last = Some((raw, now));
+        moving
+    }
+}
+
+/// The angle between two vectors, in degrees
+fn angle_between(a: AccelerationVector<f64>, b: AccelerationVector<f64>) -> f64 {
+    let dot = a.x * b.x + a.y * b.y + a.z * b.z;
+    let mags = a.magnitude() * b.magnitude();
+    if 0.0 == mags {
+        0.0
+    } else {
+        (dot / mags).max(-1.0).min(1.0).acos().to_degrees()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DELAY: u64 = 350;
+    const FLIP_DELAY: u64 = 600;
+    const PERIOD: u64 = 50;
+
+    /// Feed `state` a sample every `PERIOD`ms for `ms`ms, starting at
+    /// `*t`, and return the first rotation it settles on.
+    fn feed(state: &mut OrientationState, sensed: Option<Rotation>, moving: bool,
+            current: Option<Rotation>, t: &mut Instant, ms: u64) -> Option<Rotation> {
+        let mut settled = None;
+        let end = *t + Duration::from_millis(ms);
+        while *t < end {
+            let s = state.update(sensed, moving, current, *t);
+            settled = settled.or(s);
+            *t += Duration::from_millis(PERIOD);
+        }
+        settled
+    }
+
+    fn state() -> OrientationState {
+        OrientationState::new(Duration::from_millis(DELAY), Duration::from_millis(FLIP_DELAY))
+    }
+
+    #[test]
+    fn quarter_turn_waits_for_delay() {
+        let mut s = state();
+        let mut t = Instant::now();
+        assert_eq!(None, feed(&mut s, Some(Rotation::Left), false, Some(Rotation::Normal), &mut t, DELAY));
+        assert_eq!(Some(Rotation::Left), feed(&mut s, Some(Rotation::Left), false, Some(Rotation::Normal), &mut t, PERIOD));
+    }
+
+    #[test]
+    fn half_turn_waits_for_flip_delay() {
+        let mut s = state();
+        let mut t = Instant::now();
+        assert_eq!(None, feed(&mut s, Some(Rotation::Inverted), false, Some(Rotation::Normal), &mut t, FLIP_DELAY));
+        assert_eq!(Some(Rotation::Inverted), feed(&mut s, Some(Rotation::Inverted), false, Some(Rotation::Normal), &mut t, PERIOD));
+    }
+
+    #[test]
+    fn change_restarts_the_clock() {
+        let mut s = state();
+        let mut t = Instant::now();
+        assert_eq!(None, feed(&mut s, Some(Rotation::Left), false, Some(Rotation::Normal), &mut t, DELAY - PERIOD));
+        assert_eq!(None, feed(&mut s, Some(Rotation::Right), false, Some(Rotation::Normal), &mut t, DELAY));
+        assert_eq!(Some(Rotation::Right), feed(&mut s, Some(Rotation::Right), false, Some(Rotation::Normal), &mut t, PERIOD));
+    }
+
+    #[test]
+    fn unknown_orientation_keeps_candidate() {
+        let mut s = state();
+        let mut t = Instant::now();
+        feed(&mut s, Some(Rotation::Left), false, Some(Rotation::Normal), &mut t, PERIOD);
+        assert_eq!(Some(Rotation::Left), feed(&mut s, None, false, Some(Rotation::Normal), &mut t, DELAY));
+        assert_eq!(Some(Rotation::Left), s.candidate());
+    }
+
+    #[test]
+    fn nothing_settles_before_anything_is_sensed() {
+        let mut s = state();
+        let mut t = Instant::now();
+        assert_eq!(None, feed(&mut s, None, false, None, &mut t, FLIP_DELAY * 2));
+    }
+
+    #[test]
+    fn fast_flip_skips_intermediate() {
+        let mut s = state();
+        let mut t = Instant::now();
+        let normal = Some(Rotation::Normal);
+        assert_eq!(normal, feed(&mut s, normal, false, normal, &mut t, DELAY + PERIOD));
+        // Passing through left for longer than the delay, but moving
+        assert_eq!(None, feed(&mut s, Some(Rotation::Left), true, normal, &mut t, DELAY * 2));
+        assert_eq!(None, feed(&mut s, Some(Rotation::Inverted), true, normal, &mut t, PERIOD * 2));
+        // Then settling
+        assert_eq!(None, feed(&mut s, Some(Rotation::Inverted), false, normal, &mut t, FLIP_DELAY));
+        assert_eq!(Some(Rotation::Inverted), feed(&mut s, Some(Rotation::Inverted), false, normal, &mut t, PERIOD));
+    }
+
+    #[test]
+    fn motion_stops_the_clock() {
+        let mut s = state();
+        let mut t = Instant::now();
+        let normal = Some(Rotation::Normal);
+        assert_eq!(None, feed(&mut s, Some(Rotation::Left), false, normal, &mut t, DELAY - PERIOD));
+        assert_eq!(None, feed(&mut s, Some(Rotation::Left), true, normal, &mut t, PERIOD));
+        assert_eq!(None, feed(&mut s, Some(Rotation::Left), false, normal, &mut t, DELAY));
+        assert_eq!(Some(Rotation::Left), feed(&mut s, Some(Rotation::Left), false, normal, &mut t, PERIOD));
+    }
+
+    fn vector(x: f64, y: f64, z: f64) -> Option<AccelerationVector<f64>> {
+        Some(AccelerationVector { x, y, z })
+    }
+
+    #[test]
+    fn motion_detects_fast_turns() {
+        let mut m = Motion::new(90.0);
+        let t = Instant::now();
+        assert!(! m.update(vector(0.0, -9.8, 0.0), t));
+        // 45 degrees in 100ms is 450 degrees per second
+        assert!(m.update(vector(6.93, -6.93, 0.0), t + Duration::from_millis(100)));
+        // 45 degrees in a second is slow enough
+        assert!(! m.update(vector(9.8, 0.0, 0.0), t + Duration::from_millis(1100)));
+        // Shaking without turning isn't moving
+        assert!(! m.update(vector(12.0, 0.0, 0.0), t + Duration::from_millis(1150)));
+    }
+
+    #[test]
+    fn motion_threshold_zero_is_off() {
+        let mut m = Motion::new(0.0);
+        let t = Instant::now();
+        m.update(vector(0.0, -9.8, 0.0), t);
+        assert!(! m.update(vector(9.8, 0.0, 0.0), t + Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn motion_without_vectors_is_still() {
+        let mut m = Motion::new(90.0);
+        let t = Instant::now();
+        assert!(! m.update(None, t));
+        assert!(! m.update(None, t + Duration::from_millis(10)));
+    }
+}